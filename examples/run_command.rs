@@ -0,0 +1,39 @@
+//! Logs in to a bofhd server and runs a single command, without the REPL.
+//!
+//! ```sh
+//! BOFH_PASSWORD=... cargo run --example run_command -- https://bofhd.example.com:8000 olanor user info olanor
+//! ```
+use bofh::{Bofh, BofhError};
+use std::process::exit;
+
+fn main() -> Result<(), BofhError> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [url, username, group, name, command_args @ ..] = args.as_slice() else {
+        eprintln!("Usage: run_command <url> <username> <group> <command> [<args>...]");
+        exit(2);
+    };
+    let password = std::env::var("BOFH_PASSWORD").unwrap_or_else(|_| {
+        eprintln!("Set BOFH_PASSWORD to the password");
+        exit(2);
+    });
+
+    let bofh = Bofh::new(url.clone())?.login(username, password)?;
+    let Some(command) = bofh.command(group, name) else {
+        eprintln!("Unknown command '{} {}'", group, name);
+        exit(1);
+    };
+    let command_args: Vec<&str> = command_args.iter().map(String::as_str).collect();
+    let response = bofh.run_command(&command, &command_args)?;
+
+    if let Some(message) = response.as_message() {
+        println!("{}", message);
+    } else {
+        for row in response.as_rows() {
+            for (key, value) in row {
+                println!("{}: {}", key, bofh::format::value_to_string(&value));
+            }
+            println!();
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,79 @@
+//! Generating shell completion scripts from the server's command table.
+//!
+//! The scripts complete the command given to `bofh --cmd`. They're a snapshot of the commands
+//! available when they were generated, so they must be regenerated to pick up server changes.
+use bofh::CommandGroup;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Shells we can generate completion scripts for
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub(crate) enum Shell {
+    Bash,
+    Zsh,
+}
+
+/// Generates a completion script for `shell` reflecting the given command table
+pub(crate) fn generate(
+    shell: Shell,
+    url: &str,
+    commands: &BTreeMap<String, CommandGroup>,
+) -> String {
+    let mut script = String::new();
+    match shell {
+        Shell::Bash => {
+            writeln!(script, "# bash completion for bofh, generated from {}", url).unwrap();
+            script.push_str("_bofh_commands='");
+            for command_group in commands.values() {
+                for command in command_group.commands.values() {
+                    writeln!(script, "{} {}", command_group.name, command.name).unwrap();
+                }
+            }
+            script.push_str(
+                "'
+_bofh() {
+    local cur=\"${COMP_WORDS[COMP_CWORD]}\" prev=\"${COMP_WORDS[COMP_CWORD-1]}\"
+    if [[ \"$prev\" == \"--cmd\" ]]; then
+        local IFS=$'\\n'
+        COMPREPLY=($(compgen -W \"$_bofh_commands\" -- \"${cur#[\\\"\\']}\" | while read -r c; do printf '%q\\n' \"$c\"; done))
+    else
+        COMPREPLY=($(compgen -W \"--cmd --url --user --help\" -- \"$cur\"))
+    fi
+}
+complete -F _bofh bofh
+",
+            );
+        }
+        Shell::Zsh => {
+            writeln!(script, "#compdef bofh").unwrap();
+            writeln!(script, "# zsh completion for bofh, generated from {}", url).unwrap();
+            script.push_str("local -a bofh_commands\nbofh_commands=(\n");
+            for command_group in commands.values() {
+                for command in command_group.commands.values() {
+                    writeln!(
+                        script,
+                        "  '{} {}:{}'",
+                        command_group.name,
+                        command.name,
+                        command.usage().replace('\'', "'\\''")
+                    )
+                    .unwrap();
+                }
+            }
+            script.push_str(
+                ")
+local state
+_arguments \\
+  '--cmd=[run command and exit]:bofh command:->cmd' \\
+  '--url=[connect to bofhd server at URL]:url:' \\
+  '--user=[authenticate as USER]:user:_users' \\
+  '*: :_default'
+if [[ $state == cmd ]]; then
+  _describe 'bofh command' bofh_commands
+fi
+",
+            );
+        }
+    }
+    script
+}
@@ -0,0 +1,47 @@
+//! The user's configuration file, `bofh/config.toml` in the platform's configuration directory.
+//!
+//! Command-line options take precedence over the configuration file.
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(default)]
+pub(crate) struct Config {
+    /// Realm to qualify usernames with, like `--realm`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) realm: Option<String>,
+    /// Favorite command lines, managed with the `fav` command
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) favorites: Vec<String>,
+}
+
+impl Config {
+    pub(crate) fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("bofh").join("config.toml"))
+    }
+
+    /// Loads the configuration file, or the default configuration if there is none
+    pub(crate) fn load() -> Result<Self, String> {
+        let path = match Self::path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(Self::default()),
+        };
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        toml::from_str(&contents).map_err(|err| format!("Invalid {}: {}", path.display(), err))
+    }
+
+    /// Saves the configuration file, creating its directory if necessary.
+    ///
+    /// Note that any comments in an existing file are lost.
+    pub(crate) fn save(&self) -> Result<(), String> {
+        let path = Self::path().ok_or("Failed to find a configuration directory")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|err| format!("Failed to create {}: {}", dir.display(), err))?;
+        }
+        let contents = toml::to_string(self).map_err(|err| err.to_string())?;
+        std::fs::write(&path, contents)
+            .map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+    }
+}
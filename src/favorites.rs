@@ -0,0 +1,74 @@
+//! The `fav` command, for keeping a list of frequently used command lines that can be run by number.
+//!
+//! Favorites are stored in the configuration file. A favorite can go stale if the server stops
+//! providing its command; stale favorites are marked as such, and can't be run until they're removed.
+use crate::config::Config;
+use crate::helper::BofhHelper;
+use crate::tokenize::{self, Token};
+use crate::Args;
+use bofh::Bofh;
+
+/// Subcommands of `fav`, for completion
+pub(crate) const SUBCOMMANDS: &[&str] = &["add", "list", "remove", "run"];
+
+const USAGE: &str = "Usage: fav add <command> | fav list | fav run <n> | fav remove <n>";
+
+/// Handles a `fav` command line, where `command` is the words after `fav`
+pub(crate) fn fav(
+    command: &[Token],
+    config: &mut Config,
+    bofh: &Bofh,
+    helper: &BofhHelper,
+    args: &Args,
+) -> Result<(), String> {
+    match tokenize::values(command).as_slice() {
+        ["add", favorite @ ..] if !favorite.is_empty() => {
+            helper.resolve(favorite)?;
+            config.favorites.push(tokenize::join(&command[1..]));
+            config.save()?;
+            println!("Added favorite {}", config.favorites.len());
+        }
+        ["list"] => {
+            if config.favorites.is_empty() {
+                println!("No favorites yet, add one with 'fav add <command>'");
+            }
+            for (number, favorite) in config.favorites.iter().enumerate() {
+                let words = tokenize::words(favorite).unwrap_or_default();
+                let words: Vec<&str> = words.iter().map(String::as_str).collect();
+                if helper.resolve(&words).is_ok() {
+                    println!("{:>4}  {}", number + 1, favorite);
+                } else {
+                    println!("{:>4}  {} (no longer available)", number + 1, favorite);
+                }
+            }
+        }
+        ["run", number] => {
+            let favorite = &config.favorites[index(number, config)?];
+            let words = tokenize::tokenize(favorite)?;
+            if let Err(err) = helper.resolve(&tokenize::values(&words)) {
+                return Err(format!(
+                    "Favorite {} is no longer available ({}), remove it with 'fav remove {}'",
+                    number, err, number
+                ));
+            }
+            println!("{}", favorite);
+            let result = crate::dispatch(bofh, helper, args, &words)?;
+            crate::write_result(&result, args, args.format, None);
+        }
+        ["remove", number] => {
+            let favorite = config.favorites.remove(index(number, config)?);
+            config.save()?;
+            println!("Removed favorite '{}'", favorite);
+        }
+        _ => return Err(USAGE.to_owned()),
+    }
+    Ok(())
+}
+
+/// Parses a favorite number, counting from one, into an index into [`Config::favorites`]
+fn index(number: &str, config: &Config) -> Result<usize, String> {
+    match number.parse::<usize>() {
+        Ok(number) if number >= 1 && number <= config.favorites.len() => Ok(number - 1),
+        _ => Err(format!("No favorite number '{}', see 'fav list'", number)),
+    }
+}
@@ -0,0 +1,607 @@
+//! Helpers for presenting command results
+
+use crate::Value;
+use chrono::{FixedOffset, Local, NaiveDate, NaiveDateTime, Offset, TimeZone, Utc};
+use colored::Colorize;
+use std::io::{self, Write};
+
+/// How `dateTime.iso8601` values are rendered by [`PlainFormatter`] and [`format_datetime`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DateTimeFormat {
+    /// ISO 8601, as sent by the server, like `2023-01-31T13:37:00`
+    #[default]
+    Iso8601,
+    /// Converted to the user's local timezone, like `2023-01-31 14:37:00 +01:00`
+    Local,
+}
+
+/// Converts an XML-RPC `dateTime.iso8601` value to a [`chrono::DateTime`], or `None` if it isn't a valid date and time.
+///
+/// bofhd doesn't include timezone offsets in its timestamps, in which case the time is taken to be UTC.
+#[must_use]
+pub fn to_chrono(datetime: &iso8601::DateTime) -> Option<chrono::DateTime<FixedOffset>> {
+    let date = match datetime.date {
+        iso8601::Date::YMD { year, month, day } => NaiveDate::from_ymd_opt(year, month, day),
+        iso8601::Date::Week { year, ww, d } => NaiveDate::from_isoywd_opt(
+            year,
+            ww,
+            chrono::Weekday::try_from(u8::try_from(d.checked_sub(1)?).ok()?).ok()?,
+        ),
+        iso8601::Date::Ordinal { year, ddd } => NaiveDate::from_yo_opt(year, ddd),
+    }?;
+    let time = &datetime.time;
+    let naive = date.and_hms_milli_opt(time.hour, time.minute, time.second, time.millisecond)?;
+    let offset = FixedOffset::east_opt(time.tz_offset_hours * 3600 + time.tz_offset_minutes * 60)?;
+    offset.from_local_datetime(&naive).single()
+}
+
+/// Formats of dates and times that bofhd sends as strings, like `2023-01-31 13:37:00`, in addition to ISO 8601
+const DATETIME_STRING_FORMATS: &[&str] =
+    &["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%d %H:%M", "%Y%m%dT%H:%M:%S"];
+
+/// Converts a date and time in a command result to a [`chrono::DateTime`], or `None` if it isn't one.
+///
+/// Both XML-RPC `dateTime.iso8601` values and strings are recognized, like `2023-01-31T13:37:00`, `2023-01-31 13:37:00`
+/// or just the date `2023-01-31` (at midnight). Like with [`to_chrono`], times without a timezone offset are taken to be UTC.
+#[must_use]
+pub fn parse_datetime(value: &Value) -> Option<chrono::DateTime<FixedOffset>> {
+    let string = match value {
+        Value::DateTime(datetime) => return to_chrono(datetime),
+        Value::String(string) => string.trim(),
+        _ => return None,
+    };
+    if let Some(datetime) = iso8601::datetime(string).ok().as_ref().and_then(to_chrono) {
+        return Some(datetime);
+    }
+    let naive = DATETIME_STRING_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(string, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(string, "%Y-%m-%d")
+                .ok()?
+                .and_hms_opt(0, 0, 0)
+        })?;
+    Some(Utc.fix().from_utc_datetime(&naive))
+}
+
+/// Gets the date and time at `key` in a struct in a command result, like an account's `expire` date, see [`parse_datetime`].
+///
+/// Returns `None` if `value` isn't a struct, the key is missing, or its value isn't a date and time.
+#[must_use]
+pub fn get_datetime(value: &Value, key: &str) -> Option<chrono::DateTime<FixedOffset>> {
+    parse_datetime(value.as_struct()?.get(key)?)
+}
+
+/// Gets the date and time at `key` in a struct in a command result like [`get_datetime`], without the timezone offset
+#[must_use]
+pub fn get_naive_datetime(value: &Value, key: &str) -> Option<NaiveDateTime> {
+    get_datetime(value, key).map(|datetime| datetime.naive_local())
+}
+
+/// Renders a `dateTime.iso8601` value in the given format.
+///
+/// Values that can't be converted (see [`to_chrono`]) are rendered as sent by the server.
+#[must_use]
+pub fn format_datetime(datetime: &iso8601::DateTime, format: DateTimeFormat) -> String {
+    match (to_chrono(datetime), format) {
+        (Some(converted), DateTimeFormat::Iso8601) if converted.offset().local_minus_utc() == 0 => {
+            converted
+                .naive_local()
+                .format("%Y-%m-%dT%H:%M:%S")
+                .to_string()
+        }
+        (Some(converted), DateTimeFormat::Iso8601) => {
+            converted.format("%Y-%m-%dT%H:%M:%S%:z").to_string()
+        }
+        (Some(converted), DateTimeFormat::Local) => converted
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S %:z")
+            .to_string(),
+        (None, _) => datetime.to_string(),
+    }
+}
+
+/// Writes command results to a writer, see [`crate::Bofh::run_command_to_writer`]
+pub trait OutputFormatter {
+    /// Writes `value` to `w`
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`io::Error`] if writing fails.
+    fn write_value(&self, value: &Value, w: &mut dyn Write) -> io::Result<()>;
+
+    /// Formats `value` as a string, for when the whole result is needed in memory anyway
+    #[must_use]
+    fn format(&self, value: &Value) -> String {
+        let mut buffer = vec![];
+        self.write_value(value, &mut buffer)
+            .expect("Writing to a Vec never fails");
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+}
+
+/// Writes values in their [`Debug`] representation on a single line, like the `bofh` client does
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DebugFormatter;
+
+impl OutputFormatter for DebugFormatter {
+    fn write_value(&self, value: &Value, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "{:?}", value)
+    }
+}
+
+/// Writes values as plain text: each row of an array on its own line, each member of a struct as `name: value`,
+/// and other values as by [`value_to_string`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PlainFormatter {
+    /// How to render date and time values
+    pub datetime: DateTimeFormat,
+    /// Whether to color the names and values of struct members differently, for a terminal
+    pub color: bool,
+}
+
+impl PlainFormatter {
+    fn to_string(self, value: &Value) -> String {
+        match value {
+            Value::DateTime(datetime) => format_datetime(datetime, self.datetime),
+            value => value_to_string(value),
+        }
+    }
+}
+
+impl OutputFormatter for PlainFormatter {
+    fn write_value(&self, value: &Value, w: &mut dyn Write) -> io::Result<()> {
+        match value {
+            Value::Array(rows) => {
+                for row in rows {
+                    self.write_value(row, w)?;
+                }
+                Ok(())
+            }
+            Value::Struct(members) => {
+                for (name, member) in members {
+                    let value = self.to_string(member);
+                    if self.color {
+                        writeln!(w, "{}: {}", name.bright_blue(), value.green())?;
+                    } else {
+                        writeln!(w, "{}: {}", name, value)?;
+                    }
+                }
+                Ok(())
+            }
+            value => writeln!(w, "{}", self.to_string(value)),
+        }
+    }
+}
+
+/// Writes values as JSON on a single line, as converted by [`to_json`], for scripts
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonFormatter;
+
+impl OutputFormatter for JsonFormatter {
+    fn write_value(&self, value: &Value, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "{}", to_json(value))
+    }
+}
+
+/// How many rows of a command result were kept by [`limit_rows`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RowCount {
+    /// The number of rows in the full result
+    pub total: usize,
+    /// The number of rows that are displayed
+    pub displayed: usize,
+}
+
+impl RowCount {
+    /// Whether any rows were left out
+    #[must_use]
+    pub fn is_truncated(&self) -> bool {
+        self.displayed < self.total
+    }
+}
+
+/// Limits a command result to at most `max_rows` rows.
+///
+/// Only array results are considered to have rows; any other value counts as a single row and is never truncated.
+#[must_use]
+pub fn limit_rows(value: &Value, max_rows: Option<usize>) -> (Value, RowCount) {
+    match (value, max_rows) {
+        (Value::Array(rows), Some(max_rows)) if rows.len() > max_rows => (
+            Value::Array(rows[..max_rows].to_vec()),
+            RowCount {
+                total: rows.len(),
+                displayed: max_rows,
+            },
+        ),
+        (Value::Array(rows), _) => (
+            value.clone(),
+            RowCount {
+                total: rows.len(),
+                displayed: rows.len(),
+            },
+        ),
+        _ => (
+            value.clone(),
+            RowCount {
+                total: 1,
+                displayed: 1,
+            },
+        ),
+    }
+}
+
+/// Renders a single value the way Python's `str()` would, which is what bofhd expects of its format strings
+#[must_use]
+pub fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::Int(int) => int.to_string(),
+        Value::Int64(int) => int.to_string(),
+        Value::Bool(true) => String::from("True"),
+        Value::Bool(false) => String::from("False"),
+        Value::String(string) => string.clone(),
+        Value::Double(double) => double.to_string(),
+        Value::DateTime(datetime) => format_datetime(datetime, DateTimeFormat::Iso8601),
+        Value::Base64(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+        Value::Nil => String::from("None"),
+        value => format!("{:?}", value),
+    }
+}
+
+/// Converts a value to JSON. Dates and times become ISO 8601 strings, and binary data is decoded as (lossy) UTF-8.
+#[must_use]
+pub fn to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Int(int) => (*int).into(),
+        Value::Int64(int) => (*int).into(),
+        Value::Bool(bool) => (*bool).into(),
+        Value::String(string) => string.as_str().into(),
+        Value::Double(double) => serde_json::Number::from_f64(*double)
+            .map_or(serde_json::Value::Null, serde_json::Value::Number),
+        Value::DateTime(datetime) => format_datetime(datetime, DateTimeFormat::Iso8601).into(),
+        Value::Base64(bytes) => String::from_utf8_lossy(bytes).into(),
+        Value::Struct(members) => members
+            .iter()
+            .map(|(name, member)| (name.clone(), to_json(member)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        Value::Array(array) => array.iter().map(to_json).collect(),
+        Value::Nil => serde_json::Value::Null,
+    }
+}
+
+/// Renders a command response according to the command's format suggestion, see [`crate::Bofh::get_format_suggestion`].
+///
+/// Each of the suggestion's `str_vars` is a format string, the names of the response fields it uses, and optionally a header
+/// that's written before its first row. The response can be a struct, or an array of structs, which are formatted one row
+/// each. A row that lacks one of the fields used by a format string is skipped for that format string, like bofhd's own
+/// clients do. Field names can have a `:type` suffix, which is ignored.
+///
+/// Responses that aren't structs, and suggestions that can't be parsed, are rendered by [`PlainFormatter`].
+#[must_use]
+pub fn format_response(suggestion: &str, value: &Value) -> String {
+    let suggestion: serde_json::Value = match serde_json::from_str(suggestion) {
+        Ok(suggestion) => suggestion,
+        Err(_) => return PlainFormatter::default().format(value),
+    };
+    let rows: Vec<&Value> = match value {
+        Value::Struct(_) => vec![value],
+        Value::Array(array)
+            if !array.is_empty() && array.iter().all(|row| row.as_struct().is_some()) =>
+        {
+            array.iter().collect()
+        }
+        _ => return PlainFormatter::default().format(value),
+    };
+    let mut result = String::new();
+    if let Some(header) = suggestion.get("hdr").and_then(serde_json::Value::as_str) {
+        result += header;
+        result += "\n";
+    }
+    for str_var in suggestion
+        .get("str_vars")
+        .and_then(serde_json::Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        let format = str_var.get(0).and_then(serde_json::Value::as_str);
+        let fields: Vec<&str> = str_var
+            .get(1)
+            .and_then(serde_json::Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(serde_json::Value::as_str)
+            .map(|field| field.split(':').next().unwrap_or(field))
+            .collect();
+        let sub_header = str_var.get(2).and_then(serde_json::Value::as_str);
+        let format = match format {
+            Some(format) => format,
+            None => continue,
+        };
+        let mut first = true;
+        for row in &rows {
+            let args: Option<Vec<Value>> = fields
+                .iter()
+                .map(|&field| row.get(field).cloned())
+                .collect();
+            if let Some(args) = args {
+                if let (true, Some(sub_header)) = (first, sub_header) {
+                    result += sub_header;
+                    result += "\n";
+                }
+                first = false;
+                result += &python_format(format, &args);
+                result += "\n";
+            }
+        }
+    }
+    result
+}
+
+/// Formats `args` according to a Python-style `%` format string, like `"%-10s %5d"`.
+///
+/// Supports the flags `-` and `0`, field widths, precision, and the conversions `s`, `r`, `d`, `i`, `f` and `x`.
+/// Missing arguments are rendered as empty strings, and unknown conversions are left as is.
+#[must_use]
+pub fn python_format(format: &str, args: &[Value]) -> String {
+    let mut result = String::new();
+    let mut args = args.iter();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        let mut spec = String::from('%');
+        let mut left_align = false;
+        let mut zero_pad = false;
+        while let Some(&flag @ ('-' | '0' | ' ' | '+' | '#')) = chars.peek() {
+            left_align |= flag == '-';
+            zero_pad |= flag == '0';
+            spec.push(flag);
+            chars.next();
+        }
+        let mut width = String::new();
+        while let Some(&digit) = chars.peek().filter(|c| c.is_ascii_digit()) {
+            width.push(digit);
+            chars.next();
+        }
+        spec.push_str(&width);
+        let mut precision = None;
+        if chars.peek() == Some(&'.') {
+            chars.next();
+            let mut digits = String::new();
+            while let Some(&digit) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                digits.push(digit);
+                chars.next();
+            }
+            spec.push('.');
+            spec.push_str(&digits);
+            precision = digits.parse::<usize>().ok();
+        }
+        let formatted = match chars.next() {
+            Some('%') => String::from('%'),
+            Some('s' | 'r' | 'd' | 'i') => {
+                let string = args.next().map(value_to_string).unwrap_or_default();
+                match precision {
+                    Some(precision) => string.chars().take(precision).collect(),
+                    None => string,
+                }
+            }
+            Some('f') => match args.next() {
+                Some(Value::Double(double)) => format!("{:.*}", precision.unwrap_or(6), double),
+                Some(Value::Int(int)) => format!("{:.*}", precision.unwrap_or(6), f64::from(*int)),
+                Some(value) => value_to_string(value),
+                None => String::new(),
+            },
+            Some('x') => match args.next() {
+                Some(Value::Int(int)) => format!("{:x}", int),
+                Some(Value::Int64(int)) => format!("{:x}", int),
+                Some(value) => value_to_string(value),
+                None => String::new(),
+            },
+            Some(other) => {
+                spec.push(other);
+                spec
+            }
+            None => spec,
+        };
+        let width = width.parse::<usize>().unwrap_or(0);
+        let padding = width.saturating_sub(formatted.chars().count());
+        if left_align {
+            result.push_str(&formatted);
+            result.push_str(&" ".repeat(padding));
+        } else if zero_pad {
+            result.push_str(&"0".repeat(padding));
+            result.push_str(&formatted);
+        } else {
+            result.push_str(&" ".repeat(padding));
+            result.push_str(&formatted);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        format_datetime, format_response, get_datetime, get_naive_datetime, limit_rows,
+        parse_datetime, python_format, to_json, DateTimeFormat, DebugFormatter, JsonFormatter,
+        OutputFormatter, PlainFormatter, RowCount,
+    };
+    use crate::Value;
+    use colored::Colorize;
+
+    #[test]
+    fn python_format_strings() {
+        assert_eq!(
+            python_format("%-6s|%4d|%%", &[Value::from("ab"), Value::Int(42)]),
+            "ab    |  42|%"
+        );
+        assert_eq!(python_format("%03i", &[Value::Int(7)]), "007");
+        assert_eq!(python_format("%.2f", &[Value::Double(1.0 / 3.0)]), "0.33");
+        assert_eq!(
+            python_format("%s (%s)", &[Value::from("posix")]),
+            "posix ()"
+        );
+        assert_eq!(python_format("%s", &[Value::Nil]), "None");
+    }
+
+    #[test]
+    fn limit_array_rows() {
+        let value = Value::Array(vec![Value::Int(1), Value::Int(2), Value::Int(3)]);
+        let (limited, count) = limit_rows(&value, Some(2));
+        assert_eq!(limited, Value::Array(vec![Value::Int(1), Value::Int(2)]));
+        assert_eq!(
+            count,
+            RowCount {
+                total: 3,
+                displayed: 2
+            }
+        );
+        assert!(count.is_truncated());
+        assert!(!limit_rows(&value, None).1.is_truncated());
+        assert!(!limit_rows(&Value::from("OK"), Some(0)).1.is_truncated());
+    }
+
+    #[test]
+    fn formatters() {
+        let value = Value::Array(vec![
+            Value::Struct(
+                [(String::from("name"), Value::from("olanor"))]
+                    .into_iter()
+                    .collect(),
+            ),
+            Value::Bool(true),
+        ]);
+        assert_eq!(
+            PlainFormatter::default().format(&value),
+            "name: olanor\nTrue\n"
+        );
+        assert_eq!(DebugFormatter.format(&Value::Int(1)), "Int(1)\n");
+        colored::control::set_override(true);
+        let colored = PlainFormatter {
+            color: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            colored.format(&value),
+            format!("{}: {}\nTrue\n", "name".bright_blue(), "olanor".green())
+        );
+        assert_eq!(
+            JsonFormatter.format(&value),
+            "[{\"name\":\"olanor\"},true]\n"
+        );
+    }
+
+    #[test]
+    fn datetimes() {
+        let datetime: iso8601::DateTime = iso8601::datetime("2023-01-31T13:37:00").unwrap();
+        let value = Value::Struct(
+            [(String::from("expire"), Value::DateTime(datetime))]
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(
+            format_datetime(&datetime, DateTimeFormat::Iso8601),
+            "2023-01-31T13:37:00"
+        );
+        assert_eq!(
+            PlainFormatter::default().format(&value),
+            "expire: 2023-01-31T13:37:00\n"
+        );
+        assert_eq!(
+            python_format("Expires %s", &[Value::DateTime(datetime)]),
+            "Expires 2023-01-31T13:37:00"
+        );
+        assert!(DebugFormatter.format(&value).contains("DateTime"));
+        assert_eq!(
+            to_json(&value).to_string(),
+            r#"{"expire":"2023-01-31T13:37:00"}"#
+        );
+
+        // Whatever the local timezone is, it's the same point in time
+        let local = PlainFormatter {
+            datetime: DateTimeFormat::Local,
+            ..Default::default()
+        }
+        .format(&Value::DateTime(datetime));
+        assert_eq!(
+            chrono::DateTime::parse_from_str(local.trim_end(), "%Y-%m-%d %H:%M:%S %:z").unwrap(),
+            chrono::DateTime::parse_from_rfc3339("2023-01-31T13:37:00Z").unwrap()
+        );
+
+        let offset = iso8601::datetime("2023-01-31T13:37:00+02:00").unwrap();
+        assert_eq!(
+            format_datetime(&offset, DateTimeFormat::Iso8601),
+            "2023-01-31T13:37:00+02:00"
+        );
+    }
+
+    #[test]
+    fn typed_datetimes() {
+        let expected = chrono::DateTime::parse_from_rfc3339("2023-01-31T13:37:00Z").unwrap();
+        let datetime = iso8601::datetime("2023-01-31T13:37:00").unwrap();
+        for value in [
+            Value::DateTime(datetime),
+            Value::from("2023-01-31T13:37:00"),
+            Value::from("2023-01-31 13:37:00.00"),
+            Value::from("20230131T13:37:00"),
+            Value::from("2023-01-31T15:37:00+02:00"),
+        ] {
+            assert_eq!(parse_datetime(&value), Some(expected), "{:?}", value);
+        }
+        assert_eq!(
+            parse_datetime(&Value::from("2023-01-31")),
+            Some(chrono::DateTime::parse_from_rfc3339("2023-01-31T00:00:00Z").unwrap())
+        );
+        assert_eq!(parse_datetime(&Value::from("olanor")), None);
+        assert_eq!(parse_datetime(&Value::Int(20230131)), None);
+
+        let account = Value::Struct(
+            [
+                (String::from("expire"), Value::DateTime(datetime)),
+                (String::from("username"), Value::from("olanor")),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(get_datetime(&account, "expire"), Some(expected));
+        assert_eq!(
+            get_naive_datetime(&account, "expire"),
+            Some(expected.naive_utc())
+        );
+        assert_eq!(get_datetime(&account, "username"), None);
+        assert_eq!(get_datetime(&account, "missing"), None);
+        assert_eq!(get_datetime(&Value::Nil, "expire"), None);
+    }
+
+    #[test]
+    fn format_suggestions() {
+        let suggestion = r#"{"hdr":"Name       Id","str_vars":[["%-10s %i",["name","id"]],["Expires %s",["expire:date"],"Expiring:"]]}"#;
+        let row = |name: &str, id: i32| {
+            Value::Struct(
+                [
+                    (String::from("name"), Value::from(name)),
+                    (String::from("id"), Value::Int(id)),
+                ]
+                .into_iter()
+                .collect(),
+            )
+        };
+        assert_eq!(
+            format_response(suggestion, &Value::Array(vec![row("g0", 0), row("g1", 1)])),
+            "Name       Id\ng0         0\ng1         1\n"
+        );
+        let mut expiring = row("g2", 2);
+        if let Value::Struct(members) = &mut expiring {
+            members.insert(String::from("expire"), Value::from("2023-01-31"));
+        }
+        assert_eq!(
+            format_response(suggestion, &expiring),
+            "Name       Id\ng2         2\nExpiring:\nExpires 2023-01-31\n"
+        );
+        assert_eq!(format_response(suggestion, &Value::from("OK")), "OK\n");
+        assert_eq!(format_response("", &row("g0", 0)), "id: 0\nname: g0\n");
+    }
+}
@@ -0,0 +1,173 @@
+//! The `help` command, which shows the server's help texts.
+//!
+//! `help` shows the general help, `help <group>` the help for a command group, and `help <group> <command>`
+//! the help for a command, along with its arguments and how its output is formatted.
+//!
+//! Like in classic bofh, a command line can also end with `?` to get help for what would come next, see [`inline_help`].
+use crate::helper::{BofhHelper, INTERNAL_COMMANDS};
+use bofh::Bofh;
+use std::fmt::Write;
+
+/// Usage and description of the commands handled by the client itself
+const INTERNAL_HELP: &[(&str, &str, &str)] = &[
+    (
+        "arghelp",
+        "arghelp <help_ref>",
+        "Show the help for an argument",
+    ),
+    (
+        "commands",
+        "commands [<command>]",
+        "List the available commands and their arguments",
+    ),
+    ("exit", "exit", "Same as quit"),
+    (
+        "fav",
+        "fav add|list|run|remove",
+        "Keep a list of favorite command lines, and run them by number",
+    ),
+    (
+        "help",
+        "help [<command> [<subcommand>]]",
+        "Show help for all commands, a command group or a command",
+    ),
+    ("logout", "logout", "End the session and log in again"),
+    ("motd", "motd", "Show the Message of the Day"),
+    (
+        "perms",
+        "perms <command> <subcommand>",
+        "Show what's known about the permissions a command needs",
+    ),
+    (
+        "plan",
+        "plan [show|run|discard]",
+        "Collect commands to review and run together",
+    ),
+    ("quit", "quit", "Log out and exit"),
+    (
+        "raw",
+        "raw <method> [<args>...]",
+        "Call a bofhd method directly (needs --allow-raw)",
+    ),
+    (
+        "reload",
+        "reload",
+        "Get the available commands from the server again",
+    ),
+    (
+        "script",
+        "script [<filename>]",
+        "Record the session to a file, or stop recording",
+    ),
+    (
+        "source",
+        "source <filename>",
+        "Run the commands in a file, one per line",
+    ),
+];
+
+/// Gets the help text for a `help` command line, where `words` are the words after `help`
+pub(crate) fn help(bofh: &Bofh, helper: &BofhHelper, words: &[&str]) -> Result<String, String> {
+    match words {
+        [] => {
+            let mut help = bofh.help().map_err(|err| err.to_string())?;
+            help += "\n\nClient commands:";
+            for (_, usage, description) in INTERNAL_HELP {
+                write!(help, "\n  {:<32} {}", usage, description).unwrap();
+            }
+            Ok(help)
+        }
+        [word] if !helper.commands.contains_key(*word) => {
+            match INTERNAL_HELP.iter().find(|(name, _, _)| name == word) {
+                Some((_, usage, description)) => Ok(format!("{}\n  {}", usage, description)),
+                None => bofh.help_group(word).map_err(|err| err.to_string()),
+            }
+        }
+        [group] => bofh.help_group(group).map_err(|err| err.to_string()),
+        [_, _] => {
+            let (group, command) = helper.resolve_with_group(words)?;
+            let mut help = bofh
+                .help_command(group, &command.name)
+                .map_err(|err| err.to_string())?;
+            let arguments: Vec<String> = command
+                .args
+                .iter()
+                .filter_map(|arg| {
+                    let arg_type = arg.arg_type.as_ref()?;
+                    Some(format!(
+                        "  {:<20} {}{}",
+                        arg_type.to_string(),
+                        arg.prompt.as_deref().unwrap_or_default(),
+                        if arg.optional { " (optional)" } else { "" }
+                    ))
+                })
+                .collect();
+            if !arguments.is_empty() {
+                write!(help, "\n\nArguments:\n{}", arguments.join("\n")).unwrap();
+            }
+            let suggestion = match &command.format_suggestion {
+                Some(suggestion) => Some(suggestion.clone()),
+                None => bofh
+                    .get_format_suggestion(command)
+                    .map_err(|err| err.to_string())?,
+            };
+            if let Some(suggestion) = suggestion.and_then(|suggestion| output_format(&suggestion)) {
+                write!(help, "\n\nOutput format:\n{}", suggestion).unwrap();
+            }
+            Ok(help)
+        }
+        _ => Err(String::from("Usage: help [<command> [<subcommand>]]")),
+    }
+}
+
+/// Gets the help for a command line that ends with `?`, where `words` are the words before the `?`.
+///
+/// After a command and any of its arguments, that's the help for the next argument, or for the command if the
+/// argument has no help of its own. Before that, it's the help for the command group, or the general help.
+pub(crate) fn inline_help(
+    bofh: &Bofh,
+    helper: &BofhHelper,
+    words: &[&str],
+) -> Result<String, String> {
+    match words {
+        [] | [_] => help(bofh, helper, words),
+        [internal, ..]
+            if INTERNAL_COMMANDS.contains(internal) && !helper.commands.contains_key(*internal) =>
+        {
+            help(bofh, helper, &[internal])
+        }
+        [_, _, args @ ..] => {
+            let (group, command) = helper.resolve_with_group(words)?;
+            let arg = command
+                .args
+                .get(args.len())
+                .or_else(|| command.args.last().filter(|last| last.repeat));
+            match arg.and_then(|arg| arg.help_ref.as_deref()) {
+                Some(help_ref) => bofh.help_arg(help_ref).map_err(|err| err.to_string()),
+                None => help(bofh, helper, &[group, &command.name]),
+            }
+        }
+    }
+}
+
+/// The header and format strings of a format suggestion, one per line
+fn output_format(suggestion: &str) -> Option<String> {
+    let suggestion: serde_json::Value = serde_json::from_str(suggestion).ok()?;
+    let header = suggestion.get("hdr").and_then(serde_json::Value::as_str);
+    let formats = suggestion
+        .get("str_vars")
+        .and_then(serde_json::Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|str_var| str_var.get(0).and_then(serde_json::Value::as_str));
+    let lines: Vec<String> = header
+        .into_iter()
+        .chain(formats)
+        .map(|line| format!("  {}", line))
+        .collect();
+    if lines.is_empty() {
+        None
+    } else {
+        Some(lines.join("\n"))
+    }
+}
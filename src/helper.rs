@@ -1,3 +1,4 @@
+use crate::tokenize::{tokenize_partial, Token};
 use colored::Colorize;
 use rustyline::Context;
 use rustyline::{
@@ -7,58 +8,398 @@ use rustyline::{
 };
 use rustyline_derive::{Helper, Validator};
 use std::borrow::Cow;
-use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 use Cow::{Borrowed, Owned};
 #[derive(Helper, Validator)]
-pub(crate) struct BofhHelper<'a> {
-    pub(crate) commands: &'a BTreeMap<String, bofh::CommandGroup>,
+pub(crate) struct BofhHelper {
+    pub(crate) commands: BTreeMap<String, bofh::CommandGroup>,
+    /// Whether to resolve commands and subcommands regardless of case, see `--normalize-commands`
+    pub(crate) normalize_commands: bool,
+    /// How typed commands and subcommands are matched, see `--match-mode`
+    pub(crate) match_mode: MatchMode,
+    /// Whether to color the line and hints, see `--no-color`
+    pub(crate) color: bool,
+    /// A command line that failed because of one of its arguments, and the index of that argument
+    argument_error: RefCell<Option<(String, usize)>>,
+    /// Values to complete for the arguments of commands whose arguments are decided by the server (with `call_prompt_func`),
+    /// by the command's full name and the argument's position, like the rows of a prompt function's `map`.
+    /// Nothing fills this in yet.
+    pub(crate) prompt_values: BTreeMap<(String, usize), Vec<String>>,
+    /// The arguments in the last hint, and whether each is required, if it hinted arguments rather than the rest of a command name
+    argument_hint: RefCell<Vec<(String, bool)>>,
 }
 
-impl BofhHelper<'_> {
-    pub(crate) fn command_candidates(&self, prefix: &str) -> Vec<&str> {
-        self.commands
-            .keys()
-            .filter_map(|command| {
-                if command.starts_with(prefix) {
-                    Some(command.as_str())
-                } else {
-                    None
-                }
-            })
+/// Commands that are handled by the client itself, rather than sent to the server
+pub(crate) const INTERNAL_COMMANDS: &[&str] = &[
+    "arghelp", "commands", "exit", "fav", "help", "logout", "motd", "perms", "plan", "quit", "raw",
+    "reload", "script", "source",
+];
+
+/// How typed command and subcommand names are matched against the available ones
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum MatchMode {
+    /// Names that start with what's typed
+    #[default]
+    Prefix,
+    /// Names that contain what's typed
+    Substring,
+    /// Names that contain the typed characters in order, like `inf` for `info` or `ifo`
+    Fuzzy,
+}
+
+impl MatchMode {
+    /// How well `name` matches `typed`, where lower is better, or `None` if it doesn't match.
+    /// Names that start with what's typed are always the best matches.
+    fn rank(self, name: &str, typed: &str) -> Option<usize> {
+        match self {
+            _ if name.starts_with(typed) => Some(0),
+            Self::Substring | Self::Fuzzy if name.contains(typed) => Some(1),
+            Self::Fuzzy => {
+                let mut chars = name.chars();
+                typed
+                    .chars()
+                    .all(|typed| chars.any(|c| c == typed))
+                    .then_some(2)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The number of ranks given by [`MatchMode::rank`]
+const RANKS: usize = 3;
+
+/// The names that match `typed`, best matches first, ignoring case if `ignore_case` is set.
+///
+/// When ignoring case, names that match the exact case are preferred, so a server command that differs
+/// from another only by case can still be reached.
+fn ranked<'a>(
+    names: impl Iterator<Item = &'a str>,
+    typed: &str,
+    ignore_case: bool,
+    mode: MatchMode,
+) -> Vec<(usize, &'a str)> {
+    let lowercase = typed.to_lowercase();
+    let mut ranked: Vec<(usize, &str)> = names
+        .filter_map(|name| {
+            let rank = match mode.rank(name, typed) {
+                Some(rank) => rank,
+                None if ignore_case => RANKS + mode.rank(&name.to_lowercase(), &lowercase)?,
+                None => return None,
+            };
+            Some((rank, name))
+        })
+        .collect();
+    ranked.sort_by_key(|&(rank, _)| rank);
+    ranked
+}
+
+/// The names that match `typed` best, see [`ranked`]
+fn candidates<'a>(
+    names: impl Iterator<Item = &'a str>,
+    typed: &str,
+    ignore_case: bool,
+    mode: MatchMode,
+) -> Vec<&'a str> {
+    let ranked = ranked(names, typed, ignore_case, mode);
+    let best = ranked.first().map(|&(rank, _)| rank);
+    ranked
+        .into_iter()
+        .take_while(|&(rank, _)| Some(rank) == best)
+        .map(|(_, name)| name)
+        .collect()
+}
+
+/// The number of characters that must be inserted, deleted or replaced to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &b) in b.iter().enumerate() {
+            let replace = previous[j] + usize::from(a != b);
+            current.push(replace.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// The name closest to `typed`, ignoring case, if it's close enough that `typed` is likely a typo of it.
+///
+/// About one edit per two typed characters is allowed, so short names aren't suggested for anything.
+fn closest<'a>(names: impl Iterator<Item = &'a str>, typed: &str) -> Option<&'a str> {
+    let typed = typed.to_lowercase();
+    let length = typed.chars().count();
+    let maximum = (length / 2).max(1);
+    names
+        .map(|name| (edit_distance(&name.to_lowercase(), &typed), name))
+        .filter(|&(distance, _)| distance <= maximum && distance < length)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, name)| name)
+}
+
+/// The end of an error message about an unknown name, suggesting the name that's closest to `typed`, if any
+pub(crate) fn did_you_mean<'a>(names: impl Iterator<Item = &'a str>, typed: &str) -> String {
+    closest(names, typed).map_or_else(String::new, |name| format!(", did you mean '{}'?", name))
+}
+
+/// Splits `candidate` after the part of it that's already `typed`, counting characters rather than bytes,
+/// so the split never lands inside a multibyte character like `ø`
+fn split_typed<'a>(candidate: &'a str, typed: &str) -> (&'a str, &'a str) {
+    let end = candidate
+        .char_indices()
+        .nth(typed.chars().count())
+        .map_or(candidate.len(), |(index, _)| index);
+    candidate.split_at(end)
+}
+
+impl BofhHelper {
+    pub(crate) fn new(commands: BTreeMap<String, bofh::CommandGroup>) -> Self {
+        Self {
+            commands,
+            normalize_commands: false,
+            match_mode: MatchMode::default(),
+            color: true,
+            argument_error: RefCell::new(None),
+            prompt_values: BTreeMap::new(),
+            argument_hint: RefCell::new(vec![]),
+        }
+    }
+
+    /// Remembers that the command on `line` failed because of its argument number `index` (counting from zero),
+    /// so the argument can be highlighted if the line is recalled from history. Forgotten when the line is edited.
+    pub(crate) fn set_argument_error(&self, line: &str, index: usize) {
+        *self.argument_error.borrow_mut() = Some((line.to_owned(), index));
+    }
+
+    /// The server's command groups and the internal commands that match `typed` best
+    pub(crate) fn command_candidates(&self, typed: &str) -> Vec<&str> {
+        candidates(self.command_names(), typed, false, self.match_mode)
+    }
+
+    /// All the server's command groups and internal commands that match `typed`, best matches first, for completion
+    pub(crate) fn command_completions(&self, typed: &str) -> Vec<&str> {
+        ranked(self.command_names(), typed, false, self.match_mode)
+            .into_iter()
+            .map(|(_, name)| name)
             .collect()
     }
 
-    pub(crate) fn subcommand_candidates(&self, command: &str, prefix: &str) -> Vec<&str> {
-        if let Some(command) = self.commands.get(command) {
-            command
-                .commands
-                .keys()
-                .filter_map(|command| {
-                    if command.starts_with(prefix) {
-                        Some(command.as_str())
-                    } else {
-                        None
-                    }
-                })
-                .collect()
+    fn command_names(&self) -> impl Iterator<Item = &str> {
+        self.commands.keys().map(String::as_str).chain(
+            INTERNAL_COMMANDS
+                .iter()
+                .copied()
+                .filter(|internal| !self.commands.contains_key(*internal)),
+        )
+    }
+
+    /// The server's command groups that match `typed` best
+    pub(crate) fn group_candidates(&self, typed: &str) -> Vec<&str> {
+        candidates(
+            self.commands.keys().map(String::as_str),
+            typed,
+            false,
+            self.match_mode,
+        )
+    }
+
+    /// The known values that start with `prefix` for argument number `index` (counting from zero) of a command
+    pub(crate) fn argument_candidates(
+        &self,
+        command: &bofh::Command,
+        index: usize,
+        prefix: &str,
+    ) -> Vec<&str> {
+        let values: Vec<&str> = if command.arity().is_none() {
+            self.prompt_values
+                .get(&(command.fullname.clone(), index))
+                .map(|values| values.iter().map(String::as_str).collect())
+                .unwrap_or_default()
         } else {
-            vec![]
+            let arg = match command.args.get(index) {
+                Some(arg) => arg,
+                None => match command.args.last() {
+                    Some(last) if last.repeat => last,
+                    _ => return vec![],
+                },
+            };
+            arg.arg_type
+                .as_ref()
+                .and_then(bofh::ArgType::values)
+                .map(<[&str]>::to_vec)
+                .unwrap_or_default()
+        };
+        values
+            .into_iter()
+            .filter(|value| value.starts_with(prefix))
+            .collect()
+    }
+
+    /// The subcommands of `command` that match `typed` best
+    pub(crate) fn subcommand_candidates(&self, command: &str, typed: &str) -> Vec<&str> {
+        match self.commands.get(command) {
+            Some(command) => candidates(
+                command.commands.keys().map(String::as_str),
+                typed,
+                false,
+                self.match_mode,
+            ),
+            None => vec![],
+        }
+    }
+
+    /// All the subcommands of `command` that match `typed`, best matches first, for completion
+    pub(crate) fn subcommand_completions(&self, command: &str, typed: &str) -> Vec<&str> {
+        match self.commands.get(command) {
+            Some(command) => ranked(
+                command.commands.keys().map(String::as_str),
+                typed,
+                false,
+                self.match_mode,
+            )
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Resolves the (possibly abbreviated) command and subcommand at the start of a command line.
+    ///
+    /// With [`Self::normalize_commands`], the command and subcommand are matched regardless of case;
+    /// the returned command has the server's casing either way.
+    ///
+    /// Returns a message suitable for the user if the command is unknown, ambiguous or incomplete.
+    pub(crate) fn resolve(&self, words: &[&str]) -> Result<&bofh::Command, String> {
+        self.resolve_with_group(words).map(|(_, command)| command)
+    }
+
+    /// Like [`Self::resolve`], but also returns the name of the command's group
+    pub(crate) fn resolve_with_group(
+        &self,
+        words: &[&str],
+    ) -> Result<(&str, &bofh::Command), String> {
+        let Some(&command) = words.first() else {
+            return Err(String::from("No command"));
+        };
+        let command_candidates = candidates(
+            self.commands.keys().map(String::as_str),
+            command,
+            self.normalize_commands,
+            self.match_mode,
+        );
+        match command_candidates.len() {
+            0 => {
+                return Err(format!(
+                    "Unknown command '{}'{}",
+                    command,
+                    did_you_mean(self.command_names(), command)
+                ))
+            }
+            1 => {}
+            _ => return Err(format!("Unknown command '{}'", command)),
+        }
+        let command_group = self.commands.get(command_candidates[0]).unwrap();
+        if words.len() < 2 {
+            return Err(format!(
+                "Incomplete command '{}', possible subcommands:\n{}",
+                command_group.name,
+                command_group
+                    .commands
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            ));
+        }
+        let subcommand_candidates = candidates(
+            command_group.commands.keys().map(String::as_str),
+            words[1],
+            self.normalize_commands,
+            self.match_mode,
+        );
+        match subcommand_candidates.as_slice() {
+            [subcommand] => Ok((
+                command_group.name.as_str(),
+                command_group.commands.get(*subcommand).unwrap(),
+            )),
+            [] => Err(format!(
+                "Unknown command '{} {}'{}",
+                command,
+                words[1],
+                closest(command_group.commands.keys().map(String::as_str), words[1]).map_or_else(
+                    String::new,
+                    |name| format!(", did you mean '{} {}'?", command_group.name, name)
+                )
+            )),
+            _ => Err(format!("Unknown command '{} {}'", command, words[1])),
         }
     }
+
+    /// All the argument help references used by the commands in the command table
+    pub(crate) fn help_refs(&self) -> BTreeSet<&str> {
+        self.commands
+            .values()
+            .flat_map(|command_group| command_group.commands.values())
+            .flat_map(|command| command.args.iter())
+            .filter_map(|arg| arg.help_ref.as_deref())
+            .collect()
+    }
+
+    pub(crate) fn help_ref_candidates(&self, prefix: &str) -> Vec<&str> {
+        self.help_refs()
+            .into_iter()
+            .filter(|help_ref| help_ref.starts_with(prefix))
+            .collect()
+    }
+}
+
+/// How an argument reads in a hint: its prompt (or its type, if it has no prompt) and its default, if any, in brackets.
+/// Optional arguments are in brackets themselves, and repeatable arguments are followed by `...`, like `[Spread [ldap]...]`
+fn argument_hint(arg: &bofh::Argument) -> Option<String> {
+    let mut hint = match (
+        arg.prompt.as_deref().filter(|prompt| !prompt.is_empty()),
+        &arg.arg_type,
+    ) {
+        (Some(prompt), _) => prompt.to_owned(),
+        (None, Some(arg_type)) => arg_type.to_string(),
+        (None, None) => return None,
+    };
+    if let Some(default) = &arg.default {
+        hint = format!("{} [{}]", hint, default);
+    }
+    if arg.repeat {
+        hint += "...";
+    }
+    if arg.optional {
+        hint = format!("[{}]", hint);
+    }
+    Some(hint)
+}
+
+/// Whether the cursor at the end of `line` starts a new word, rather than continuing the last one in `tokens`
+fn starts_new_word(line: &str, tokens: &[Token]) -> bool {
+    tokens
+        .last()
+        .is_none_or(|token| token.span.end < line.len())
 }
 
-impl Hinter for BofhHelper<'_> {
+impl Hinter for BofhHelper {
     type Hint = String;
 
     fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
-        let words: Vec<&str> = line.split_whitespace().collect();
+        self.argument_hint.borrow_mut().clear();
+        let tokens = tokenize_partial(line);
+        let words: Vec<&str> = tokens.iter().map(|token| token.value.as_str()).collect();
 
         if words.is_empty() || pos < line.len() {
             return None;
         }
 
-        let spaces = line.matches(char::is_whitespace).count();
-        let mut word_pos = pos - spaces;
+        let new_word = starts_new_word(line, &tokens);
 
         let command_candidates = self.command_candidates(words[0]);
         let subcommand_candidates = if words.len() > 1 && command_candidates.len() == 1 {
@@ -74,23 +415,22 @@ impl Hinter for BofhHelper<'_> {
                 let command = self.commands.get(command_candidates[0]).unwrap();
                 let subcommand = command.commands.get(subcommand_candidates[0]).unwrap();
                 // Hint arguments if subcommand is complete or unambiguously partial
-                if words[1] == subcommand.name || line.ends_with(char::is_whitespace) {
-                    // TODO reduce this:
-                    if words.len() >= 2 {
-                        return Some(format!(
-                            "{}{}",
-                            if line.ends_with(char::is_whitespace) {
-                                ""
-                            } else {
-                                " "
-                            },
-                            subcommand.args[words.len() - 2..]
-                                .iter()
-                                .filter_map(|arg| arg.arg_type.clone())
-                                .collect::<Vec<String>>()
-                                .join(" ")
-                        ));
+                if words[1] == subcommand.name || new_word {
+                    let hints: Vec<(String, bool)> = subcommand
+                        .remaining_args(words.len() - 2)
+                        .iter()
+                        .filter_map(|arg| Some((argument_hint(arg)?, !arg.optional)))
+                        .collect();
+                    if hints.is_empty() {
+                        return None;
                     }
+                    let usage = hints
+                        .iter()
+                        .map(|(hint, _)| hint.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(" ");
+                    *self.argument_hint.borrow_mut() = hints;
+                    return Some(format!("{}{}", if new_word { "" } else { " " }, usage));
                 }
             }
         };
@@ -101,7 +441,7 @@ impl Hinter for BofhHelper<'_> {
         // TODO In the unlikely scenario that the server only supports one command, or it has a command
         // TODO which only supports one subcommand, this will erroneously cause that (sub)command not to
         // TODO be hinted! Should probably be fixed in a better way, just in case.
-        if line.ends_with(char::is_whitespace) {
+        if new_word {
             return None;
         }
 
@@ -119,7 +459,6 @@ impl Hinter for BofhHelper<'_> {
                 })
                 .collect()
         } else if words.len() == 2 {
-            word_pos -= words[0].len();
             if command_candidates.len() == 1 {
                 subcommand_candidates
                     .iter()
@@ -138,16 +477,19 @@ impl Hinter for BofhHelper<'_> {
             return None;
         };
 
-        // We only give unambiguous hints, ie. if there is one and only one hint
-        if candidates.len() == 1 {
-            Some(candidates[0][word_pos..].to_owned())
-        } else {
-            None
+        // We only give unambiguous hints, ie. if there is one and only one hint,
+        // and only for the rest of a name that starts with what's typed
+        let typed = words[words.len() - 1];
+        match candidates.as_slice() {
+            [candidate] if candidate.starts_with(typed) => {
+                Some(split_typed(candidate, typed).1.to_owned())
+            }
+            _ => None,
         }
     }
 }
 
-impl Completer for BofhHelper<'_> {
+impl Completer for BofhHelper {
     type Candidate = Pair;
 
     fn complete(
@@ -156,23 +498,67 @@ impl Completer for BofhHelper<'_> {
         pos: usize,
         _ctx: &Context<'_>,
     ) -> rustyline::Result<(usize, Vec<Self::Candidate>)> {
-        let words: Vec<&str> = line.split_whitespace().collect();
-        let spaces = line.matches(char::is_whitespace).count();
-        let mut word_pos = pos - spaces;
+        // Only what's before the cursor decides what to complete
+        let line = &line[..pos];
+        let tokens = tokenize_partial(line);
+        let words: Vec<&str> = tokens.iter().map(|token| token.value.as_str()).collect();
+        let new_word = starts_new_word(line, &tokens);
+        // The word under the cursor is replaced from where it starts, however much whitespace and quoting came before it
+        let (start, typed) = match tokens.last() {
+            Some(token) if !new_word => (token.span.start, token.value.as_str()),
+            _ => (pos, ""),
+        };
 
         // Complete commands
         let candidates: Vec<&str> = if words.is_empty() {
-            // Completing on an empty line shows all command groups
-            self.commands.keys().map(String::as_str).collect()
+            // Completing on an empty line shows all command groups and internal commands
+            self.command_names().collect()
         } else {
             let command_candidates = self.command_candidates(words[0]);
 
-            if words.len() == 1 {
-                if line.ends_with(char::is_whitespace) {
+            if words[0] == "arghelp" {
+                // Complete argument help references
+                if words.len() == 1 && new_word {
+                    self.help_ref_candidates("")
+                } else if words.len() == 2 && !new_word {
+                    self.help_ref_candidates(words[1])
+                } else {
+                    vec![]
+                }
+            } else if words[0] == "commands" {
+                // Complete the command group to list
+                match (words.len(), new_word) {
+                    (1, true) => self.group_candidates(""),
+                    (2, false) => self.group_candidates(words[1]),
+                    _ => vec![],
+                }
+            } else if words[0] == "help" {
+                // Complete the command group and subcommand to get help for
+                match (words.len(), new_word) {
+                    (1, true) => self.group_candidates(""),
+                    (2, false) => self.group_candidates(words[1]),
+                    (2, true) => self.subcommand_candidates(words[1], ""),
+                    (3, false) => self.subcommand_candidates(words[1], words[2]),
+                    _ => vec![],
+                }
+            } else if words[0] == "fav" {
+                // Complete favorites subcommands
+                if words.len() == 1 && new_word {
+                    crate::favorites::SUBCOMMANDS.to_vec()
+                } else if words.len() == 2 && !new_word {
+                    crate::favorites::SUBCOMMANDS
+                        .iter()
+                        .copied()
+                        .filter(|subcommand| subcommand.starts_with(words[1]))
+                        .collect()
+                } else {
+                    vec![]
+                }
+            } else if words.len() == 1 {
+                if new_word {
                     // Complete subcommands
                     if command_candidates.len() == 1 {
                         if let Some(command_group) = self.commands.get(command_candidates[0]) {
-                            word_pos -= words[0].len();
                             command_group.commands.keys().map(String::as_str).collect()
                         } else {
                             vec![]
@@ -182,93 +568,441 @@ impl Completer for BofhHelper<'_> {
                     }
                 } else {
                     // Complete command group
-                    command_candidates
+                    self.command_completions(words[0])
                 }
-            } else if words.len() == 2 && !line.ends_with(char::is_whitespace) {
-                word_pos -= words[0].len();
+            } else if words.len() == 2 && !new_word {
                 // Complete subcommand
                 if command_candidates.len() == 1 {
-                    self.subcommand_candidates(command_candidates[0], words[1])
+                    self.subcommand_completions(command_candidates[0], words[1])
                 } else {
                     vec![]
                 }
+            } else if let Ok(subcommand) = self.resolve(&words) {
+                // Complete argument values, if the argument only has a few valid ones
+                let (given, prefix) = if new_word {
+                    (&words[..], "")
+                } else {
+                    (&words[..words.len() - 1], words[words.len() - 1])
+                };
+                self.argument_candidates(subcommand, given.len() - 2, prefix)
             } else {
                 vec![]
             }
         };
 
         Ok((
-            pos,
+            start,
             candidates
                 .iter()
-                .map(|&candidate| Pair {
-                    // FIXME move this to highlight_candidate when that accepts a completion::Candidate
-                    display: format!(
-                        "{}{}",
-                        &candidate[..word_pos].green(),
-                        &candidate[word_pos..].bright_green().bold()
-                    ),
-                    replacement: if candidates.len() == 1 {
-                        format!("{} ", &candidate[word_pos..])
+                .map(|&candidate| {
+                    // Only the typed start of a candidate is shown as done, not a typed substring of it
+                    let (done, rest) = if candidate.starts_with(typed) {
+                        split_typed(candidate, typed)
                     } else {
-                        candidate[word_pos..].to_owned()
-                    },
+                        ("", candidate)
+                    };
+                    Pair {
+                        // FIXME move this to highlight_candidate when that accepts a completion::Candidate
+                        display: format!("{}{}", done.green(), rest.bright_green().bold()),
+                        // The space puts the cursor where the next word starts, whether the candidate is the only one
+                        // or is picked from several. With several, the line editor only inserts what they have in
+                        // common, which never includes the space unless they're all the same.
+                        replacement: format!("{} ", candidate),
+                    }
                 })
                 .collect(),
         ))
     }
 }
 
-impl Highlighter for BofhHelper<'_> {
+impl Highlighter for BofhHelper {
     fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
-        Owned(format!("{}", hint.bright_black()))
+        if !self.color {
+            return Borrowed(hint);
+        }
+        let hints = self.argument_hint.borrow();
+        let usage = hint.trim_start();
+        let hinted: Vec<&str> = hints.iter().map(|(hint, _)| hint.as_str()).collect();
+        if hints.is_empty() || hinted.join(" ") != usage {
+            return Owned(format!("{}", hint.bright_black()));
+        }
+        // Required arguments that are still missing stand out from the optional ones
+        let hints: Vec<String> = hints
+            .iter()
+            .map(|(hint, required)| {
+                if *required {
+                    hint.yellow().to_string()
+                } else {
+                    hint.bright_black().to_string()
+                }
+            })
+            .collect();
+        Owned(format!(
+            "{}{}",
+            &hint[..hint.len() - usage.len()],
+            hints.join(" ")
+        ))
     }
 
     fn highlight<'l>(&self, line: &'l str, _: usize) -> Cow<'l, str> {
-        let words: Vec<&str> = line.split_whitespace().collect();
+        if !self.color {
+            return Borrowed(line);
+        }
+        let tokens = tokenize_partial(line);
+        let words: Vec<&str> = tokens.iter().map(|token| token.value.as_str()).collect();
 
-        if words.is_empty() {
+        // Without a command table (if the server failed to provide one), we can't tell valid commands from invalid ones
+        if words.is_empty() || self.commands.is_empty() {
             return Borrowed(line);
         }
 
+        // Highlight the argument that caused the last error, and leave the rest of the line after it alone
+        let mut argument_error = self.argument_error.borrow_mut();
+        let (line, rest) = match argument_error.as_ref() {
+            Some((error_line, index)) if error_line == line => match tokens.get(index + 2) {
+                Some(token) => (
+                    &line[..token.span.start],
+                    format!(
+                        "{}{}",
+                        line[token.span.clone()].bright_red().bold(),
+                        &line[token.span.end..]
+                    ),
+                ),
+                None => (line, String::new()),
+            },
+            Some(_) => {
+                *argument_error = None;
+                (line, String::new())
+            }
+            None => (line, String::new()),
+        };
+
         let command_candidates = self.command_candidates(words[0]);
+        let internal =
+            matches!(command_candidates.as_slice(), [name] if !self.commands.contains_key(*name));
         let subcommand_candidates = if words.len() > 1 && command_candidates.len() == 1 {
             self.subcommand_candidates(command_candidates[0], words[1])
         } else {
             vec![]
         };
 
-        let mut line = line.replace(
-            words[0],
-            &format!(
-                "{}",
-                match command_candidates.len() {
-                    0 => words[0].bright_red().bold(),
-                    1 => words[0].bright_green().bold(),
-                    _ => words[0].bright_yellow().bold(),
-                }
-            ),
-        );
+        // Color the command and subcommand where they are in the line, so the same text elsewhere
+        // (like `user` in `user user_info`) is left alone
+        let mut colors = vec![(&tokens[0], command_candidates.len())];
+        if words.len() > 1 && !internal && !INTERNAL_COMMANDS.contains(&words[0]) {
+            colors.push((&tokens[1], subcommand_candidates.len()));
+        }
+        let mut highlighted = String::with_capacity(line.len());
+        let mut end = 0;
+        for &(token, candidates) in &colors {
+            let word = &line[token.span.clone()];
+            highlighted += &line[end..token.span.start];
+            end = token.span.end;
+            let word = match candidates {
+                // Internal commands stand out from the server's commands
+                1 if internal => word.bright_cyan().bold(),
+                0 => word.bright_red().bold(),
+                1 => word.bright_green().bold(),
+                _ => word.bright_yellow().bold(),
+            };
+            highlighted += &word.to_string();
+        }
 
-        if words.len() > 1 {
-            line = line.replace(
-                words[1],
-                &format!(
-                    "{}",
-                    match subcommand_candidates.len() {
-                        0 => words[1].bright_red().bold(),
-                        1 => words[1].bright_green().bold(),
-                        _ => words[1].bright_yellow().bold(),
+        // Arguments beyond what the subcommand accepts are errors
+        if colors.len() == 2 && subcommand_candidates.len() == 1 {
+            let subcommand =
+                &self.commands[command_candidates[0]].commands[subcommand_candidates[0]];
+            if let Some((_, Some(maximum))) = subcommand.arity() {
+                for token in tokens.iter().skip(2 + maximum) {
+                    // The line may be cut short before an argument that caused an error
+                    if token.span.start >= line.len() {
+                        break;
                     }
-                ),
-            );
+                    highlighted += &line[end..token.span.start];
+                    highlighted += &line[token.span.clone()].bright_red().to_string();
+                    end = token.span.end;
+                }
+            }
         }
+        highlighted += &line[end..];
 
-        Owned(line)
+        Owned(highlighted + &rest)
     }
 
     // TODO can highlighting be optimized?
     fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
-        true
+        self.color
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn helper() -> BofhHelper {
+        let info = bofh::Command {
+            fullname: String::from("user_info"),
+            name: String::from("info"),
+            args: vec![bofh::Argument {
+                arg_type: Some(bofh::ArgType::AccountName),
+                ..Default::default()
+            }],
+            format_suggestion: None,
+            help: None,
+            read_only: None,
+            permissions: None,
+            prompt_func: false,
+        };
+        let user = bofh::CommandGroup {
+            name: String::from("user"),
+            commands: BTreeMap::from([(String::from("info"), info)]),
+        };
+        BofhHelper::new(BTreeMap::from([(String::from("user"), user)]))
+    }
+
+    #[test]
+    fn highlight_only_tokens() {
+        colored::control::set_override(true);
+        let helper = helper();
+        assert_eq!(
+            helper.highlight("user info user_info", 0),
+            format!(
+                "{} {} user_info",
+                "user".bright_green().bold(),
+                "info".bright_green().bold()
+            )
+        );
+        assert_eq!(
+            helper.highlight("  user  information", 0),
+            format!(
+                "  {}  {}",
+                "user".bright_green().bold(),
+                "information".bright_red().bold()
+            )
+        );
+    }
+
+    #[test]
+    fn highlight_internal_commands() {
+        colored::control::set_override(true);
+        let helper = helper();
+        assert_eq!(
+            helper.highlight("help user info", 0),
+            format!("{} user info", "help".bright_cyan().bold())
+        );
+        assert_eq!(
+            helper.highlight("fav add user info", 0),
+            format!("{} add user info", "fav".bright_cyan().bold())
+        );
+    }
+
+    #[test]
+    fn argument_hints() {
+        let mut arg = bofh::Argument {
+            arg_type: Some(bofh::ArgType::GroupName),
+            ..Default::default()
+        };
+        assert_eq!(argument_hint(&arg).unwrap(), "groupName");
+        arg.prompt = Some(String::from("Group"));
+        arg.default = Some(String::from("all"));
+        assert_eq!(argument_hint(&arg).unwrap(), "Group [all]");
+        arg.optional = true;
+        arg.repeat = true;
+        assert_eq!(argument_hint(&arg).unwrap(), "[Group [all]...]");
+        assert_eq!(argument_hint(&bofh::Argument::default()), None);
+    }
+
+    #[test]
+    fn argument_values() {
+        let mut helper = helper();
+        let mut command = helper.resolve(&["user", "info"]).unwrap().clone();
+        assert!(helper.argument_candidates(&command, 0, "").is_empty());
+        command.args[0].arg_type = Some(bofh::ArgType::YesNo);
+        assert_eq!(helper.argument_candidates(&command, 0, ""), ["yes", "no"]);
+        assert_eq!(helper.argument_candidates(&command, 0, "n"), ["no"]);
+        assert!(helper.argument_candidates(&command, 1, "").is_empty());
+
+        // Commands with a prompt function
+        command.args = vec![];
+        command.prompt_func = true;
+        helper
+            .prompt_values
+            .insert((command.fullname.clone(), 1), vec![String::from("posix")]);
+        assert_eq!(helper.argument_candidates(&command, 1, "p"), ["posix"]);
+    }
+
+    #[test]
+    fn highlight_extra_arguments() {
+        colored::control::set_override(true);
+        let helper = helper();
+        assert_eq!(
+            helper.highlight("user info olanor extra", 0),
+            format!(
+                "{} {} olanor {}",
+                "user".bright_green().bold(),
+                "info".bright_green().bold(),
+                "extra".bright_red()
+            )
+        );
+    }
+
+    #[test]
+    fn complete_after_extra_whitespace() {
+        let helper = helper();
+        let history = rustyline::history::History::new();
+        let ctx = Context::new(&history);
+        let complete = |line: &str, pos: usize| {
+            let (start, pairs) = helper.complete(line, pos, &ctx).unwrap();
+            let replacements: Vec<String> =
+                pairs.into_iter().map(|pair| pair.replacement).collect();
+            (start, replacements)
+        };
+        assert_eq!(complete("user  in", 8), (6, vec![String::from("info ")]));
+        let (_, all) = complete("", 0);
+        assert!(all.contains(&String::from("user ")) && all.contains(&String::from("help ")));
+        assert_eq!(complete("rel", 3), (0, vec![String::from("reload ")]));
+        assert_eq!(complete("  us", 4), (2, vec![String::from("user ")]));
+        assert_eq!(complete("  user   ", 9), (9, vec![String::from("info ")]));
+        assert_eq!(complete("user \"in x", 8), (5, vec![String::from("info ")]));
+        assert_eq!(
+            helper.hint("  user  in", 10, &ctx),
+            Some(String::from("fo"))
+        );
+    }
+
+    #[test]
+    fn complete_unique_names() {
+        let mut helper = helper();
+        helper.color = false;
+        let history = rustyline::history::History::new();
+        let ctx = Context::new(&history);
+        let complete = |line: &str| {
+            let (start, pairs) = helper.complete(line, line.len(), &ctx).unwrap();
+            let replacements: Vec<String> =
+                pairs.into_iter().map(|pair| pair.replacement).collect();
+            (start, replacements)
+        };
+
+        // A unique command group is completed with a space, after which its subcommands are completed
+        let (start, replacements) = complete("us");
+        assert_eq!(replacements, ["user "]);
+        let line = format!("{}{}", &"us"[..start], replacements[0]);
+        assert_eq!(complete(&line), (line.len(), vec![String::from("info ")]));
+
+        // A unique subcommand is completed with a space, after which its arguments are hinted
+        let (start, replacements) = complete("user i");
+        assert_eq!(replacements, ["info "]);
+        let line = format!("{}{}", &"user i"[..start], replacements[0]);
+        assert_eq!(line, "user info ");
+        assert_eq!(
+            helper.hint(&line, line.len(), &ctx),
+            Some(String::from("accountName"))
+        );
+
+        // Several candidates get the space too, for when one of them is picked
+        assert_eq!(
+            complete("s"),
+            (0, vec![String::from("script "), String::from("source ")])
+        );
+    }
+
+    #[test]
+    fn complete_non_ascii() {
+        let mut helper = helper();
+        let mut user = helper.commands["user"].clone();
+        user.name = String::from("bøker");
+        let mut info = user.commands.remove("info").unwrap();
+        info.name = String::from("lån");
+        user.commands.insert(String::from("lån"), info);
+        helper.commands.insert(String::from("bøker"), user);
+        let history = rustyline::history::History::new();
+        let ctx = Context::new(&history);
+        let complete = |line: &str| {
+            let (start, pairs) = helper.complete(line, line.len(), &ctx).unwrap();
+            let replacements: Vec<String> =
+                pairs.into_iter().map(|pair| pair.replacement).collect();
+            (start, replacements)
+        };
+        assert_eq!(complete("bø"), (0, vec![String::from("bøker ")]));
+        assert_eq!(
+            complete("bøker lå"),
+            ("bøker ".len(), vec![String::from("lån ")])
+        );
+        assert_eq!(complete("user info blåbærsyltetøy"), (10, vec![]));
+        assert_eq!(
+            helper.hint("bøker l", "bøker l".len(), &ctx),
+            Some(String::from("ån"))
+        );
+    }
+
+    #[test]
+    fn match_modes() {
+        let mut helper = helper();
+        assert!(helper.command_candidates("ser").is_empty());
+        assert!(helper.resolve(&["user", "nfo"]).is_err());
+
+        helper.match_mode = MatchMode::Substring;
+        assert_eq!(helper.command_candidates("ser"), ["user"]);
+        assert_eq!(
+            helper.resolve(&["user", "nfo"]).unwrap().fullname,
+            "user_info"
+        );
+        // Names that start with what's typed come first, and are the only ones when resolving a command
+        assert_eq!(
+            helper.command_completions("s"),
+            ["script", "source", "user", "commands", "perms"]
+        );
+        assert_eq!(helper.command_candidates("s"), ["script", "source"]);
+
+        helper.match_mode = MatchMode::Fuzzy;
+        assert_eq!(helper.command_candidates("usr"), ["user"]);
+        assert_eq!(
+            helper.resolve(&["user", "ifo"]).unwrap().fullname,
+            "user_info"
+        );
+        assert!(helper.command_candidates("rsu").is_empty());
+        // Only the rest of a name that starts with what's typed can be hinted
+        let history = rustyline::history::History::new();
+        let ctx = Context::new(&history);
+        assert_eq!(helper.hint("user ifo", 8, &ctx), None);
+    }
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(edit_distance("user", "user"), 0);
+        assert_eq!(edit_distance("usr", "user"), 1);
+        assert_eq!(edit_distance("uesr", "user"), 2);
+        assert_eq!(edit_distance("", "fav"), 3);
+
+        let helper = helper();
+        assert_eq!(helper.resolve(&[]).unwrap_err(), "No command");
+        assert_eq!(
+            helper.resolve(&["usr", "info"]).unwrap_err(),
+            "Unknown command 'usr', did you mean 'user'?"
+        );
+        assert_eq!(
+            helper.resolve(&["user", "ifno"]).unwrap_err(),
+            "Unknown command 'user ifno', did you mean 'user info'?"
+        );
+        assert_eq!(
+            helper.resolve(&["hlep", "user"]).unwrap_err(),
+            "Unknown command 'hlep', did you mean 'help'?"
+        );
+        assert_eq!(
+            helper.resolve(&["group", "info"]).unwrap_err(),
+            "Unknown command 'group'"
+        );
+        assert_eq!(closest(["fav"].into_iter(), "x"), None);
+    }
+
+    #[test]
+    fn no_color() {
+        let mut helper = helper();
+        helper.color = false;
+        assert!(matches!(
+            helper.highlight("user info", 0),
+            Borrowed("user info")
+        ));
+        assert!(matches!(helper.highlight_hint("fo"), Borrowed("fo")));
     }
 }
@@ -0,0 +1,608 @@
+//! The commands handled by the client itself, like `help`, `script` and `plan`, rather than by the server.
+//!
+//! [`run`] handles a command line if it's one of these, and tells the REPL what to do next. Commands that need more of
+//! the REPL than a [`Context`] gives, like `logout`, are left to it. [`run_line`] also runs the other command lines on
+//! the server, for the REPL and for files run with `source`.
+use crate::config::Config;
+use crate::helper::BofhHelper;
+use crate::password::PasswordSource;
+use crate::plan::Plan;
+use crate::tokenize::Token;
+use crate::{favorites, help, interrupt, prompt, source, tokenize, Args, DispatchError};
+use bofh::{Bofh, BofhError, Response, Value};
+use std::fs::File;
+
+/// What the REPL does after [`run`]
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    /// The command line was handled. Internal commands that fail or are misused aren't worth going back to in the
+    /// history, so `record` is only set if it should be kept there.
+    Handled {
+        /// Whether to add the line to the history
+        record: bool,
+    },
+    /// The command line is for the server
+    Server,
+    /// Log out, and log in again
+    Logout,
+    /// Quit the client
+    Quit,
+}
+
+/// What [`run_line`] did with a command line
+#[derive(Debug)]
+pub(crate) enum Line {
+    /// The command line was handled by the client, see [`Outcome::Handled`]
+    Handled {
+        /// Whether to add the line to the history
+        record: bool,
+    },
+    /// Log out, and log in again
+    Logout,
+    /// Quit the client
+    Quit,
+    /// The command wasn't sent to the server, like when the user cancelled a prompt for one of its arguments
+    Refused(String),
+    /// The command was sent to the server
+    Ran {
+        /// The command line that was sent, including the arguments that were prompted for
+        command: Vec<Token>,
+        result: Result<Value, DispatchError>,
+    },
+}
+
+/// The parts of the REPL that internal commands use
+pub(crate) struct Context<'a> {
+    pub(crate) bofh: &'a mut Bofh,
+    pub(crate) helper: &'a mut BofhHelper,
+    pub(crate) args: &'a Args,
+    pub(crate) config: &'a mut Config,
+    /// The plan started by `plan`, which collects commands instead of running them
+    pub(crate) plan: &'a mut Option<Plan>,
+    /// The transcript started by `script`, and the path it's written to
+    pub(crate) script: &'a mut Option<(String, File)>,
+    /// Asks the user a yes/no question, like whether to run a plan
+    pub(crate) confirm: &'a mut dyn FnMut(&str) -> bool,
+    /// Shows a prompt and reads a line, for arguments that are missing from a command line, see
+    /// [`prompt::missing_arguments`]
+    pub(crate) read: &'a mut dyn FnMut(&str, bool) -> Option<String>,
+    /// The user to log in as again when the session expires
+    pub(crate) user: &'a str,
+    pub(crate) passwords: &'a mut dyn PasswordSource,
+    /// How many files are being run with `source`, which can run other files
+    pub(crate) sourcing: usize,
+}
+
+/// Runs a command line like the REPL does: as an internal command (see [`run`]) if it is one, and otherwise on the server,
+/// after prompting for any missing arguments. If the session has expired, the user is asked to log in again, and the
+/// command is retried once.
+pub(crate) fn run_line(context: &mut Context<'_>, command: &[Token]) -> Line {
+    match run(context, command) {
+        Outcome::Handled { record } => return Line::Handled { record },
+        Outcome::Logout => return Line::Logout,
+        Outcome::Quit => return Line::Quit,
+        Outcome::Server => {}
+    }
+    // Ask for any missing arguments, so the server doesn't have to reject the command, unless it won't be run anyway
+    let words = tokenize::values(command);
+    let prompted = match context.helper.resolve(&words).cloned() {
+        Ok(subcommand) => {
+            crate::check_read_only(context.args, &words, &subcommand).and_then(|()| {
+                prompt::missing_arguments(
+                    context.bofh,
+                    &subcommand,
+                    words.get(2..).unwrap_or_default(),
+                    context.read,
+                )
+            })
+        }
+        Err(_) => Ok(vec![]),
+    };
+    let prompted = match prompted {
+        Ok(prompted) => prompted,
+        Err(err) => return Line::Refused(err),
+    };
+    // The prompted arguments are taken as they are, so one that looks like a group isn't split into words
+    let full: Vec<Token> = command
+        .iter()
+        .cloned()
+        .chain(prompted.into_iter().map(Token::literal))
+        .collect();
+    let result = match interrupt::catching(|| {
+        crate::dispatch(context.bofh, context.helper, context.args, &full)
+    }) {
+        // Log in again and retry once, so a command isn't lost when the session expires
+        Err(DispatchError::Bofh(BofhError::SessionExpiredError(request))) => {
+            eprintln!("Your session has expired, please log in again");
+            let result =
+                crate::retry_expired(context.bofh, context.user, request, context.passwords)
+                    .map(Response::into_value)
+                    .map_err(DispatchError::Bofh);
+            if !context.bofh.session_expired() {
+                crate::save_session(context.args, context.user, context.bofh);
+            }
+            result
+        }
+        result => result,
+    };
+    // The commands may have been fetched again after a server restart or a new login
+    context.helper.commands = context.bofh.commands().clone();
+    Line::Ran {
+        command: full,
+        result,
+    }
+}
+
+/// Runs `command` if it's an internal command, or adds it to the plan if one is being collected
+pub(crate) fn run(context: &mut Context<'_>, command: &[Token]) -> Outcome {
+    let record = match tokenize::values(command).as_slice() {
+        [] => true,
+        [words @ .., "?"] => {
+            let result = help::inline_help(context.bofh, context.helper, words);
+            // Fetching a command's help stores it in the command table
+            context.helper.commands = context.bofh.commands().clone();
+            print(result)
+        }
+        ["plan", rest @ ..] => plan(context, rest),
+        ["help", rest @ ..] => {
+            let result = help::help(context.bofh, context.helper, rest);
+            context.helper.commands = context.bofh.commands().clone();
+            print(result)
+        }
+        ["script", rest @ ..] => script(context.script, rest),
+        ["source", path] => match source::source(path, context) {
+            Ok(0) => true,
+            Ok(failures) => {
+                eprintln!("Failed commands in {}: {}", path, failures);
+                true
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                false
+            }
+        },
+        ["source", ..] => usage("source <filename>"),
+        ["raw", ..] if !context.args.allow_raw => {
+            eprintln!("Raw bofhd calls are disabled, start the client with --allow-raw");
+            false
+        }
+        ["raw", method, raw_args @ ..] => {
+            match context.bofh.run_raw(method, raw_args) {
+                Ok(value) => println!("{:?}", value),
+                Err(err) => eprintln!("{}", err),
+            }
+            true
+        }
+        ["raw"] => usage("raw <method> [<args>...]"),
+        ["quit" | "exit"] => return Outcome::Quit,
+        ["logout"] => return Outcome::Logout,
+        ["commands"] => print(crate::list_commands(context.helper, None)),
+        ["commands", name] => print(crate::list_commands(context.helper, Some(*name))),
+        ["commands", ..] => usage("commands [<command>]"),
+        ["fav", ..] => match favorites::fav(
+            &command[1..],
+            context.config,
+            context.bofh,
+            context.helper,
+            context.args,
+        ) {
+            Ok(()) => true,
+            Err(err) => {
+                eprintln!("{}", err);
+                false
+            }
+        },
+        ["perms"] => usage("perms <command> <subcommand>"),
+        ["perms", rest @ ..] => {
+            println!("{}", crate::describe_permissions(context.helper, rest));
+            true
+        }
+        ["reload", ..] => {
+            match context.bofh.init_commands(true) {
+                Ok(()) => {
+                    context.helper.commands = context.bofh.commands().clone();
+                    println!("Reloaded the command table");
+                }
+                Err(err) => eprintln!("{}", err),
+            }
+            true
+        }
+        ["motd", ..] => {
+            match context.bofh.refresh_motd() {
+                Ok(_) => println!("{}", context.bofh.motd.as_deref().unwrap_or_default()),
+                Err(err) => eprintln!("{}", err),
+            }
+            true
+        }
+        ["arghelp", help_ref] if context.helper.help_refs().contains(help_ref) => {
+            match context.bofh.help_arg(help_ref) {
+                Ok(help) => println!("{}", help),
+                Err(err) => eprintln!("{}", err),
+            }
+            true
+        }
+        ["arghelp", help_ref] => {
+            eprintln!("Unknown argument help reference '{}'", help_ref);
+            false
+        }
+        ["arghelp", ..] => usage("arghelp <help_ref>"),
+        _ => match context.plan {
+            Some(current) => {
+                match current.add(context.helper, context.args, &tokenize::join(command)) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        false
+                    }
+                }
+            }
+            None => return Outcome::Server,
+        },
+    };
+    Outcome::Handled { record }
+}
+
+/// Prints the result of an internal command, and returns whether it succeeded
+fn print(result: Result<String, String>) -> bool {
+    match result {
+        Ok(output) => {
+            println!("{}", output);
+            true
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            false
+        }
+    }
+}
+
+/// Prints the usage of a misused internal command
+fn usage(usage: &str) -> bool {
+    eprintln!("Usage: {}", usage);
+    false
+}
+
+/// The `plan` command, which starts collecting commands, or shows, runs or discards the ones collected
+fn plan(context: &mut Context<'_>, command: &[&str]) -> bool {
+    match (command.first().copied(), context.plan.take()) {
+        (None, None) => {
+            println!(
+                "Collecting commands for a plan instead of running them. \
+                 Use 'plan show', 'plan run' or 'plan discard'."
+            );
+            *context.plan = Some(Plan::default());
+        }
+        (None | Some("show"), Some(current)) => {
+            current.show();
+            *context.plan = Some(current);
+        }
+        (Some("run"), Some(current)) => {
+            current.show();
+            if (context.confirm)(&format!(
+                "Run these {} commands? Commands that succeed before a failure are not rolled back [y/N] ",
+                current.commands.len()
+            )) {
+                if let Err(err) = current.run(context.bofh, context.helper, context.args) {
+                    eprintln!("{}", err);
+                }
+            } else {
+                println!("Not running the plan");
+                *context.plan = Some(current);
+            }
+        }
+        (Some("discard"), Some(_)) => println!("Discarded the plan"),
+        (_, current) => {
+            *context.plan = current;
+            return usage("plan [show|run|discard]");
+        }
+    }
+    true
+}
+
+/// The `script` command, which starts recording a transcript of the session to a file, or stops it
+fn script(script: &mut Option<(String, File)>, command: &[&str]) -> bool {
+    match (command, script.take()) {
+        ([], Some((path, _))) => println!("Stopped recording to {}", path),
+        ([], None) => {
+            eprintln!("Not recording, start with 'script <filename>'");
+            return false;
+        }
+        ([path], None) => match File::create(path) {
+            Ok(file) => {
+                println!("Recording the session to {}", path);
+                *script = Some(((*path).to_owned(), file));
+            }
+            Err(err) => {
+                eprintln!("Failed to open {}: {}", path, err);
+                return false;
+            }
+        },
+        ([_], Some((path, file))) => {
+            eprintln!("Already recording to {}, stop with 'script'", path);
+            *script = Some((path, file));
+            return false;
+        }
+        (_, current) => {
+            *script = current;
+            return usage("script [<filename>]");
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bofh::transport::MockTransport;
+    use bofh::BofhBuilder;
+    use clap::Parser;
+    use std::io;
+    use std::sync::Arc;
+
+    /// The words of a command line
+    fn line(line: &str) -> Vec<Token> {
+        tokenize::tokenize(line).unwrap()
+    }
+
+    fn server() -> MockTransport {
+        let user_info = Value::Array(vec![
+            Value::Array(vec![Value::from("user"), Value::from("info")]),
+            Value::Array(vec![Value::Struct(
+                [(String::from("type"), Value::from("accountName"))]
+                    .into_iter()
+                    .collect(),
+            )]),
+        ]);
+        MockTransport::new()
+            .respond("get_motd", "Welcome")
+            .respond("login", "session")
+            .respond(
+                "get_commands",
+                Value::Struct(
+                    [(String::from("user_info"), user_info)]
+                        .into_iter()
+                        .collect(),
+                ),
+            )
+            .respond("logout", Value::Nil)
+    }
+
+    fn connect_to(transport: &Arc<MockTransport>) -> Bofh {
+        BofhBuilder::new(String::from("https://bofhd.example.com:8000"))
+            .transport(Arc::clone(transport) as _)
+            .connect()
+            .unwrap()
+            .login("olanor", String::from("secret"))
+            .unwrap()
+    }
+
+    fn connect() -> Bofh {
+        connect_to(&Arc::new(server()))
+    }
+
+    #[test]
+    fn outcomes() {
+        let mut bofh = connect();
+        let mut helper = BofhHelper::new(bofh.commands().clone());
+        let args = Args::parse_from(["bofh"]);
+        let mut config = Config::default();
+        let mut plan = None;
+        let mut script = None;
+        let mut confirm = |_: &str| false;
+        let mut context = Context {
+            bofh: &mut bofh,
+            helper: &mut helper,
+            args: &args,
+            config: &mut config,
+            plan: &mut plan,
+            script: &mut script,
+            confirm: &mut confirm,
+            read: &mut |_, _| None,
+            user: "olanor",
+            passwords: &mut |_: &str| Ok(String::from("secret")),
+            sourcing: 0,
+        };
+        let handled = |record| Outcome::Handled { record };
+
+        assert_eq!(run(&mut context, &line("")), handled(true));
+        assert_eq!(run(&mut context, &line("quit")), Outcome::Quit);
+        assert_eq!(run(&mut context, &line("exit")), Outcome::Quit);
+        assert_eq!(run(&mut context, &line("logout")), Outcome::Logout);
+        assert_eq!(
+            run(&mut context, &line("user info olanor")),
+            Outcome::Server
+        );
+
+        // Misused or failing internal commands aren't kept in the history
+        assert_eq!(run(&mut context, &line("commands")), handled(true));
+        assert_eq!(run(&mut context, &line("commands a b")), handled(false));
+        assert_eq!(run(&mut context, &line("commands nosuch")), handled(false));
+        assert_eq!(run(&mut context, &line("perms")), handled(false));
+        assert_eq!(run(&mut context, &line("perms user info")), handled(true));
+        assert_eq!(run(&mut context, &line("raw get_motd")), handled(false));
+        assert_eq!(run(&mut context, &line("source")), handled(false));
+        assert_eq!(run(&mut context, &line("arghelp")), handled(false));
+        assert_eq!(run(&mut context, &line("arghelp nosuch")), handled(false));
+        assert_eq!(run(&mut context, &line("script")), handled(false));
+        assert_eq!(run(&mut context, &line("script a b")), handled(false));
+        assert!(context.script.is_none());
+        assert_eq!(run(&mut context, &line("motd")), handled(true));
+        assert_eq!(run(&mut context, &line("reload")), handled(true));
+        assert_eq!(context.helper.commands.len(), 1);
+    }
+
+    #[test]
+    fn plans() {
+        let mut bofh = connect();
+        let mut helper = BofhHelper::new(bofh.commands().clone());
+        let args = Args::parse_from(["bofh"]);
+        let mut config = Config::default();
+        let mut plan = None;
+        let mut script = None;
+        let mut questions = vec![];
+        let mut confirm = |question: &str| {
+            questions.push(question.to_owned());
+            false
+        };
+        let mut context = Context {
+            bofh: &mut bofh,
+            helper: &mut helper,
+            args: &args,
+            config: &mut config,
+            plan: &mut plan,
+            script: &mut script,
+            confirm: &mut confirm,
+            read: &mut |_, _| None,
+            user: "olanor",
+            passwords: &mut |_: &str| Ok(String::from("secret")),
+            sourcing: 0,
+        };
+        let handled = |record| Outcome::Handled { record };
+
+        assert_eq!(run(&mut context, &line("plan show")), handled(false));
+        assert!(context.plan.is_none());
+        assert_eq!(run(&mut context, &line("plan")), handled(true));
+        // Commands for the server are collected instead of run, and unknown ones are refused
+        assert_eq!(run(&mut context, &line("user info olanor")), handled(true));
+        assert_eq!(run(&mut context, &line("user nosuch")), handled(false));
+        assert_eq!(
+            context.plan.as_ref().unwrap().commands,
+            ["user info olanor"]
+        );
+        // Declining to run the plan keeps it
+        assert_eq!(run(&mut context, &line("plan run")), handled(true));
+        assert!(context.plan.is_some());
+        assert_eq!(run(&mut context, &line("plan nosuch")), handled(false));
+        assert!(context.plan.is_some());
+        assert_eq!(run(&mut context, &line("plan discard")), handled(true));
+        assert!(context.plan.is_none());
+        assert_eq!(
+            run(&mut context, &line("user info olanor")),
+            Outcome::Server
+        );
+        assert_eq!(questions.len(), 1);
+        assert!(questions[0].starts_with("Run these 1 commands?"));
+    }
+
+    #[test]
+    fn lines() {
+        let transport = Arc::new(
+            server()
+                .respond("run_command", "Account: olanor")
+                .fault_once(
+                    "run_command",
+                    "Cerebrum.modules.bofhd.errors.SessionExpiredError:Session expired",
+                ),
+        );
+        let mut bofh = connect_to(&transport);
+        let mut helper = BofhHelper::new(bofh.commands().clone());
+        let args = Args::parse_from(["bofh"]);
+        let mut config = Config::default();
+        let mut plan = None;
+        let mut script = None;
+        let mut prompts = vec![];
+        let mut read = |prompt: &str, _| {
+            prompts.push(prompt.to_owned());
+            Some(String::from("olanor"))
+        };
+        let mut passwords = vec!["secret"];
+        let mut next_password = |_: &str| {
+            passwords
+                .pop()
+                .map(String::from)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+        };
+        let mut context = Context {
+            bofh: &mut bofh,
+            helper: &mut helper,
+            args: &args,
+            config: &mut config,
+            plan: &mut plan,
+            script: &mut script,
+            confirm: &mut |_| false,
+            read: &mut read,
+            user: "olanor",
+            passwords: &mut next_password,
+            sourcing: 0,
+        };
+
+        assert!(matches!(
+            run_line(&mut context, &line("commands")),
+            Line::Handled { record: true }
+        ));
+        assert!(matches!(run_line(&mut context, &line("quit")), Line::Quit));
+        // The expired session is logged in again, and the missing argument is prompted for
+        match run_line(&mut context, &line("user info")) {
+            Line::Ran { command, result } => {
+                assert_eq!(tokenize::values(&command), ["user", "info", "olanor"]);
+                assert_eq!(result.unwrap(), Value::from("Account: olanor"));
+            }
+            line => panic!("unexpected {:?}", line),
+        }
+        assert!(!context.bofh.session_expired());
+        assert!(matches!(
+            run_line(&mut context, &line("user nosuch")),
+            Line::Ran {
+                result: Err(DispatchError::Rejected(_)),
+                ..
+            }
+        ));
+        assert_eq!(prompts, ["accountName: "]);
+        assert_eq!(
+            transport
+                .calls()
+                .iter()
+                .filter(|(method, _)| method == "login")
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn sourced_lines() {
+        let transport = Arc::new(server().respond("run_command", "Account: olanor"));
+        let mut bofh = connect_to(&transport);
+        let mut helper = BofhHelper::new(bofh.commands().clone());
+        let args = Args::parse_from(["bofh"]);
+        let mut config = Config::default();
+        let mut plan = None;
+        let mut script = None;
+        let mut context = Context {
+            bofh: &mut bofh,
+            helper: &mut helper,
+            args: &args,
+            config: &mut config,
+            plan: &mut plan,
+            script: &mut script,
+            confirm: &mut |_| false,
+            read: &mut |_, _| None,
+            user: "olanor",
+            passwords: &mut |_: &str| Ok(String::from("secret")),
+            sourcing: 0,
+        };
+
+        let path = std::env::temp_dir().join(format!("bofh-source-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        // Lines with only a format have nothing to run, and internal commands are run like at the prompt
+        std::fs::write(
+            path,
+            "# comment\n--json\nformat json\nmotd\nuser info olanor --json\nuser nosuch\nquit\n",
+        )
+        .unwrap();
+        assert_eq!(source::source(path, &mut context), Ok(2));
+        // A file that runs itself stops eventually
+        std::fs::write(path, format!("source {}\n", path)).unwrap();
+        let result = source::source(path, &mut context);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(result, Ok(0));
+        assert_eq!(context.sourcing, 0);
+        assert_eq!(
+            transport
+                .calls()
+                .iter()
+                .filter(|(method, _)| method == "run_command")
+                .count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,41 @@
+//! Abandoning a running command with Ctrl-C.
+//!
+//! While a command runs, Ctrl-C sets a flag instead of ending the program, which makes the client stop waiting for the
+//! server (see [`bofh::BofhBuilder::interrupt`]) and return to the prompt. The server may still complete the command.
+//! At the prompt, Ctrl-C is handled by the line editor as usual. This needs Unix signals; elsewhere, Ctrl-C during a
+//! command ends the program.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+/// Set when Ctrl-C is pressed while a command runs
+static INTERRUPTED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+/// The flag that's set when Ctrl-C is pressed while a command runs, for [`bofh::BofhBuilder::interrupt`]
+pub(crate) fn flag() -> Arc<AtomicBool> {
+    Arc::clone(INTERRUPTED.get_or_init(Arc::default))
+}
+
+/// Runs `run`, with Ctrl-C setting the interrupt flag instead of ending the program
+pub(crate) fn catching<T>(run: impl FnOnce() -> T) -> T {
+    flag().store(false, Ordering::SeqCst);
+    #[cfg(unix)]
+    // SAFETY: The handler only stores to an atomic that's already initialized
+    let previous = unsafe {
+        let handler: extern "C" fn(libc::c_int) = on_interrupt;
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t)
+    };
+    let result = run();
+    #[cfg(unix)]
+    if previous != libc::SIG_ERR {
+        // SAFETY: Restores the handler that was there before
+        unsafe { libc::signal(libc::SIGINT, previous) };
+    }
+    result
+}
+
+#[cfg(unix)]
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    if let Some(flag) = INTERRUPTED.get() {
+        flag.store(true, Ordering::SeqCst);
+    }
+}
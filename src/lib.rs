@@ -1,6 +1,28 @@
+pub mod format;
+pub mod transport;
+
+/// The XML-RPC values that commands take and return, re-exported so clients don't need to depend on the same version of
+/// [`xmlrpc`].
+///
+/// This is the only `xmlrpc` type in the API apart from [`BofhError::XmlRpcError`], and [`Response`] has accessors for
+/// the common shapes of results, so most clients only need [`Value`] for the values inside them.
+pub use xmlrpc::Value;
+
+use format::OutputFormatter;
+use reqwest::blocking::Client;
+use reqwest::Certificate;
+use std::cell::{Cell, Ref, RefCell};
 use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Once};
+use std::time::Duration;
 use thiserror::Error;
-use xmlrpc::{Request, Value};
+use transport::{HttpTransport, Transport};
 
 /// Errors that might occur when communicating with a bofhd server.
 #[derive(Error, Debug)]
@@ -8,63 +30,654 @@ pub enum BofhError {
     /// Error occurring in the XML-RPC protocol
     #[error("{0}")]
     XmlRpcError(#[from] xmlrpc::Error),
-    /// Attempted to run authenticated command before session was established
-    #[error("Attempted to run authenticated command before session was established")]
+    /// Attempted to run an authenticated command after the session was ended with [`Bofh::end_session`]
+    #[error("Attempted to run authenticated command without a session")]
     NoSessionError,
     /// Error in a Cerebrum/bofhd command
+    #[error("{message}")]
+    CerebrumError {
+        /// The class of the error, for telling errors apart programmatically, like `CerebrumError`,
+        /// or the path to the exception that caused it, like `Cerebrum.Errors.NotFoundError`
+        kind: String,
+        /// The description of the error, for the user
+        message: String,
+    },
+    /// The user isn't allowed to run a command, or to run it on the given arguments, and must be granted access to it
     #[error("{0}")]
-    CerebrumError(String),
-    /// Server restarted in the middle of the session
+    PermissionDenied(String),
+    /// Server restarted in the middle of the session, and kept reporting that while the client recovered from it.
+    ///
+    /// A single restart is handled transparently, by re-fetching the commands and retrying the request.
     #[error("Server restarted")]
     ServerRestartedError,
-    /// Session has expired, and the client must re-authenticate
+    /// Session has expired, and the client must re-authenticate.
+    ///
+    /// Holds the request that failed, which can be retried with [`Bofh::retry_after_login`].
     #[error("Session expired")]
-    SessionExpiredError,
+    SessionExpiredError(ExpiredRequest),
     /// The bofhd server reported that a command was not implemented
     #[error("{0}")]
     NotImplementedError(String),
     /// XML-RPC request reported a fault
     #[error("{0}")]
     Fault(String),
+    /// Error occurring in the HTTP transport
+    #[error("{0}")]
+    HttpError(#[from] reqwest::Error),
+    /// The server didn't respond in time, see [`BofhBuilder::timeout`]
+    #[error("Server not responding (timed out)")]
+    TimeoutError,
+    /// The request was abandoned before the server responded, see [`BofhBuilder::interrupt`].
+    ///
+    /// The server may still carry out the command.
+    #[error("Interrupted (the command may still complete on the server)")]
+    Interrupted,
+    /// The request was lost before the server responded, because the thread sending it stopped unexpectedly.
+    ///
+    /// The server may still carry out the command.
+    #[error("The request was lost (the command may still complete on the server)")]
+    RequestLost,
+    /// The server responded with valid XML-RPC, but not with what bofhd is expected to respond with
+    #[error("Malformed response from the server: {0}")]
+    MalformedResponse(String),
+    /// A command was given too few or too many arguments, so it wasn't sent to the server, see [`Command::validate_args`]
+    #[error("Wrong number of arguments, expected {expected} but got {got}")]
+    ArgumentError {
+        /// How many arguments the command takes, like `1`, `1 to 2` or `at least 1`
+        expected: String,
+        /// The number of arguments that were given
+        got: usize,
+    },
+    /// A CA certificate couldn't be read, see [`BofhBuilder::ca_cert`]
+    #[error("Invalid CA certificate {0}")]
+    CertificateError(String),
+    /// Error writing command output
+    #[error("{0}")]
+    IoError(#[from] std::io::Error),
+    /// The server responded with something other than XML-RPC, like an HTML error page
+    #[error("The server doesn't look like a bofhd XML-RPC server (HTTP status {status}), check the URL and port. Response: {snippet}")]
+    NotXmlRpcError {
+        /// The HTTP status code of the response
+        status: u16,
+        /// The start of the response body
+        snippet: String,
+    },
+}
+
+/// A session-scoped request that failed because the session had expired, see [`Bofh::retry_after_login`]
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct ExpiredRequest {
+    /// The raw bofhd command, like `run_command`
+    pub command: String,
+    /// The command's arguments, without the session identifier
+    pub args: Vec<CommandArg>,
+}
+
+/// An argument to a bofhd command, see [`Bofh::run_command_args`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandArg {
+    /// A single value, which is sent as an XML-RPC string
+    Value(String),
+    /// A group of values, like `person affiliation (STUDENT ansatt)` in other bofh clients, which is sent as an XML-RPC
+    /// array of strings in the argument's place. An empty group is an empty array
+    Group(Vec<String>),
+    /// A list of arguments that may themselves be lists or maps, for commands that take nested structures, which is sent
+    /// as an XML-RPC array in the argument's place
+    List(Vec<CommandArg>),
+    /// Named arguments, which are sent as an XML-RPC struct in the argument's place
+    Map(BTreeMap<String, CommandArg>),
+}
+
+impl From<&str> for CommandArg {
+    fn from(value: &str) -> Self {
+        Self::Value(value.to_owned())
+    }
+}
+
+impl From<String> for CommandArg {
+    fn from(value: String) -> Self {
+        Self::Value(value)
+    }
+}
+
+impl From<CommandArg> for Value {
+    fn from(arg: CommandArg) -> Self {
+        match arg {
+            CommandArg::Value(value) => Self::String(value),
+            CommandArg::Group(values) => {
+                Self::Array(values.into_iter().map(Self::String).collect())
+            }
+            CommandArg::List(args) => Self::Array(args.into_iter().map(Self::from).collect()),
+            CommandArg::Map(args) => Self::Struct(
+                args.into_iter()
+                    .map(|(name, arg)| (name, Self::from(arg)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// The result of a bofh command, see [`Bofh::run_command`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response(Value);
+
+impl Response {
+    /// The rows of a result that's a list of structs, like `group list`, or the one row of a single struct.
+    ///
+    /// Anything else has no rows, including the values in a list that aren't structs.
+    #[must_use]
+    pub fn as_rows(&self) -> Vec<BTreeMap<String, Value>> {
+        match &self.0 {
+            Value::Array(values) => values
+                .iter()
+                .filter_map(Value::as_struct)
+                .cloned()
+                .collect(),
+            Value::Struct(row) => vec![row.clone()],
+            _ => vec![],
+        }
+    }
+
+    /// The result if it's a single struct, like `user info`
+    #[must_use]
+    pub fn as_single(&self) -> Option<&BTreeMap<String, Value>> {
+        self.0.as_struct()
+    }
+
+    /// The result if it's a message, like `OK, password changed` from `user password`
+    #[must_use]
+    pub fn as_message(&self) -> Option<&str> {
+        self.0.as_str()
+    }
+
+    /// The result as the server sent it
+    #[must_use]
+    pub fn value(&self) -> &Value {
+        &self.0
+    }
+
+    /// The result as the server sent it
+    #[must_use]
+    pub fn into_value(self) -> Value {
+        self.0
+    }
+}
+
+impl From<Value> for Response {
+    fn from(value: Value) -> Self {
+        Self(value)
+    }
+}
+
+impl From<Response> for Value {
+    fn from(response: Response) -> Self {
+        response.0
+    }
+}
+
+impl fmt::Debug for ExpiredRequest {
+    /// Leaves out the arguments, which may contain passwords
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExpiredRequest")
+            .field("command", &self.command)
+            .field("args", &format_args!("[{} arguments]", self.args.len()))
+            .finish()
+    }
 }
 
 /// A bofhd command
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Command {
-    /// The actual, full bofhd command name, which can be supplied to [`Bofh::run_command`]
+    /// The actual, full bofhd command name, which can be supplied to [`Bofh::run_command_str`]
     pub fullname: String,
     /// The name of this subcommand
     pub name: String,
     /// Valid arguments to this command
     pub args: Vec<Argument>,
-    /// Output format suggestion for clients
+    /// Output format suggestion for clients, as the JSON encoding of the struct sent by the server.
+    /// Only filled in if [`Bofh::prefetch_format_suggestions`] is set; otherwise, see [`Bofh::get_format_suggestion`]
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
     pub format_suggestion: Option<String>,
-    /// Help text for command, supplied by the server
+    /// Help text for command, supplied by the server. Filled in on demand by [`Bofh::help_command`]
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
     pub help: Option<String>,
+    /// Whether this command only reads state, if the server says so.
+    ///
+    /// bofhd doesn't currently annotate its commands like this, so this is usually `None`; see [`Command::is_read_only`] for a best-effort guess.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub read_only: Option<bool>,
+    /// The permissions (bofhd operations) needed to run this command, if the server says so.
+    ///
+    /// bofhd doesn't currently expose these, so this is usually `None`. Note that bofhd only lists the commands
+    /// the user is allowed to run in [`Bofh::get_commands`], so a command's presence there is a hint in itself.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub permissions: Option<Vec<String>>,
+    /// Whether the server decides the arguments as they're given, with [`Bofh::call_prompt_func`], instead of listing them
+    /// in [`Self::args`], which is then empty.
+    ///
+    /// Clients must prompt for such a command's arguments one by one, rather than pass them all at once.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub prompt_func: bool,
+}
+
+/// Subcommand names that conventionally only read state
+const READ_ONLY_SUBCOMMANDS: &[&str] = &[
+    "info", "list", "show", "search", "find", "history", "stats", "get", "status",
+];
+
+impl Command {
+    /// Whether this command only reads state.
+    ///
+    /// If the server hasn't annotated the command (see [`Command::read_only`]), this is a heuristic
+    /// based on the subcommand name: `info`, `list`, `search` and the like are considered read-only,
+    /// and everything else is assumed to modify state. The heuristic can't know what a command
+    /// actually does on the server, so it should only be used for safety nets, never for granting access.
+    #[must_use]
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.unwrap_or_else(|| {
+            READ_ONLY_SUBCOMMANDS.contains(&self.name.as_str())
+                || self.name.starts_with("list_")
+                || self.name.ends_with("_info")
+                || self.name.ends_with("_list")
+        })
+    }
+
+    /// The argument types of this command, with optional arguments in brackets and repeatable arguments followed by `...`,
+    /// like `accountName [email] group...`
+    #[must_use]
+    pub fn usage(&self) -> String {
+        self.usage_after(0)
+    }
+
+    /// How many arguments this command takes, as the number of required arguments and the maximum number, if there is one.
+    ///
+    /// Returns `None` if the arguments are decided by the server as they're given (with `call_prompt_func`).
+    #[must_use]
+    pub fn arity(&self) -> Option<(usize, Option<usize>)> {
+        if self.prompt_func {
+            return None;
+        }
+        let required = self.args.iter().filter(|arg| !arg.optional).count();
+        let maximum = if self.args.iter().any(|arg| arg.repeat) {
+            None
+        } else {
+            Some(self.args.len())
+        };
+        Some((required, maximum))
+    }
+
+    /// Checks that the command takes `got` arguments, going by its argument list.
+    ///
+    /// Optional arguments may be left out, and a repeatable argument absorbs any extra arguments. Commands whose arguments
+    /// are decided by the server as they're given (with `call_prompt_func`) aren't checked.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError::ArgumentError`] if the command doesn't take that many arguments.
+    pub fn validate_args(&self, got: usize) -> Result<(), BofhError> {
+        let Some((required, maximum)) = self.arity() else {
+            return Ok(());
+        };
+        if got >= required && maximum.is_none_or(|maximum| got <= maximum) {
+            return Ok(());
+        }
+        Err(BofhError::ArgumentError {
+            expected: match maximum {
+                Some(maximum) if maximum == required => required.to_string(),
+                Some(maximum) => format!("{} to {}", required, maximum),
+                None => format!("at least {}", required),
+            },
+            got,
+        })
+    }
+
+    /// Packs the values given for a repeatable last argument into a single [`CommandArg::Group`] in its place, which is how
+    /// bofhd expects them: it runs the command once for each value in the array.
+    ///
+    /// The values of groups and lists are packed with the rest. If any of the values is a list or a map, they're packed into
+    /// a [`CommandArg::List`] instead. A single value is left as it is, and so are the arguments of commands without a
+    /// repeatable last argument.
+    #[must_use]
+    pub fn pack_repeated_args(&self, args: &[CommandArg]) -> Vec<CommandArg> {
+        match self.args.last() {
+            Some(last) if last.repeat && args.len() > self.args.len() => {
+                let (single, repeated) = args.split_at(self.args.len() - 1);
+                let values: Vec<CommandArg> = repeated
+                    .iter()
+                    .flat_map(|arg| match arg {
+                        CommandArg::Group(values) => {
+                            values.iter().cloned().map(CommandArg::Value).collect()
+                        }
+                        CommandArg::List(args) => args.clone(),
+                        arg => vec![arg.clone()],
+                    })
+                    .collect();
+                let strings: Option<Vec<String>> = values
+                    .iter()
+                    .map(|value| match value {
+                        CommandArg::Value(value) => Some(value.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                let packed = match strings {
+                    Some(strings) => CommandArg::Group(strings),
+                    None => CommandArg::List(values),
+                };
+                single
+                    .iter()
+                    .cloned()
+                    .chain(std::iter::once(packed))
+                    .collect()
+            }
+            _ => args.to_vec(),
+        }
+    }
+
+    /// The arguments to bofhd's `run_command` for running this command with `args`: the command's full name, followed by
+    /// `args` with any repeated values packed together.
+    fn request_args(&self, args: &[CommandArg]) -> Result<Vec<CommandArg>, BofhError> {
+        self.validate_args(args.len())?;
+        let mut request_args = vec![CommandArg::from(self.fullname.as_str())];
+        request_args.extend(self.pack_repeated_args(args));
+        Ok(request_args)
+    }
+
+    /// The arguments as they can be logged, with passwords masked. Arguments of unknown type, like those of a command whose
+    /// arguments are decided by the server, are masked too.
+    fn logged_args(&self, args: &[CommandArg]) -> Vec<String> {
+        args.iter()
+            .enumerate()
+            .map(|(index, arg)| match self.remaining_args(index).first() {
+                Some(Argument {
+                    arg_type: Some(arg_type),
+                    ..
+                }) if *arg_type != ArgType::AccountPassword => format!("{:?}", arg),
+                _ => String::from("<redacted>"),
+            })
+            .collect()
+    }
+
+    /// The arguments that remain after `given` arguments have been supplied.
+    ///
+    /// If all the arguments have been supplied, there are none, unless the last argument can be repeated, in which case it's that argument.
+    #[must_use]
+    pub fn remaining_args(&self, given: usize) -> &[Argument] {
+        match self.args.last() {
+            Some(last) if last.repeat && given >= self.args.len() => std::slice::from_ref(last),
+            _ => self.args.get(given..).unwrap_or_default(),
+        }
+    }
+
+    /// The usage of the arguments that remain after `given` arguments have been supplied, in the same format as [`Command::usage`].
+    ///
+    /// If all the arguments have been supplied, this is empty, unless the last argument can be repeated, in which case it's that argument.
+    #[must_use]
+    pub fn usage_after(&self, given: usize) -> String {
+        self.remaining_args(given)
+            .iter()
+            .filter_map(Argument::usage)
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// The arguments as they're shown by [`Display`](fmt::Display), or a note if they're decided by the server
+    fn arguments_display(&self) -> String {
+        if self.arity().is_none() {
+            return String::from("(prompted by the server)");
+        }
+        self.args
+            .iter()
+            .filter(|arg| arg.is_described())
+            .map(ToString::to_string)
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+impl fmt::Display for Command {
+    /// Shows the command like it's typed, followed by its arguments, like `user info <Account name>`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let group = self
+            .fullname
+            .strip_suffix(self.name.as_str())
+            .and_then(|group| group.strip_suffix('_'))
+            .unwrap_or(&self.fullname);
+        write!(f, "{} {}", group, self.name)?;
+        match self.arguments_display().as_str() {
+            "" => Ok(()),
+            arguments => write!(f, " {}", arguments),
+        }
+    }
 }
 
 /// An argument for a bofhd command
 #[derive(Debug, Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Argument {
     /// Whether this argument is optional or required
     pub optional: bool,
     /// Whether this argument can be repeated
     pub repeat: bool,
     /// The default value for this argument
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
     pub default: Option<String>,
+    /// Whether the server computes the default value from the preceding arguments, which bofhd signals with a `default` of
+    /// `True` or `1` instead of a string. Get it with [`Bofh::get_default_param`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub server_default: bool,
     /// The argument type
-    pub arg_type: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub arg_type: Option<ArgType>,
     /// The help reference that should be used for this argument, if the client requests help
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
     pub help_ref: Option<String>,
     /// The prompt that should be used for this argument, if it's not supplied
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
     pub prompt: Option<String>,
 }
 
-#[derive(Debug)]
-enum ArgType {}
+impl Argument {
+    /// The argument type, in brackets if it's optional and followed by `...` if it can be repeated
+    fn usage(&self) -> Option<String> {
+        self.arg_type
+            .as_ref()
+            .map(|arg_type| match (self.optional, self.repeat) {
+                (false, false) => arg_type.to_string(),
+                (false, true) => format!("{}...", arg_type),
+                (true, false) => format!("[{}]", arg_type),
+                (true, true) => format!("[{}...]", arg_type),
+            })
+    }
+
+    /// Whether there's anything to show for the argument, a prompt or a type
+    fn is_described(&self) -> bool {
+        self.prompt.is_some() || self.arg_type.is_some()
+    }
+}
+
+impl fmt::Display for Argument {
+    /// Shows the argument's prompt, or its type if it has no prompt, like `<Account name>`, in brackets if it's optional
+    /// and followed by `...` if it can be repeated
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let prompt = match (
+            self.prompt.as_deref().filter(|prompt| !prompt.is_empty()),
+            &self.arg_type,
+        ) {
+            (Some(prompt), _) => prompt.to_owned(),
+            (None, Some(arg_type)) => arg_type.to_string(),
+            (None, None) => String::from("?"),
+        };
+        let repeat = if self.repeat { "..." } else { "" };
+        if self.optional {
+            write!(f, "[<{}>{}]", prompt, repeat)
+        } else {
+            write!(f, "<{}>{}", prompt, repeat)
+        }
+    }
+}
+
+/// The type of a bofhd command argument, as reported by the server.
+///
+/// These are the types commonly used by bofhd; others are kept as [`ArgType::Other`].
+/// Parsing a type name and displaying it gives back the same name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "String", into = "String")
+)]
+pub enum ArgType {
+    /// `accountName`
+    AccountName,
+    /// `accountPassword`
+    AccountPassword,
+    /// `affiliation`
+    Affiliation,
+    /// `date`
+    Date,
+    /// `disk`
+    Disk,
+    /// `emailAddress`
+    EmailAddress,
+    /// `entityType`
+    EntityType,
+    /// `groupName`
+    GroupName,
+    /// `groupOperation`
+    GroupOperation,
+    /// `id`
+    Id,
+    /// `integer`
+    Integer,
+    /// `memberType`
+    MemberType,
+    /// `ou`
+    Ou,
+    /// `personId`
+    PersonId,
+    /// `personName`
+    PersonName,
+    /// `quarantineType`
+    QuarantineType,
+    /// `spread`
+    Spread,
+    /// `string`, free text
+    SimpleString,
+    /// `yesNo`
+    YesNo,
+    /// Any other type
+    Other(String),
+}
+
+/// The bofhd names of the [`ArgType`] variants, except [`ArgType::Other`]
+const ARG_TYPES: &[(&str, ArgType)] = &[
+    ("accountName", ArgType::AccountName),
+    ("accountPassword", ArgType::AccountPassword),
+    ("affiliation", ArgType::Affiliation),
+    ("date", ArgType::Date),
+    ("disk", ArgType::Disk),
+    ("emailAddress", ArgType::EmailAddress),
+    ("entityType", ArgType::EntityType),
+    ("groupName", ArgType::GroupName),
+    ("groupOperation", ArgType::GroupOperation),
+    ("id", ArgType::Id),
+    ("integer", ArgType::Integer),
+    ("memberType", ArgType::MemberType),
+    ("ou", ArgType::Ou),
+    ("personId", ArgType::PersonId),
+    ("personName", ArgType::PersonName),
+    ("quarantineType", ArgType::QuarantineType),
+    ("spread", ArgType::Spread),
+    ("string", ArgType::SimpleString),
+    ("yesNo", ArgType::YesNo),
+];
+
+impl ArgType {
+    /// The valid values for arguments of this type, if there are only a few known ones, like `yes` and `no` for [`ArgType::YesNo`]
+    #[must_use]
+    pub fn values(&self) -> Option<&'static [&'static str]> {
+        match self {
+            Self::YesNo => Some(&["yes", "no"]),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for ArgType {
+    fn from(name: &str) -> Self {
+        ARG_TYPES
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map_or_else(
+                || Self::Other(name.to_owned()),
+                |(_, arg_type)| arg_type.clone(),
+            )
+    }
+}
+
+impl FromStr for ArgType {
+    type Err = Infallible;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Ok(Self::from(name))
+    }
+}
+
+impl From<String> for ArgType {
+    fn from(name: String) -> Self {
+        Self::from(name.as_str())
+    }
+}
+
+impl From<ArgType> for String {
+    fn from(arg_type: ArgType) -> Self {
+        arg_type.to_string()
+    }
+}
+
+impl fmt::Display for ArgType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Other(name) => write!(f, "{}", name),
+            arg_type => {
+                let (name, _) = ARG_TYPES
+                    .iter()
+                    .find(|(_, known)| known == arg_type)
+                    .expect("All ArgType variants except Other have a name");
+                write!(f, "{}", name)
+            }
+        }
+    }
+}
 
 /// A bofhd command group, ie. semantically linked command prefixes
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CommandGroup {
     /// The common prefix of the grouped commands
     pub name: String,
@@ -72,259 +685,2655 @@ pub struct CommandGroup {
     pub commands: BTreeMap<String, Command>,
 }
 
-/// The bofh client communicating with the bofhd server
-pub struct Bofh {
-    /// The URL to the bofhd server
-    pub url: String,
-    /// The Message Of The Day provided by the bofhd server after connection
-    pub motd: Option<String>,
-    session: Option<String>,
-}
-
-impl Bofh {
-    /// Creates a new connection to a bofhd server, and tests the connection by requesting the server's Message of the Day (which is stored in [`self::motd`]).
-    ///
-    /// # Errors
-    ///
-    /// Will return a [`BofhError`] if the connection to the bofhd server fails, or it doesn't respond to the [`Self::get_motd`] command.
-    pub fn new(url: String) -> Result<Self, BofhError> {
-        let mut bofh = Self {
-            url,
-            session: None,
-            motd: None,
-        };
-        bofh.motd = Some(bofh.get_motd()?);
-        Ok(bofh)
+impl fmt::Display for CommandGroup {
+    /// Shows the group's name, followed by an indented line for each command with its arguments, like `  info <Account name>`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        let width = self
+            .commands
+            .keys()
+            .map(String::len)
+            .max()
+            .unwrap_or_default();
+        for command in self.commands.values() {
+            let line = format!(
+                "  {:<width$}  {}",
+                command.name,
+                command.arguments_display(),
+                width = width
+            );
+            write!(f, "\n{}", line.trim_end())?;
+        }
+        Ok(())
     }
+}
 
-    fn run_request(&self, request: Request) -> Result<Value, BofhError> {
-        match request.call_url(&self.url) {
-            Ok(result) => Ok(result),
-            Err(err) => {
-                if let Some(fault) = err.fault() {
-                    if let Some(bofhd_error) = fault
-                        .fault_string
-                        .strip_prefix("Cerebrum.modules.bofhd.errors.")
-                    {
-                        if let Some(cerebrum_error) = bofhd_error.strip_prefix("CerebrumError:") {
-                            Err(BofhError::CerebrumError(cerebrum_error.to_owned()))
-                        } else if bofhd_error.strip_prefix("ServerRestartedError:").is_some() {
-                            //Err(BofhError::ServerRestartedError)
-                            //self.init_commands(True);
-                            self.run_request(request)
-                        } else if bofhd_error.strip_prefix("SessionExpiredError:").is_some() {
-                            //Err(BofhError::SessionExpiredError(request))
-                            todo!() // TODO
-                        } else {
-                            //unimplemented!()
-                            Err(BofhError::Fault(bofhd_error.to_owned()))
-                        }
-                    } else if let Some(not_implemented_error) =
-                        fault.fault_string.strip_prefix("NotImplementedError:")
-                    {
-                        Err(BofhError::NotImplementedError(
-                            not_implemented_error.to_owned(),
-                        ))
-                    } else {
-                        Err(BofhError::Fault(fault.fault_string.clone()))
-                    }
-                } else {
-                    Err(BofhError::XmlRpcError(err))
+/// A hash of the names and arguments of `commands`, for telling quickly whether a cached command table is outdated, see
+/// [`Bofh::commands_changed`].
+///
+/// The hash only changes when the commands do, and is the same across runs, platforms and versions of this crate.
+/// Help texts and format suggestions aren't included, since they're fetched separately.
+#[must_use]
+pub fn commands_hash(commands: &BTreeMap<String, CommandGroup>) -> u64 {
+    // 64-bit FNV-1a, which unlike `DefaultHasher` is guaranteed to stay the same
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut write = |field: &str| {
+        for byte in field.bytes().chain(std::iter::once(0)) {
+            hash = (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+    for group in commands.values() {
+        write(&group.name);
+        for command in group.commands.values() {
+            write(&command.fullname);
+            if command.prompt_func {
+                write("prompt_func");
+            }
+            for arg in &command.args {
+                write(
+                    &arg.arg_type
+                        .as_ref()
+                        .map(ToString::to_string)
+                        .unwrap_or_default(),
+                );
+                write(match (arg.optional, arg.repeat) {
+                    (false, false) => "",
+                    (true, false) => "optional",
+                    (false, true) => "repeat",
+                    (true, true) => "optional repeat",
+                });
+                write(arg.default.as_deref().unwrap_or_default());
+                if arg.server_default {
+                    write("server_default");
                 }
+                write(arg.prompt.as_deref().unwrap_or_default());
+                write(arg.help_ref.as_deref().unwrap_or_default());
             }
         }
     }
+    hash
+}
 
-    fn run_raw_command(&self, command: &str, args: &[&str]) -> Result<Value, BofhError> {
-        let mut request = Request::new(command);
-        for arg in args {
-            request = request.arg(*arg);
-        }
-        self.run_request(request)
-    }
+/// A selectable row in a [`PromptMap`]
+#[derive(Debug, Clone)]
+pub struct PromptMapRow {
+    /// The text to show the user, with the row's format string applied to its arguments
+    pub display: String,
+    /// The value to use as the argument if the user selects this row
+    pub value: Value,
+}
 
-    fn run_raw_sess_command(&self, command: &str, args: &[&str]) -> Result<Value, BofhError> {
-        if let Some(session) = &self.session {
-            let mut request = Request::new(command).arg(session.clone());
-            for arg in args {
-                request = request.arg(*arg);
+/// The `map` of alternatives returned by bofhd's `call_prompt_func`, for presenting a selection menu.
+///
+/// The map is a list of `[[format, *args], value]` entries. Entries without a value, or whose arguments are all `None`, are headers (for example `[["Header", None], None]`), which should be shown as titles and can't be selected.
+/// The other entries are selectable rows, displayed by applying the Python-style format string to its arguments.
+#[derive(Debug, Clone, Default)]
+pub struct PromptMap {
+    /// Header lines, in order
+    pub headers: Vec<String>,
+    /// Selectable rows, in order
+    pub rows: Vec<PromptMapRow>,
+}
+
+impl PromptMap {
+    /// Parses a `map` from a `call_prompt_func` response. Malformed entries are skipped.
+    ///
+    /// Returns `None` if the map isn't a list.
+    #[must_use]
+    pub fn from_value(map: &Value) -> Option<Self> {
+        let mut prompt_map = Self::default();
+        for entry in map.as_array()? {
+            let (description, value) = match entry.as_array() {
+                Some([description, value]) => (description, value),
+                _ => continue,
+            };
+            let (format, args) = match description.as_array() {
+                Some([format, args @ ..]) => (format.as_str().unwrap_or_default(), args),
+                _ => match description.as_str() {
+                    Some(format) => (format, &[][..]),
+                    None => continue,
+                },
+            };
+            if !args.is_empty() && args.iter().all(|arg| *arg == Value::Nil) {
+                prompt_map.headers.push(format.to_owned());
+            } else if *value == Value::Nil {
+                prompt_map.headers.push(format::python_format(format, args));
+            } else {
+                prompt_map.rows.push(PromptMapRow {
+                    display: format::python_format(format, args),
+                    value: value.clone(),
+                });
             }
-            self.run_request(request)
-        } else {
-            // TODO Maybe just panic here instead, this should never happen
-            Err(BofhError::NoSessionError)
         }
+        Some(prompt_map)
     }
+}
 
-    // XXX: There are only a handful of bofhd commands:
-    // motd = get_motd(client_name, version)
-    // session = login(user, pass)
-    // logout(session)
-    // get_commands(session) -- see _init_commands
-    // help(session) -- general help
-    // help(session, "arg_help", ref) -- help on arg type,
-    //                                   ref found in arg['help_ref']
-    // help(session, group) -- help on group
-    // help(session, group, cmd) -- help on command
-    // run_command(session, command, args)  # command = group_cmd
-    // call_prompt_func(session, command, args) =>
-    //   {prompt: string, help_ref: key, last_arg: bool, default: value,
-    //    map: [[["Header", None], value], [[format, *args], value], ...],
-    //    raw: bool}
-    // get_default_param(session, command, args)
-    // get_format_suggestion(command)
+/// The response from bofhd's `call_prompt_func`, describing how to prompt for the next argument to a command
+#[derive(Debug, Clone, Default)]
+pub struct PromptFuncResult {
+    /// The prompt to show the user
+    pub prompt: String,
+    /// The help reference for the argument, see [`Bofh::help_arg`]
+    pub help_ref: Option<String>,
+    /// Whether this is the last argument, after which the command can be run
+    pub last_arg: bool,
+    /// The default value for the argument
+    pub default: Option<String>,
+    /// Alternatives the user can select from, if any
+    pub map: Option<PromptMap>,
+    /// Whether the user's input should be passed to the server as is, even if there is a [`Self::map`]
+    pub raw: bool,
+}
 
-    fn get_commands(&mut self) -> Result<BTreeMap<String, CommandGroup>, BofhError> {
-        let response = self.run_raw_sess_command("get_commands", &[])?;
-        let mut commands = BTreeMap::<String, CommandGroup>::new();
-        for (cmd, array) in response.as_struct().unwrap() {
-            let cmd_group = array[0].as_array().unwrap()[0].as_str().unwrap();
-            if !commands.contains_key(cmd_group) {
-                commands.insert(
-                    cmd_group.into(),
-                    CommandGroup {
-                        name: cmd_group.into(),
-                        commands: BTreeMap::new(),
-                    },
-                );
-            }
-            commands.get_mut(cmd_group).unwrap().commands.insert(
-                array[0].as_array().unwrap()[1].as_str().unwrap().into(),
-                Command {
-                    name: array[0].as_array().unwrap()[1].as_str().unwrap().into(),
-                    fullname: cmd.into(),
-                    args: match &array[1] {
-                        Value::Array(array) => {
-                            let mut vector = vec![];
-                            for strct in array {
-                                let strct = strct.as_struct().unwrap();
-                                vector.push(Argument {
-                                    optional: match strct
-                                        .get("optional")
-                                        .or(Some(&Value::Bool(false)))
-                                    {
-                                        Some(Value::Bool(value)) => *value,
-                                        Some(Value::String(value)) => {
-                                            matches!(value.as_str(), "True")
-                                        }
-                                        _ => false,
-                                    },
-                                    repeat: match strct.get("repeat").or(Some(&Value::Bool(false)))
-                                    {
-                                        Some(Value::Bool(value)) => *value,
-                                        Some(Value::String(value)) => {
-                                            matches!(value.as_str(), "True")
-                                        }
-                                        _ => false,
-                                    },
-                                    default: strct
-                                        .get("default")
-                                        .map(|x| x.as_str().unwrap().to_owned()),
-                                    arg_type: strct
-                                        .get("type")
-                                        .map(|x| x.as_str().unwrap().to_owned()),
-                                    help_ref: strct
-                                        .get("help_ref")
-                                        .map(|x| x.as_str().unwrap().to_owned()),
-                                    prompt: strct
-                                        .get("prompt")
-                                        .map(|x| x.as_str().unwrap().to_owned()),
-                                });
-                            }
-                            vector
-                        }
-                        Value::String(_) => vec![Argument::default()], // prompt_func
-                        _ => vec![],
-                    },
-                    format_suggestion: None,
-                    help: None,
-                },
-            );
+impl PromptFuncResult {
+    /// Parses a `call_prompt_func` response, or returns `None` if it isn't a struct
+    fn from_value(value: &Value) -> Option<Self> {
+        let response = value.as_struct()?;
+        let flag = |name| response.get(name).is_some_and(parse_bofh_bool);
+        Some(Self {
+            prompt: response
+                .get("prompt")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_owned(),
+            help_ref: response
+                .get("help_ref")
+                .and_then(Value::as_str)
+                .map(str::to_owned),
+            last_arg: flag("last_arg"),
+            default: response
+                .get("default")
+                .filter(|default| **default != Value::Nil)
+                .map(format::value_to_string),
+            map: response.get("map").and_then(PromptMap::from_value),
+            raw: flag("raw"),
+        })
+    }
+}
+
+/// Parses a fault string from the server into the most specific [`BofhError`].
+///
+/// A [`BofhError::SessionExpiredError`] has an empty request, which is filled in by `run_sess_request_args`, since it
+/// knows the request without the session.
+fn parse_fault(fault_string: String) -> BofhError {
+    if let Some(bofhd_error) = fault_string.strip_prefix("Cerebrum.modules.bofhd.errors.") {
+        if bofhd_error.starts_with("ServerRestartedError:") {
+            BofhError::ServerRestartedError
+        } else if bofhd_error.starts_with("SessionExpiredError:") {
+            BofhError::SessionExpiredError(ExpiredRequest::default())
+        } else if let Some(permission_denied) = bofhd_error.strip_prefix("PermissionDenied:") {
+            BofhError::PermissionDenied(permission_denied.to_owned())
+        } else {
+            parse_cerebrum_error(bofhd_error)
         }
-        Ok(commands)
+    } else if let Some(not_implemented_error) = fault_string.strip_prefix("NotImplementedError:") {
+        BofhError::NotImplementedError(not_implemented_error.to_owned())
+    } else {
+        BofhError::Fault(fault_string)
     }
+}
 
-    /// Run a bofh command on the bofhd server.
-    ///
-    /// Note that this function actually runs the bofhd command `run_command bofh_command`, and can't be used to run raw bofhd commands. Those are all exposed through separate functions.
-    ///
-    /// # Errors
-    ///
-    /// Returns a [`BofhError`] if the command fails for some reason.
-    ///
-    /// If the bofhd session has expired and this function returns a [`BofhError::SessionExpiredError`], the client might want to reauthenticate using [`Self::login`] and then retry the command.
-    pub fn run_command(&self, command: &str, args: &[&str]) -> Result<Value, BofhError> {
-        // TODO: Return a formatted value?
-        let args: Vec<&str> = {
-            let mut command_args = vec![command];
-            for &arg in args {
-                command_args.push(arg);
-            }
-            command_args
-        };
-        self.run_raw_sess_command("run_command", &args)
+/// Parses a bofhd error, the part of a fault string after `Cerebrum.modules.bofhd.errors.`, like
+/// `CerebrumError:Could not find account`.
+///
+/// The message of a `CerebrumError` may start with the path to the exception that caused it, like
+/// `CerebrumError:Cerebrum.Errors.NotFoundError:Could not find account`, which is used as the kind of error instead.
+fn parse_cerebrum_error(bofhd_error: &str) -> BofhError {
+    let (kind, message) = bofhd_error.split_once(':').unwrap_or(("", bofhd_error));
+    let (kind, message) = match message.split_once(':') {
+        Some((path, message))
+            if kind == "CerebrumError"
+                && path.contains('.')
+                && path
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') =>
+        {
+            (path, message.trim_start())
+        }
+        _ => (kind, message),
+    };
+    BofhError::CerebrumError {
+        kind: kind.to_owned(),
+        message: message.to_owned(),
     }
+}
 
-    /// Authenticate with the bofhd server and set up a session. Returns the commands available to the authenticated user.
-    ///
-    /// Note that this consumes `password` to discourage user-facing clients from holding onto the user's password.
-    /// If the user needs to reauthenticate (if [`Self::run_command`] later returns a [`BofhError::SessionExpiredError`], for example), please prompt the user for the password again.
-    ///
-    /// # Errors
-    ///
-    /// Returns a [`BofhError`] if logging in or getting the commands from the server fail for some reason.
-    ///
-    /// # Panics
-    ///
-    /// Will normally never panic, unless the session identifier returned by the bofhd server is in an invalid format.
-    #[allow(clippy::needless_pass_by_value)]
-    pub fn login(
-        &mut self,
-        username: &str,
-        password: String,
-    ) -> Result<BTreeMap<String, CommandGroup>, BofhError> {
-        self.session = Some(
-            self.run_raw_command("login", &[username, &password])?
-                .as_str()
-                .expect("Invalid bofhd session identifier")
-                .to_owned(),
-        );
-        self.get_commands()
+/// Finds the server version in a Message of the Day, the word after "version" if it starts with a digit
+fn parse_server_version(motd: &str) -> Option<String> {
+    let mut words = motd.split_whitespace();
+    words.find(|word| word.eq_ignore_ascii_case("version"))?;
+    let version = words
+        .next()?
+        .trim_end_matches(|c: char| !c.is_ascii_alphanumeric());
+    version
+        .starts_with(|c: char| c.is_ascii_digit())
+        .then(|| version.to_owned())
+}
+
+/// Parses the session identifier returned by `login`.
+///
+/// Some misconfigured servers respond to a failed login with an error struct instead of a fault, which must not be mistaken for a session.
+fn parse_session(response: &Value) -> Result<String, BofhError> {
+    response.as_str().map(str::to_owned).ok_or_else(|| {
+        BofhError::MalformedResponse(format!(
+            "expected a session identifier from login, got {:?}",
+            response
+        ))
+    })
+}
+
+/// Parses a command specification from `get_commands`, returning the command's group name and the command.
+///
+/// A specification is a tuple of the command's group and name, and its arguments: either a list of argument structs,
+/// or the name of a prompt function.
+fn parse_command(fullname: &str, spec: &Value) -> Result<(String, Command), BofhError> {
+    let malformed =
+        |problem: &str| BofhError::MalformedResponse(format!("command {}: {}", fullname, problem));
+    let spec = spec
+        .as_array()
+        .ok_or_else(|| malformed("specification is not a tuple"))?;
+    let names = spec
+        .first()
+        .and_then(Value::as_array)
+        .ok_or_else(|| malformed("command tuple missing names"))?;
+    let cmd_group = names
+        .first()
+        .and_then(Value::as_str)
+        .ok_or_else(|| malformed("command tuple missing group name"))?;
+    let name = names
+        .get(1)
+        .and_then(Value::as_str)
+        .ok_or_else(|| malformed("command tuple missing command name"))?;
+    // Instead of a list of arguments, commands whose arguments are decided by the server have the name of a prompt function
+    let prompt_func = matches!(spec.get(1), Some(Value::String(_)));
+    let args = match spec.get(1) {
+        Some(Value::Array(array)) => array
+            .iter()
+            .map(|arg| parse_argument(arg).map_err(|problem| malformed(&problem)))
+            .collect::<Result<_, _>>()?,
+        _ => vec![],
+    };
+    let command = Command {
+        name: name.into(),
+        fullname: fullname.into(),
+        args,
+        format_suggestion: None,
+        help: None,
+        read_only: None,
+        permissions: None,
+        prompt_func,
+    };
+    Ok((cmd_group.into(), command))
+}
+
+/// Parses a flag in a bofhd response, which is false unless it's true, a nonzero integer, or a string like `True`.
+///
+/// Some bofhd servers send flags as the strings `"True"` and `"False"` instead of as booleans. They don't tell their
+/// version (see [`Bofh::server_version`]), so both are accepted.
+fn parse_bofh_bool(value: &Value) -> bool {
+    match value {
+        Value::Bool(value) => *value,
+        Value::Int(value) => *value != 0,
+        Value::String(value) => ["true", "1", "yes"]
+            .iter()
+            .any(|spelling| value.trim().eq_ignore_ascii_case(spelling)),
+        _ => false,
     }
+}
 
-    /// Get the current Message of the Day from the bofhd server
-    ///
-    /// # Errors
-    ///
-    /// Returns a [`BofhError`] if the command fails for some reason.
+/// Parses an argument struct from a command specification, or describes what's wrong with it
+fn parse_argument(arg: &Value) -> Result<Argument, String> {
+    let strct = arg
+        .as_struct()
+        .ok_or_else(|| format!("argument is not a struct: {:?}", arg))?;
+    let flag = |field: &str| strct.get(field).is_some_and(parse_bofh_bool);
+    let string = |field: &str| match strct.get(field) {
+        None | Some(Value::Nil) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value.clone())),
+        Some(value) => Err(format!(
+            "argument field '{}' is not a string: {:?}",
+            field, value
+        )),
+    };
+    // A default that isn't a string tells the client to ask the server for it
+    let (default, server_default) = match strct.get("default") {
+        Some(value @ (Value::Bool(_) | Value::Int(_))) => (None, parse_bofh_bool(value)),
+        _ => (string("default")?, false),
+    };
+    Ok(Argument {
+        optional: flag("optional"),
+        repeat: flag("repeat"),
+        default,
+        server_default,
+        arg_type: string("type")?.map(ArgType::from),
+        help_ref: string("help_ref")?,
+        prompt: string("prompt")?,
+    })
+}
+
+/// The bofh client communicating with the bofhd server.
+///
+/// A client starts out [`Unauthenticated`], as returned by [`Bofh::new`], and can only run commands once it's
+/// [`Authenticated`] with [`Bofh::login`]. Calls that don't need a session, like [`Bofh::get_motd`], work in either state.
+pub struct Bofh<S: State = Authenticated> {
+    /// The URL to the bofhd server
+    pub url: String,
+    /// The Message Of The Day provided by the bofhd server after connection
+    pub motd: Option<String>,
+    /// The bofhd server's version, if the Message of the Day mentions it, like `bofhd version 1.2.3`.
     ///
-    /// # Panics
+    /// bofhd has no call for its version, so this is usually `None`.
+    pub server_version: Option<String>,
+    /// Whether to fetch every command's format suggestion along with the commands, see [`Command::format_suggestion`].
     ///
-    /// Will normally never panic, unless the Message of the Day returned by the bofhd server is in an invalid format.
-    pub fn get_motd(&self) -> Result<String, BofhError> {
-        Ok(self
-            .run_raw_command("get_motd", &[])?
-            .as_str()
-            .expect("Invalid bofhd response")
-            .to_owned())
-    }
+    /// That's one extra round-trip per command, so it's off by default.
+    pub prefetch_format_suggestions: bool,
+    transport: Arc<dyn Transport>,
+    /// The commands as of the last [`Self::get_commands`], see [`Self::commands`]
+    commands: RefCell<BTreeMap<String, CommandGroup>>,
+    /// Set while recovering from a server restart, so a server that keeps restarting doesn't make us recurse forever
+    restarting: Cell<bool>,
+    /// The timeout for the calls being made, overriding [`BofhBuilder::timeout`], see [`Self::run_command_timeout`]
+    call_timeout: Cell<Option<Duration>>,
+    /// Whether the server has `system.multicall`, once it's been asked, see [`Self::run_commands`]
+    multicall: Cell<Option<bool>>,
+    /// The options the client was built with, for [`Self::reconnect`]
+    options: BofhBuilder,
+    state: S,
 }
 
-impl Drop for Bofh {
-    #[allow(clippy::let_underscore_drop)]
-    /// Logs the user out of the bofhd session.
-    fn drop(&mut self) {
-        if self.session.is_some() {
-            let _ = self.run_raw_sess_command("logout", &[]);
-        }
+mod private {
+    pub trait Sealed {
+        /// The session identifier, if there is a session
+        fn session(&self) -> Option<&str>;
+
+        /// Whether the server has said that the session has expired, if there is a session
+        fn expired(&self) -> Option<&std::cell::Cell<bool>>;
+
+        /// Makes the session use a new connection, if there is a session
+        fn set_connection(&mut self, transport: &std::sync::Arc<dyn crate::Transport>);
     }
 }
 
-#[cfg(test)]
+use private::Sealed;
+
+/// The state of a [`Bofh`] client, either [`Unauthenticated`] or [`Authenticated`]
+pub trait State: Sealed {}
+
+/// A client that hasn't logged in yet, or has logged out
+#[derive(Debug)]
+pub struct Unauthenticated;
+
+impl Sealed for Unauthenticated {
+    fn session(&self) -> Option<&str> {
+        None
+    }
+
+    fn expired(&self) -> Option<&Cell<bool>> {
+        None
+    }
+
+    fn set_connection(&mut self, _transport: &Arc<dyn Transport>) {}
+}
+
+impl State for Unauthenticated {}
+
+/// A client with a bofhd session
+pub struct Authenticated {
+    session: Session,
+}
+
+impl Sealed for Authenticated {
+    fn session(&self) -> Option<&str> {
+        // Empty after `Bofh::end_session`
+        Some(self.session.id.as_str()).filter(|id| !id.is_empty())
+    }
+
+    fn expired(&self) -> Option<&Cell<bool>> {
+        Some(&self.session.expired)
+    }
+
+    fn set_connection(&mut self, transport: &Arc<dyn Transport>) {
+        self.session.transport = Arc::clone(transport);
+    }
+}
+
+impl State for Authenticated {}
+
+/// A bofhd session, which is logged out when it's dropped
+struct Session {
+    id: String,
+    transport: Arc<dyn Transport>,
+    /// Set when the server says the session has expired, see [`Bofh::session_expired`]
+    expired: Cell<bool>,
+    /// See [`Bofh::set_logout_on_drop`]
+    logout_on_drop: bool,
+}
+
+impl Drop for Session {
+    /// Logs the user out of the bofhd session, ignoring any errors. Use [`Bofh::logout`] to handle them.
+    fn drop(&mut self) {
+        if self.id.is_empty() || !self.logout_on_drop {
+            return;
+        }
+        let _ = self
+            .transport
+            .call("logout", &[Value::from(self.id.as_str())]);
+    }
+}
+
+/// Configures a connection to a bofhd server, see [`Bofh::new`]
+#[derive(Debug, Clone)]
+pub struct BofhBuilder {
+    url: String,
+    ca_cert: Option<PathBuf>,
+    timeout: Option<Duration>,
+    motd_timeout: Option<Duration>,
+    insecure: bool,
+    client_name: String,
+    interrupt: Option<Arc<AtomicBool>>,
+    transport: Option<Arc<dyn Transport>>,
+}
+
+impl BofhBuilder {
+    /// Starts configuring a connection to the bofhd server at `url`
+    #[must_use]
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            ca_cert: None,
+            timeout: None,
+            motd_timeout: None,
+            insecure: false,
+            client_name: String::from(DEFAULT_CLIENT_NAME),
+            interrupt: None,
+            transport: None,
+        }
+    }
+
+    /// Trusts the CA certificates in the PEM file at `path`, in addition to the system's, for servers with certificates
+    /// signed by an internal CA
+    #[must_use]
+    pub fn ca_cert(mut self, path: impl Into<PathBuf>) -> Self {
+        self.ca_cert = Some(path.into());
+        self
+    }
+
+    /// Gives up on requests that take longer than `timeout`, with a [`BofhError::TimeoutError`]. By default, or with `None`,
+    /// requests never time out.
+    #[must_use]
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Gives up on getting the Message of the Day after `timeout`, if that's shorter than [`Self::timeout`], since it
+    /// should be quick even when commands aren't. That includes the one [`Self::connect`] gets.
+    #[must_use]
+    pub fn motd_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.motd_timeout = timeout;
+        self
+    }
+
+    /// Identifies the client to the server as `client_name` (by default `bofh-rs`), along with this crate's version.
+    ///
+    /// Some servers tailor the Message of the Day to the client, or reject unknown clients.
+    #[must_use]
+    pub fn client_name(mut self, client_name: impl Into<String>) -> Self {
+        self.client_name = client_name.into();
+        self
+    }
+
+    /// Skips validating the server's TLS certificate and hostname, for test servers with self-signed certificates.
+    ///
+    /// This makes the connection vulnerable to man-in-the-middle attacks, so a warning is printed to stderr (once) when it's used.
+    #[must_use]
+    pub fn insecure(mut self, insecure: bool) -> Self {
+        self.insecure = insecure;
+        self
+    }
+
+    /// Abandons the request in flight with a [`BofhError::Interrupted`] when `interrupt` is set, like from a Ctrl-C handler.
+    /// The flag is cleared again when the request is abandoned.
+    ///
+    /// Only the client stops waiting for the response: a command that has reached the server may still complete there.
+    #[must_use]
+    pub fn interrupt(mut self, interrupt: Arc<AtomicBool>) -> Self {
+        self.interrupt = Some(interrupt);
+        self
+    }
+
+    /// Sends requests with `transport` instead of over HTTP(S) to the URL, like a [`transport::MockTransport`] for tests.
+    ///
+    /// The other connection options are ignored then.
+    #[must_use]
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Sets up the client without contacting the server, so [`Bofh::motd`] is `None`. Use [`Self::connect`] to test the
+    /// connection too.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`BofhError::CertificateError`] if the CA certificates can't be read, or another [`BofhError`] if the
+    /// HTTP client can't be set up.
+    pub fn build(self) -> Result<Bofh<Unauthenticated>, BofhError> {
+        Ok(Bofh {
+            url: self.url.clone(),
+            motd: None,
+            server_version: None,
+            prefetch_format_suggestions: false,
+            transport: self.open_transport(&self.url)?,
+            commands: RefCell::new(BTreeMap::new()),
+            restarting: Cell::new(false),
+            call_timeout: Cell::new(None),
+            multicall: Cell::new(None),
+            options: self,
+            state: Unauthenticated,
+        })
+    }
+
+    /// Sets up the client like [`Self::build`], and connects to the server, testing the connection by requesting the
+    /// server's Message of the Day (which is stored in [`Bofh::motd`]).
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of [`Self::build`], or another [`BofhError`] if the connection to the bofhd server fails, or it
+    /// doesn't respond to the [`Bofh::get_motd`] command.
+    pub fn connect(self) -> Result<Bofh<Unauthenticated>, BofhError> {
+        let mut bofh = self.build()?;
+        let motd = bofh.get_motd()?;
+        bofh.server_version = parse_server_version(&motd);
+        bofh.motd = Some(motd);
+        Ok(bofh)
+    }
+
+    /// Connects to the server like [`Self::connect`], and resumes an existing session instead of logging in, see
+    /// [`Bofh::with_session`].
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of [`Self::connect`], and a [`BofhError::SessionExpiredError`] if the server rejects the session.
+    pub fn build_with_session(self, session: String) -> Result<Bofh<Authenticated>, BofhError> {
+        self.connect()?.with_session(session)
+    }
+
+    /// Sets up the transport to the server at `url`, unless another transport is given
+    fn open_transport(&self, url: &str) -> Result<Arc<dyn Transport>, BofhError> {
+        if let Some(transport) = &self.transport {
+            return Ok(Arc::clone(transport));
+        }
+        let mut client = Client::builder().timeout(self.timeout);
+        if let Some(path) = &self.ca_cert {
+            for certificate in read_certificates(path)? {
+                client = client.add_root_certificate(certificate);
+            }
+        }
+        if self.insecure {
+            INSECURE_WARNING.call_once(|| {
+                eprintln!(
+                    "Warning: Not validating the server's certificate, the connection is not secure"
+                );
+            });
+            // This skips hostname validation too
+            client = client.danger_accept_invalid_certs(true);
+        }
+        let mut transport = HttpTransport::new(url.to_owned(), client.build()?);
+        if let Some(interrupt) = &self.interrupt {
+            transport = transport.interrupt(Arc::clone(interrupt));
+        }
+        Ok(Arc::new(transport))
+    }
+}
+
+/// The client name sent to the server, unless another one is set with [`BofhBuilder::client_name`]
+pub const DEFAULT_CLIENT_NAME: &str = "bofh-rs";
+
+/// Makes sure the warning about insecure connections is only printed once, see [`BofhBuilder::insecure`]
+static INSECURE_WARNING: Once = Once::new();
+
+/// Reads the certificates in a PEM file, which may contain several
+fn read_certificates(path: &Path) -> Result<Vec<Certificate>, BofhError> {
+    let error =
+        |reason: String| BofhError::CertificateError(format!("{}: {}", path.display(), reason));
+    let pem = std::fs::read_to_string(path).map_err(|err| error(err.to_string()))?;
+    let certificates = pem
+        .split_inclusive(PEM_END)
+        .filter(|block| block.contains(PEM_BEGIN))
+        .map(|block| Certificate::from_pem(block.as_bytes()).map_err(|err| error(err.to_string())))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certificates.is_empty() {
+        return Err(error(String::from("no PEM certificates found")));
+    }
+    Ok(certificates)
+}
+
+/// bofhd methods that don't take a session identifier as their first argument, see [`Bofh::run_raw`]
+const SESSIONLESS_METHODS: &[&str] = &["get_format_suggestion", "get_motd", "login"];
+
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+impl<S: State> Bofh<S> {
+    fn run_request(&self, method: &str, args: &[Value]) -> Result<Value, BofhError> {
+        let result = match self.call_timeout.get() {
+            Some(timeout) => self.transport.call_with_timeout(method, args, timeout),
+            None => self.transport.call(method, args),
+        };
+        match result {
+            Err(BofhError::Fault(fault_string)) => match parse_fault(fault_string) {
+                BofhError::ServerRestartedError => self.recover_from_restart(method, args),
+                err => Err(err),
+            },
+            result => result,
+        }
+    }
+
+    /// Runs `call` with the calls it makes giving up after `timeout`, instead of the client's timeout
+    fn with_call_timeout<T>(&self, timeout: Duration, call: impl FnOnce() -> T) -> T {
+        let previous = self.call_timeout.replace(Some(timeout));
+        let result = call();
+        self.call_timeout.set(previous);
+        result
+    }
+
+    /// Re-fetches the commands (if authenticated) after the server has restarted, since they may have changed, and retries `request` once.
+    ///
+    /// Returns a [`BofhError::ServerRestartedError`] if the server reports another restart while recovering.
+    fn recover_from_restart(&self, method: &str, args: &[Value]) -> Result<Value, BofhError> {
+        if self.restarting.replace(true) {
+            return Err(BofhError::ServerRestartedError);
+        }
+        let result = match self.state.session() {
+            Some(session) => self.fetch_commands(session),
+            None => Ok(BTreeMap::new()),
+        }
+        .and_then(|_| self.run_request(method, args));
+        self.restarting.set(false);
+        result
+    }
+
+    fn run_raw_command(&self, command: &str, args: &[&str]) -> Result<Value, BofhError> {
+        log::info!("Calling {}", command);
+        // Only the username is logged for a login, not the password
+        let logged = match command {
+            "login" => &args[..args.len().min(1)],
+            _ => args,
+        };
+        log::debug!("Arguments to {}: {:?}", command, logged);
+        let args: Vec<Value> = args.iter().map(|&arg| Value::from(arg)).collect();
+        self.run_request(command, &args)
+    }
+
+    fn run_sess_request(
+        &self,
+        session: &str,
+        command: &str,
+        args: &[&str],
+    ) -> Result<Value, BofhError> {
+        let args: Vec<CommandArg> = args.iter().map(|&arg| CommandArg::from(arg)).collect();
+        self.run_sess_request_args(session, command, &args)
+    }
+
+    fn run_sess_request_args(
+        &self,
+        session: &str,
+        command: &str,
+        args: &[CommandArg],
+    ) -> Result<Value, BofhError> {
+        if session.is_empty() {
+            return Err(BofhError::NoSessionError);
+        }
+        log::info!("Calling {}", command);
+        // The arguments may contain passwords, see `Command::logged_args`
+        log::debug!("{} arguments to {}", args.len(), command);
+        let request: Vec<Value> = std::iter::once(Value::from(session))
+            .chain(args.iter().cloned().map(Value::from))
+            .collect();
+        self.run_request(command, &request)
+            .map_err(|err| match err {
+                BofhError::SessionExpiredError(_) => self.expired_error(command, args),
+                err => err,
+            })
+    }
+
+    /// Notes that the session has expired, and returns the error for the request that found out
+    fn expired_error(&self, command: &str, args: &[CommandArg]) -> BofhError {
+        if let Some(expired) = self.state.expired() {
+            expired.set(true);
+        }
+        BofhError::SessionExpiredError(ExpiredRequest {
+            command: command.to_owned(),
+            args: args.to_vec(),
+        })
+    }
+
+    /// Gets the commands available in `session`, and stores them in the command table, see [`Bofh::get_commands`]
+    fn fetch_commands(&self, session: &str) -> Result<BTreeMap<String, CommandGroup>, BofhError> {
+        let response = self.run_sess_request(session, "get_commands", &[])?;
+        let specs = response.as_struct().ok_or_else(|| {
+            BofhError::MalformedResponse(format!(
+                "expected a struct of commands from get_commands, got {:?}",
+                response
+            ))
+        })?;
+        let mut commands = BTreeMap::<String, CommandGroup>::new();
+        for (cmd, spec) in specs {
+            // One malformed command shouldn't make the others unavailable
+            let (cmd_group, mut command) = match parse_command(cmd, spec) {
+                Ok(command) => command,
+                Err(err) => {
+                    log::warn!("Skipping a command: {}", err);
+                    continue;
+                }
+            };
+            if self.prefetch_format_suggestions {
+                command.format_suggestion = self.get_format_suggestion(&command)?;
+            }
+            commands
+                .entry(cmd_group.clone())
+                .or_insert_with(|| CommandGroup {
+                    name: cmd_group,
+                    commands: BTreeMap::new(),
+                })
+                .commands
+                .insert(command.name.clone(), command);
+        }
+        *self.commands.borrow_mut() = commands.clone();
+        Ok(commands)
+    }
+
+    /// Get the server's suggestion for how to format the output of a command, encoded as JSON.
+    ///
+    /// The suggestion is a struct with a list of Python-style format strings and the response fields they use (`str_vars`),
+    /// and optionally a header (`hdr`). Returns `Ok(None)` if the server has no suggestion for the command, including if it
+    /// responds with a fault.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the request fails for some other reason.
+    pub fn get_format_suggestion(&self, command: &Command) -> Result<Option<String>, BofhError> {
+        match self.run_raw_command("get_format_suggestion", &[&command.fullname]) {
+            Ok(suggestion @ Value::Struct(_)) => Ok(Some(format::to_json(&suggestion).to_string())),
+            Ok(_)
+            | Err(
+                BofhError::CerebrumError { .. }
+                | BofhError::PermissionDenied(_)
+                | BofhError::NotImplementedError(_)
+                | BofhError::Fault(_),
+            ) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Get the current Message of the Day from the bofhd server.
+    ///
+    /// The client name (see [`BofhBuilder::client_name`]) and version are sent along, unless the server doesn't accept them.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the command fails for some reason, or a [`BofhError::MalformedResponse`] if the server responds with something other than a string.
+    /// Gives up with a [`BofhError::TimeoutError`] after [`BofhBuilder::motd_timeout`].
+    pub fn get_motd(&self) -> Result<String, BofhError> {
+        match (self.options.motd_timeout, self.options.timeout) {
+            (Some(motd_timeout), Some(timeout)) if timeout <= motd_timeout => self.fetch_motd(),
+            (Some(motd_timeout), _) => self.with_call_timeout(motd_timeout, || self.fetch_motd()),
+            (None, _) => self.fetch_motd(),
+        }
+    }
+
+    fn fetch_motd(&self) -> Result<String, BofhError> {
+        let motd = match self.run_raw_command(
+            "get_motd",
+            &[&self.options.client_name, env!("CARGO_PKG_VERSION")],
+        ) {
+            // Older servers don't take any arguments
+            Err(BofhError::Fault(_)) => self.run_raw_command("get_motd", &[])?,
+            motd => motd?,
+        };
+        match motd {
+            Value::String(motd) => Ok(motd),
+            motd => Err(BofhError::MalformedResponse(format!(
+                "expected a Message of the Day, got {:?}",
+                motd
+            ))),
+        }
+    }
+
+    /// Fetches the current Message of the Day from the bofhd server and stores it in [`Self::motd`], along with the
+    /// [`Self::server_version`] it mentions.
+    ///
+    /// Returns `true` if it differs from the previously stored one.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the command fails for some reason. The stored Message of the Day is left as is.
+    pub fn refresh_motd(&mut self) -> Result<bool, BofhError> {
+        let motd = self.get_motd()?;
+        let changed = self.motd.as_ref() != Some(&motd);
+        self.server_version = parse_server_version(&motd);
+        self.motd = Some(motd);
+        Ok(changed)
+    }
+
+    /// Reconnects to the server, for when the connection is broken after a network problem.
+    ///
+    /// The transport is set up again with the same options (see [`BofhBuilder`]) and [`Self::url`], and the connection is
+    /// tested by fetching the Message of the Day. If there is a session, it's checked by fetching the commands again.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the server can't be reached. If the session is gone, a [`BofhError::SessionExpiredError`]
+    /// is returned; log in again with [`Bofh::retry_after_login`], or [`Bofh::logout`] and [`Bofh::login`].
+    pub fn reconnect(&mut self) -> Result<(), BofhError> {
+        self.transport = self.options.open_transport(&self.url)?;
+        self.state.set_connection(&self.transport);
+        self.refresh_motd()?;
+        if let Some(session) = self.state.session() {
+            self.fetch_commands(session)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the client is logged in, with a session that the server hasn't said has expired.
+    ///
+    /// A session can time out on the server without the client knowing, until the next request fails with a
+    /// [`BofhError::SessionExpiredError`].
+    #[must_use]
+    pub fn is_authenticated(&self) -> bool {
+        self.state.session().is_some() && !self.session_expired()
+    }
+
+    /// Whether the server has said that the client's session has expired, see [`Self::retry_after_login`]
+    #[must_use]
+    pub fn session_expired(&self) -> bool {
+        self.state.expired().is_some_and(Cell::get)
+    }
+
+    /// Moves the client into another state
+    fn with_state<T: State>(self, state: T) -> (Bofh<T>, S) {
+        let Self {
+            url,
+            motd,
+            server_version,
+            prefetch_format_suggestions,
+            transport,
+            commands,
+            restarting,
+            call_timeout,
+            multicall,
+            options,
+            state: old_state,
+        } = self;
+        (
+            Bofh {
+                url,
+                motd,
+                server_version,
+                prefetch_format_suggestions,
+                transport,
+                commands,
+                restarting,
+                call_timeout,
+                multicall,
+                options,
+                state,
+            },
+            old_state,
+        )
+    }
+
+    /// Logs in, and returns the new session identifier
+    #[allow(clippy::needless_pass_by_value)]
+    fn new_session(&self, username: &str, password: String) -> Result<String, BofhError> {
+        let response = self.run_raw_command("login", &[username, &password])?;
+        parse_session(&response)
+    }
+}
+
+impl Bofh<Unauthenticated> {
+    /// Creates a new connection to a bofhd server, and tests the connection by requesting the server's Message of the Day (which is stored in [`Self::motd`]).
+    ///
+    /// The connection isn't authenticated yet, see [`Self::login`].
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`BofhError`] if the connection to the bofhd server fails, or it doesn't respond to the [`Self::get_motd`] command.
+    ///
+    /// Use a [`BofhBuilder`] to configure the connection.
+    pub fn new(url: String) -> Result<Self, BofhError> {
+        BofhBuilder::new(url).connect()
+    }
+
+    /// Authenticate with the bofhd server and set up a session, and get the commands available to the authenticated user
+    /// (see [`Bofh::commands`]).
+    ///
+    /// Note that this consumes `password` to discourage user-facing clients from holding onto the user's password.
+    /// If the user needs to reauthenticate (if [`Bofh::run_command`] later returns a [`BofhError::SessionExpiredError`], for example), please prompt the user for the password again.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if logging in or getting the commands from the server fail for some reason.
+    pub fn login(self, username: &str, password: String) -> Result<Bofh<Authenticated>, BofhError> {
+        let bofh = self.authenticate(username, password)?;
+        bofh.get_commands()?;
+        Ok(bofh)
+    }
+
+    /// Authenticate with the bofhd server and set up a session, without getting the available commands.
+    ///
+    /// This is useful for clients that want to handle a failing [`Bofh::get_commands`] separately from a failing login. Otherwise, use [`Self::login`].
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if logging in fails for some reason, or a [`BofhError::MalformedResponse`] if the server responds with something other than a session identifier.
+    pub fn authenticate(
+        self,
+        username: &str,
+        password: String,
+    ) -> Result<Bofh<Authenticated>, BofhError> {
+        let session = self.new_session(username, password)?;
+        Ok(self.with_session_id(session))
+    }
+
+    /// Resumes an existing session, like one saved from [`Bofh::session_id`] in an earlier run, so the user doesn't have
+    /// to log in again. The session is checked by fetching the commands, like [`Self::login`] does.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError::SessionExpiredError`] if the session has expired or is unknown to the server, or another
+    /// [`BofhError`] if getting the commands fails for some reason. The session isn't logged out then.
+    pub fn with_session(self, session: String) -> Result<Bofh<Authenticated>, BofhError> {
+        let mut bofh = self.with_session_id(session);
+        match bofh.get_commands() {
+            Ok(_) => Ok(bofh),
+            Err(err) => {
+                bofh.set_logout_on_drop(false);
+                Err(err)
+            }
+        }
+    }
+
+    /// Moves the client into the session `id`, without checking it
+    fn with_session_id(self, id: String) -> Bofh<Authenticated> {
+        let session = Session {
+            id,
+            transport: Arc::clone(&self.transport),
+            expired: Cell::new(false),
+            logout_on_drop: true,
+        };
+        self.with_state(Authenticated { session }).0
+    }
+}
+
+/// A command's arguments in a `system.multicall` (or `None` if it wasn't sent), along with its result
+type SentCommand = (Option<Vec<CommandArg>>, Result<Response, BofhError>);
+
+impl Bofh<Authenticated> {
+    /// Creates a client that's already logged in, with a known session identifier instead of a username and password,
+    /// like one from [`Self::session_id`]. The commands are fetched, which checks that the session is valid.
+    ///
+    /// Use a [`BofhBuilder`] and [`BofhBuilder::build_with_session`] to configure the connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError::SessionExpiredError`] if the server rejects the session, which is discarded without logging
+    /// it out, or another [`BofhError`] if connecting or getting the commands fails.
+    pub fn from_session(url: String, session: String) -> Result<Self, BofhError> {
+        BofhBuilder::new(url).build_with_session(session)
+    }
+
+    /// The identifier of the bofhd session, which can be resumed later with [`Bofh::with_session`].
+    ///
+    /// Anyone with the identifier can act as the user until the session expires, so keep it as safe as a password.
+    #[must_use]
+    pub fn session_id(&self) -> &str {
+        &self.state.session.id
+    }
+
+    /// Whether to log out of the session when the client is dropped, which it does by default.
+    ///
+    /// Turn this off to keep the session for resuming later, see [`Bofh::with_session`]. [`Self::logout`] still logs out.
+    pub fn set_logout_on_drop(&mut self, logout_on_drop: bool) {
+        self.state.session.logout_on_drop = logout_on_drop;
+    }
+
+    fn run_raw_sess_command(&self, command: &str, args: &[&str]) -> Result<Value, BofhError> {
+        self.run_sess_request(&self.state.session.id, command, args)
+    }
+
+    // XXX: There are only a handful of bofhd commands:
+    // motd = get_motd(client_name, version)
+    // session = login(user, pass)
+    // logout(session)
+    // get_commands(session) -- see _init_commands
+    // help(session) -- general help
+    // help(session, "arg_help", ref) -- help on arg type,
+    //                                   ref found in arg['help_ref']
+    // help(session, group) -- help on group
+    // help(session, group, cmd) -- help on command
+    // run_command(session, command, args)  # command = group_cmd
+    // call_prompt_func(session, command, *args) =>
+    //   {prompt: string, help_ref: key, last_arg: bool, default: value,
+    //    map: [[["Header", None], value], [[format, *args], value], ...],
+    //    raw: bool}
+    // get_default_param(session, command, *args)
+    // get_format_suggestion(command)
+
+    /// Get the commands available to the authenticated user, grouped by command group.
+    ///
+    /// Commands that the server describes in a way that can't be parsed are skipped, and logged as a warning.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the command fails for some reason, or a [`BofhError::MalformedResponse`] if the response
+    /// isn't a command table at all.
+    pub fn get_commands(&self) -> Result<BTreeMap<String, CommandGroup>, BofhError> {
+        self.fetch_commands(&self.state.session.id)
+    }
+
+    /// The commands available to the authenticated user, as of the last time they were fetched from the server.
+    ///
+    /// They're fetched by [`Bofh::login`], [`Self::get_commands`] and [`Self::init_commands`], and refreshed automatically
+    /// when the server restarts or the user logs in again with [`Self::retry_after_login`]. Before that, there are none.
+    #[must_use]
+    pub fn commands(&self) -> Ref<'_, BTreeMap<String, CommandGroup>> {
+        self.commands.borrow()
+    }
+
+    /// The command `name` in the command group `group`, like `user` `info`, from [`Self::commands`], for running with
+    /// [`Self::run_command`]
+    #[must_use]
+    pub fn command(&self, group: &str, name: &str) -> Option<Command> {
+        self.commands
+            .borrow()
+            .get(group)
+            .and_then(|command_group| command_group.commands.get(name))
+            .cloned()
+    }
+
+    /// Whether the commands differ from `old`, like a command table cached from an earlier session, going by their names
+    /// and arguments (see [`commands_hash`]).
+    ///
+    /// This compares with [`Self::commands`] as of the last time they were fetched, so call [`Self::get_commands`] first
+    /// to check against the server as it is now.
+    #[must_use]
+    pub fn commands_changed(&self, old: &BTreeMap<String, CommandGroup>) -> bool {
+        commands_hash(&self.commands()) != commands_hash(old)
+    }
+
+    /// Looks up a command in [`Self::commands`] by its full name, like `user_info`, without asking the server.
+    ///
+    /// See [`Self::get_command`] for asking the server.
+    #[must_use]
+    pub fn lookup_command(&self, fullname: &str) -> Option<Ref<'_, Command>> {
+        Ref::filter_map(self.commands.borrow(), |commands| {
+            commands
+                .values()
+                .flat_map(|command_group| command_group.commands.values())
+                .find(|command| command.fullname == fullname)
+        })
+        .ok()
+    }
+
+    /// Looks up a command in [`Self::commands`] by its group and name, like `user` and `info`, without asking the server
+    #[must_use]
+    pub fn lookup(&self, group: &str, name: &str) -> Option<Ref<'_, Command>> {
+        Ref::filter_map(self.commands.borrow(), |commands| {
+            commands
+                .get(group)
+                .and_then(|command_group| command_group.commands.get(name))
+        })
+        .ok()
+    }
+
+    /// Iterates over all the commands in [`Self::commands`], across command groups, in order.
+    ///
+    /// Each command is borrowed from the command table, which therefore can't be refreshed while they're held.
+    pub fn iter_commands(&self) -> impl Iterator<Item = Ref<'_, Command>> {
+        self.iter_commands_with_group().map(|(_, command)| command)
+    }
+
+    /// Like [`Self::iter_commands`], but with the name of each command's group
+    pub fn iter_commands_with_group(&self) -> impl Iterator<Item = (String, Ref<'_, Command>)> {
+        let names: Vec<(String, String)> = self
+            .commands
+            .borrow()
+            .values()
+            .flat_map(|command_group| {
+                command_group
+                    .commands
+                    .keys()
+                    .map(|name| (command_group.name.clone(), name.clone()))
+            })
+            .collect();
+        // Commands that disappear if the table is refreshed while iterating are skipped
+        names.into_iter().filter_map(move |(group, name)| {
+            self.lookup(&group, &name).map(|command| (group, command))
+        })
+    }
+
+    /// Fetch the commands available to the authenticated user with [`Self::get_commands`], unless they've been fetched already.
+    ///
+    /// The commands are only fetched if none have been fetched yet, or if `force` is set, which is useful if they might have changed.
+    /// This happens automatically when the server reports that it has restarted.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the command fails for some reason.
+    pub fn init_commands(&self, force: bool) -> Result<(), BofhError> {
+        if force || self.commands.borrow().is_empty() {
+            self.get_commands()?;
+        }
+        Ok(())
+    }
+
+    /// Get a single command available to the authenticated user, by its full name (like `user_info`).
+    ///
+    /// Returns `Ok(None)` if there is no such command.
+    ///
+    /// bofhd has no call for introspecting a single command, so this falls back to fetching the full command table with `get_commands` and only parsing the requested command.
+    /// That saves parsing the rest of the table, but not the round-trip; if a server gains per-command introspection, this is where it should be used.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the command fails for some reason.
+    pub fn get_command(&self, fullname: &str) -> Result<Option<Command>, BofhError> {
+        let response = self.run_raw_sess_command("get_commands", &[])?;
+        let specs = response.as_struct().ok_or_else(|| {
+            BofhError::MalformedResponse(format!(
+                "expected a struct of commands from get_commands, got {:?}",
+                response
+            ))
+        })?;
+        specs
+            .get(fullname)
+            .map(|spec| parse_command(fullname, spec).map(|(_, command)| command))
+            .transpose()
+    }
+
+    /// Run a bofh command on the bofhd server.
+    ///
+    /// The number of arguments is checked against the command's argument list before anything is sent to the server,
+    /// see [`Command::validate_args`]. If the last argument is repeatable, the values given for it are sent together, see
+    /// [`Command::pack_repeated_args`].
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError::ArgumentError`] if the command doesn't take that many arguments, or another [`BofhError`]
+    /// if the command fails for some reason.
+    ///
+    /// If the bofhd session has expired and this function returns a [`BofhError::SessionExpiredError`], the client might want to prompt for the password and retry the command with [`Self::retry_after_login`].
+    pub fn run_command(&self, command: &Command, args: &[&str]) -> Result<Response, BofhError> {
+        let args: Vec<CommandArg> = args.iter().map(|&arg| CommandArg::from(arg)).collect();
+        self.run_command_args(command, &args)
+    }
+
+    /// Run a bofh command on the bofhd server, like [`Self::run_command`], with arguments that may be groups of values, or
+    /// nested lists and maps.
+    ///
+    /// A [`CommandArg::Group`] or [`CommandArg::List`] counts as a single argument, and is sent as an XML-RPC array in its
+    /// place, like a [`CommandArg::Map`] is sent as a struct. Several values
+    /// for a repeatable last argument are sent the same way, see [`Command::pack_repeated_args`].
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError::ArgumentError`] if the command doesn't take that many arguments, or another [`BofhError`]
+    /// if the command fails for some reason.
+    pub fn run_command_args(
+        &self,
+        command: &Command,
+        args: &[CommandArg],
+    ) -> Result<Response, BofhError> {
+        let command_args = command.request_args(args)?;
+        log::debug!(
+            "Arguments to {}: [{}]",
+            command.fullname,
+            command.logged_args(args).join(", ")
+        );
+        self.run_sess_request_args(&self.state.session.id, "run_command", &command_args)
+            .map(Response)
+    }
+
+    /// Run several bofh commands, like [`Self::run_command`] for each, and return each command's result in order.
+    ///
+    /// If the server has `system.multicall`, the commands are sent together in a single request, which saves a round-trip
+    /// per command for bulk operations. Otherwise, they're run one after the other. Either way, a command that fails
+    /// doesn't stop the rest, and commands with the wrong number of arguments aren't sent.
+    ///
+    /// A server restart is handled like for a single command: the commands are fetched again, and the commands that the
+    /// restart stopped are retried once. The commands that fail because the session has expired each return a
+    /// [`BofhError::SessionExpiredError`] that can be retried with [`Self::retry_after_login`].
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the request with all the commands fails, like if the connection fails.
+    pub fn run_commands(
+        &self,
+        calls: &[(&Command, Vec<&str>)],
+    ) -> Result<Vec<Result<Response, BofhError>>, BofhError> {
+        if self.state.session().is_none() {
+            return Err(BofhError::NoSessionError);
+        }
+        if !self.has_multicall()? {
+            return Ok(calls
+                .iter()
+                .map(|(command, args)| self.run_command(command, args))
+                .collect());
+        }
+        let requests: Vec<Result<Vec<CommandArg>, BofhError>> = calls
+            .iter()
+            .map(|(command, args)| {
+                let args: Vec<CommandArg> = args.iter().map(|&arg| CommandArg::from(arg)).collect();
+                command.request_args(&args)
+            })
+            .collect();
+        let multicall: Vec<Value> = requests
+            .iter()
+            .filter_map(|request| request.as_ref().ok())
+            .map(|command_args| {
+                let params = std::iter::once(Value::from(self.state.session.id.as_str()))
+                    .chain(command_args.iter().cloned().map(Value::from))
+                    .collect();
+                Value::Struct(BTreeMap::from([
+                    (String::from("methodName"), Value::from("run_command")),
+                    (String::from("params"), Value::Array(params)),
+                ]))
+            })
+            .collect();
+        let mut results = if multicall.is_empty() {
+            vec![].into_iter()
+        } else {
+            log::info!("Calling system.multicall with {} commands", multicall.len());
+            let sent = multicall.len();
+            match self.run_request("system.multicall", &[Value::Array(multicall)])? {
+                Value::Array(results) if results.len() == sent => results.into_iter(),
+                response => {
+                    return Err(BofhError::MalformedResponse(format!(
+                        "expected a result for each command from system.multicall, got {:?}",
+                        response
+                    )))
+                }
+            }
+        };
+        let results = requests
+            .into_iter()
+            .map(|request| {
+                let command_args = match request {
+                    Ok(command_args) => command_args,
+                    Err(err) => return (None, Err(err)),
+                };
+                let result = match results.next() {
+                    Some(Value::Array(mut result)) if result.len() == 1 => {
+                        Ok(Response(result.remove(0)))
+                    }
+                    Some(Value::Struct(fault)) => {
+                        match fault.get("faultString").and_then(Value::as_str) {
+                            Some(fault_string) => Err(match parse_fault(fault_string.to_owned()) {
+                                BofhError::SessionExpiredError(_) => {
+                                    self.expired_error("run_command", &command_args)
+                                }
+                                err => err,
+                            }),
+                            None => Err(BofhError::MalformedResponse(format!(
+                                "expected a fault from system.multicall, got {:?}",
+                                fault
+                            ))),
+                        }
+                    }
+                    result => Err(BofhError::MalformedResponse(format!(
+                        "expected a result from system.multicall, got {:?}",
+                        result
+                    ))),
+                };
+                (Some(command_args), result)
+            })
+            .collect();
+        Ok(self.retry_restarted(results))
+    }
+
+    /// Retries the commands of a `system.multicall` that failed because the server restarted, once, after fetching the
+    /// commands again, like [`Self::recover_from_restart`] does for a single request.
+    fn retry_restarted(&self, results: Vec<SentCommand>) -> Vec<Result<Response, BofhError>> {
+        let restarted = |result: &Result<Response, BofhError>| {
+            matches!(result, Err(BofhError::ServerRestartedError))
+        };
+        if !results.iter().any(|(_, result)| restarted(result)) || self.restarting.replace(true) {
+            return results.into_iter().map(|(_, result)| result).collect();
+        }
+        let fetched = self.fetch_commands(&self.state.session.id);
+        let results = results
+            .into_iter()
+            .map(|(command_args, result)| match (command_args, &fetched) {
+                (Some(command_args), Ok(_)) if restarted(&result) => self
+                    .run_sess_request_args(&self.state.session.id, "run_command", &command_args)
+                    .map(Response),
+                _ => result,
+            })
+            .collect();
+        self.restarting.set(false);
+        results
+    }
+
+    /// Whether the server has `system.multicall`, going by `system.listMethods`, which is only asked once
+    fn has_multicall(&self) -> Result<bool, BofhError> {
+        if let Some(multicall) = self.multicall.get() {
+            return Ok(multicall);
+        }
+        let multicall = match self.run_raw_command("system.listMethods", &[]) {
+            Ok(Value::Array(methods)) => methods
+                .iter()
+                .any(|method| method.as_str() == Some("system.multicall")),
+            Ok(_)
+            | Err(
+                BofhError::Fault(_)
+                | BofhError::CerebrumError { .. }
+                | BofhError::PermissionDenied(_)
+                | BofhError::NotImplementedError(_),
+            ) => false,
+            Err(err) => return Err(err),
+        };
+        self.multicall.set(Some(multicall));
+        Ok(multicall)
+    }
+
+    /// Run a bofh command on the bofhd server, like [`Self::run_command`], but give up after `timeout` instead of the
+    /// client's timeout (see [`BofhBuilder::timeout`]), for commands that are known to be slow or that should be quick.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError::TimeoutError`] if the server doesn't respond in time, or another [`BofhError`] like
+    /// [`Self::run_command`].
+    pub fn run_command_timeout(
+        &self,
+        command: &Command,
+        args: &[&str],
+        timeout: Duration,
+    ) -> Result<Response, BofhError> {
+        self.with_call_timeout(timeout, || self.run_command(command, args))
+    }
+
+    /// Run a bofh command on the bofhd server by its full name (like `user_info`), without checking its arguments.
+    ///
+    /// Note that this function actually runs the bofhd command `run_command bofh_command`, and can't be used to run raw bofhd commands. Those are all exposed through separate functions.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the command fails for some reason.
+    ///
+    /// If the bofhd session has expired and this function returns a [`BofhError::SessionExpiredError`], the client might want to prompt for the password and retry the command with [`Self::retry_after_login`].
+    pub fn run_command_str(&self, command: &str, args: &[&str]) -> Result<Response, BofhError> {
+        let args: Vec<&str> = {
+            let mut command_args = vec![command];
+            for &arg in args {
+                command_args.push(arg);
+            }
+            command_args
+        };
+        self.run_raw_sess_command("run_command", &args)
+            .map(Response)
+    }
+
+    /// Run a bofh command on the bofhd server by its full name, like [`Self::run_command_str`], with arguments that may be
+    /// groups of values, or nested lists and maps, like [`Self::run_command_args`].
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the command fails for some reason.
+    pub fn run_command_str_args(
+        &self,
+        command: &str,
+        args: &[CommandArg],
+    ) -> Result<Response, BofhError> {
+        let args: Vec<CommandArg> = std::iter::once(CommandArg::from(command))
+            .chain(args.iter().cloned())
+            .collect();
+        self.run_sess_request_args(&self.state.session.id, "run_command", &args)
+            .map(Response)
+    }
+
+    /// Call a bofhd XML-RPC method directly, like `get_format_suggestion` or a method this library doesn't know about.
+    ///
+    /// The session identifier is sent as the first argument, except to the methods that don't take one (`get_motd`, `login` and
+    /// `get_format_suggestion`). Beware that calling `logout` this way ends the session without the client knowing.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the call fails for some reason.
+    pub fn run_raw(&self, method: &str, args: &[&str]) -> Result<Value, BofhError> {
+        if SESSIONLESS_METHODS.contains(&method) {
+            self.run_raw_command(method, args)
+        } else {
+            self.run_raw_sess_command(method, args)
+        }
+    }
+
+    /// Run a bofh command, like [`Self::run_command`], and write the result to `w` with `formatter`.
+    ///
+    /// This lets formatters stream large results to files or sockets without building the whole output in memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError::ArgumentError`] if the command doesn't take that many arguments, another [`BofhError`] if the
+    /// command fails, or a [`BofhError::IoError`] if writing the result fails.
+    pub fn run_command_to_writer(
+        &self,
+        command: &Command,
+        args: &[&str],
+        formatter: &dyn OutputFormatter,
+        w: &mut dyn Write,
+    ) -> Result<(), BofhError> {
+        let response = self.run_command(command, args)?;
+        formatter.write_value(response.value(), w)?;
+        Ok(())
+    }
+
+    /// Authenticate again, after a [`BofhError::SessionExpiredError`], and retry the request that failed.
+    ///
+    /// The commands are fetched again too, see [`Self::commands`].
+    ///
+    /// The request is only retried once: if the new session expires too, the [`BofhError::SessionExpiredError`] is returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if logging in or the retried request fails for some reason.
+    pub fn retry_after_login(
+        &mut self,
+        username: &str,
+        password: String,
+        request: &ExpiredRequest,
+    ) -> Result<Response, BofhError> {
+        self.login_again(username, password)?;
+        self.run_sess_request_args(&self.state.session.id, &request.command, &request.args)
+            .map(Response)
+    }
+
+    /// Authenticate again with a new session, after [`Self::end_session`] or when the session has expired, and fetch the
+    /// commands again (see [`Self::commands`]).
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if logging in or getting the commands from the server fail for some reason.
+    pub fn login_again(&mut self, username: &str, password: String) -> Result<(), BofhError> {
+        self.state.session.id = self.new_session(username, password)?;
+        self.state.session.expired.set(false);
+        self.init_commands(true)
+    }
+
+    /// End the current bofhd session, without dropping the client.
+    ///
+    /// The session (and the cached [`Self::commands`]) is cleared even if the server fails to log it out, so session-scoped
+    /// commands will return [`BofhError::NoSessionError`] until the user logs in again with [`Self::login_again`].
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if there is no session, or if logging out fails for some reason.
+    pub fn end_session(&mut self) -> Result<(), BofhError> {
+        let result = self.run_raw_sess_command("logout", &[]);
+        // Either way, there's no session left to log out when it's dropped
+        self.state.session.id.clear();
+        self.state.session.expired.set(false);
+        self.commands.get_mut().clear();
+        result.map(|_| ())
+    }
+
+    /// Log out of the current bofhd session, without dropping the client, like [`Self::end_session`].
+    ///
+    /// The session (and the cached [`Self::commands`]) is discarded even if the server fails to log it out, so the returned
+    /// client can always log in again with [`Bofh::login`], possibly as a different user. The result tells whether the server
+    /// logged the session out; it fails with a [`BofhError`] if logging out fails for some reason.
+    ///
+    /// Dropping the client logs out too, but ignores any errors.
+    pub fn logout(mut self) -> (Bofh<Unauthenticated>, Result<(), BofhError>) {
+        let result = self.end_session();
+        (self.with_state(Unauthenticated).0, result)
+    }
+
+    /// Get the server's suggested default value for the next argument to `command`, given the arguments supplied so far.
+    ///
+    /// Returns `Ok(None)` if the server has no suggestion.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the command fails for some reason.
+    pub fn get_default_param(
+        &self,
+        command: &Command,
+        args: &[&str],
+    ) -> Result<Option<String>, BofhError> {
+        let mut command_args = vec![command.fullname.as_str()];
+        command_args.extend_from_slice(args);
+        Ok(
+            match self.run_raw_sess_command("get_default_param", &command_args)? {
+                Value::Nil => None,
+                Value::String(default) if default.is_empty() => None,
+                default => Some(format::value_to_string(&default)),
+            },
+        )
+    }
+
+    /// Ask the server how to prompt for the next argument to a command whose arguments are resolved by a prompt function
+    /// (which `get_commands` reports in place of the argument list), given the arguments supplied so far.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the command fails for some reason, or a [`BofhError::MalformedResponse`] if the response isn't a struct.
+    pub fn call_prompt_func(
+        &self,
+        command: &Command,
+        args: &[&str],
+    ) -> Result<PromptFuncResult, BofhError> {
+        let mut command_args = vec![command.fullname.as_str()];
+        command_args.extend_from_slice(args);
+        let response = self.run_raw_sess_command("call_prompt_func", &command_args)?;
+        PromptFuncResult::from_value(&response).ok_or_else(|| {
+            BofhError::MalformedResponse(format!(
+                "expected a struct from call_prompt_func, got {:?}",
+                response
+            ))
+        })
+    }
+
+    /// Send a `help` request with the given arguments, and return the help text
+    fn help_text(&self, args: &[&str]) -> Result<String, BofhError> {
+        match self.run_raw_sess_command("help", args)? {
+            Value::String(help) => Ok(help),
+            other => Err(BofhError::MalformedResponse(format!(
+                "expected help text, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    /// Get the general help text, which gives an overview of the available command groups
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the command fails for some reason.
+    pub fn help(&self) -> Result<String, BofhError> {
+        self.help_text(&[])
+    }
+
+    /// Get the help text for a command group, like `user`
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the command fails for some reason, for example if the server doesn't know the group.
+    pub fn help_group(&self, group: &str) -> Result<String, BofhError> {
+        self.help_text(&[group])
+    }
+
+    /// Get the help text for a command, like `user` `info`.
+    ///
+    /// bofhd doesn't include help texts in the command table, so [`Command::help`] starts out empty. The first time
+    /// a command's help is fetched, it's stored in the command table (see [`Self::commands`]), and later calls
+    /// don't need to ask the server again.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the command fails for some reason, for example if the server doesn't know the command.
+    pub fn help_command(&self, group: &str, cmd: &str) -> Result<String, BofhError> {
+        let cached = self
+            .commands
+            .borrow()
+            .get(group)
+            .and_then(|command_group| command_group.commands.get(cmd))
+            .and_then(|command| command.help.clone());
+        if let Some(help) = cached {
+            return Ok(help);
+        }
+        let help = self.help_text(&[group, cmd])?;
+        if let Some(command) = self
+            .commands
+            .borrow_mut()
+            .get_mut(group)
+            .and_then(|command_group| command_group.commands.get_mut(cmd))
+        {
+            command.help = Some(help.clone());
+        }
+        Ok(help)
+    }
+
+    /// Get the help text for a command argument, as referenced by [`Argument::help_ref`]
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`BofhError`] if the command fails for some reason, for example if the server doesn't know the help reference.
+    pub fn help_arg(&self, help_ref: &str) -> Result<String, BofhError> {
+        self.help_text(&["arg_help", help_ref])
+    }
+}
+
+#[cfg(test)]
 mod tests {
-    use crate::Bofh;
+    use crate::transport::MockTransport;
+    use crate::Value;
+    use crate::{
+        commands_hash, parse_argument, parse_bofh_bool, parse_cerebrum_error, parse_command,
+        parse_server_version, parse_session, read_certificates, ArgType, Argument, Authenticated,
+        Bofh, BofhBuilder, BofhError, Command, CommandArg, CommandGroup, ExpiredRequest,
+        PromptFuncResult, PromptMap, Response,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    /// A server with a single command, `user info <accountName>`
+    fn mock_server() -> MockTransport {
+        let user_info = Value::Array(vec![
+            Value::Array(vec![Value::from("user"), Value::from("info")]),
+            Value::Array(vec![Value::Struct(
+                [(String::from("type"), Value::from("accountName"))]
+                    .into_iter()
+                    .collect(),
+            )]),
+        ]);
+        MockTransport::new()
+            .respond("get_motd", "Welcome")
+            .respond("login", "session")
+            .respond(
+                "get_commands",
+                Value::Struct(
+                    [(String::from("user_info"), user_info)]
+                        .into_iter()
+                        .collect(),
+                ),
+            )
+            .respond("run_command", "Account: olanor")
+            .respond("logout", Value::Nil)
+    }
+
+    fn connect(transport: &Arc<MockTransport>) -> Bofh<Authenticated> {
+        BofhBuilder::new(String::from("https://bofhd.example.com:8000"))
+            .transport(Arc::clone(transport) as _)
+            .connect()
+            .unwrap()
+            .login("olanor", String::from("secret"))
+            .unwrap()
+    }
+
+    #[test]
+    fn login() {
+        let transport = Arc::new(mock_server());
+        let bofh = connect(&transport);
+        assert_eq!(bofh.motd.as_deref(), Some("Welcome"));
+        assert!(bofh.is_authenticated());
+        assert_eq!(
+            transport.calls(),
+            [
+                (
+                    String::from("get_motd"),
+                    vec![
+                        Value::from("bofh-rs"),
+                        Value::from(env!("CARGO_PKG_VERSION"))
+                    ]
+                ),
+                (
+                    String::from("login"),
+                    vec![Value::from("olanor"), Value::from("secret")]
+                ),
+                (String::from("get_commands"), vec![Value::from("session")]),
+            ]
+        );
+        drop(bofh);
+        assert_eq!(
+            transport.calls().last(),
+            Some(&(String::from("logout"), vec![Value::from("session")]))
+        );
+    }
+
+    #[test]
+    fn build_and_connect() {
+        let transport = Arc::new(mock_server());
+        let builder = BofhBuilder::new(String::from("https://bofhd.example.com:8000"))
+            .transport(Arc::clone(&transport) as _);
+        let bofh = builder.clone().build().unwrap();
+        assert!(bofh.motd.is_none());
+        assert!(transport.calls().is_empty());
+        let bofh = builder.connect().unwrap();
+        assert_eq!(bofh.motd.as_deref(), Some("Welcome"));
+        assert_eq!(transport.calls().len(), 1);
+    }
+
+    #[test]
+    fn get_commands() {
+        let transport = Arc::new(mock_server());
+        let bofh = connect(&transport);
+        let commands = bofh.get_commands().unwrap();
+        assert_eq!(commands["user"].commands["info"].fullname, "user_info");
+        assert_eq!(commands["user"].commands["info"].usage(), "accountName");
+        assert_eq!(bofh.commands().len(), 1);
+    }
+
+    #[test]
+    fn commands_changed() {
+        let transport = Arc::new(mock_server());
+        let bofh = connect(&transport);
+        let mut cached = bofh.commands().clone();
+        assert!(!bofh.commands_changed(&cached));
+        assert_eq!(
+            commands_hash(&cached),
+            commands_hash(&bofh.get_commands().unwrap())
+        );
+
+        let user = &mut cached.get_mut("user").unwrap().commands;
+        user.get_mut("info").unwrap().help = Some(String::from("Show an account"));
+        assert!(!bofh.commands_changed(&cached));
+        let user = &mut cached.get_mut("user").unwrap().commands;
+        user.get_mut("info").unwrap().args[0].optional = true;
+        assert!(bofh.commands_changed(&cached));
+        cached.clear();
+        assert!(bofh.commands_changed(&cached));
+    }
+
+    #[test]
+    fn run_command() {
+        let transport = Arc::new(mock_server());
+        let bofh = connect(&transport);
+        let command = bofh.commands()["user"].commands["info"].clone();
+        let response = bofh.run_command(&command, &["olanor"]).unwrap();
+        assert_eq!(response.as_message(), Some("Account: olanor"));
+        assert_eq!(response.as_single(), None);
+        assert!(response.as_rows().is_empty());
+        assert_eq!(response.into_value(), Value::from("Account: olanor"));
+        assert_eq!(
+            transport.calls().last(),
+            Some(&(
+                String::from("run_command"),
+                vec![
+                    Value::from("session"),
+                    Value::from("user_info"),
+                    Value::from("olanor")
+                ]
+            ))
+        );
+        assert!(matches!(
+            bofh.run_command(&command, &[]),
+            Err(BofhError::ArgumentError { .. })
+        ));
+    }
+
+    #[test]
+    fn responses() {
+        let row = |name: &str| -> BTreeMap<String, Value> {
+            [(String::from("name"), Value::from(name))]
+                .into_iter()
+                .collect()
+        };
+        let list = Response::from(Value::Array(vec![
+            Value::Struct(row("admins")),
+            Value::Struct(row("users")),
+        ]));
+        assert_eq!(list.as_rows(), [row("admins"), row("users")]);
+        assert_eq!(list.as_single(), None);
+        assert_eq!(list.as_message(), None);
+        let single = Response::from(Value::Struct(row("admins")));
+        assert_eq!(single.as_rows(), [row("admins")]);
+        assert_eq!(single.as_single(), Some(&row("admins")));
+    }
+
+    #[test]
+    fn run_commands() {
+        let command = connect(&Arc::new(mock_server())).commands()["user"].commands["info"].clone();
+        let calls = [
+            (&command, vec!["olanor"]),
+            (&command, vec![]),
+            (&command, vec!["nobody"]),
+        ];
+
+        // Without system.multicall, the commands are run one by one
+        let transport = Arc::new(mock_server());
+        let results = connect(&transport).run_commands(&calls).unwrap();
+        assert_eq!(
+            results[0].as_ref().unwrap().as_message(),
+            Some("Account: olanor")
+        );
+        assert!(matches!(results[1], Err(BofhError::ArgumentError { .. })));
+        assert_eq!(
+            transport
+                .calls()
+                .iter()
+                .filter(|(method, _)| method == "run_command")
+                .count(),
+            2
+        );
+
+        let transport = Arc::new(
+            mock_server()
+                .respond(
+                    "system.listMethods",
+                    Value::Array(vec![Value::from("system.multicall")]),
+                )
+                .respond(
+                    "system.multicall",
+                    Value::Array(vec![
+                        Value::Array(vec![Value::from("Account: olanor")]),
+                        Value::Struct(BTreeMap::from([
+                            (String::from("faultCode"), Value::Int(1)),
+                            (
+                                String::from("faultString"),
+                                Value::from(
+                                    "Cerebrum.modules.bofhd.errors.CerebrumError:Could not find account",
+                                ),
+                            ),
+                        ])),
+                    ]),
+                ),
+        );
+        let bofh = connect(&transport);
+        let results = bofh.run_commands(&calls).unwrap();
+        assert_eq!(
+            results[0].as_ref().unwrap().as_message(),
+            Some("Account: olanor")
+        );
+        assert!(matches!(results[1], Err(BofhError::ArgumentError { .. })));
+        assert!(
+            matches!(&results[2], Err(BofhError::CerebrumError { message, .. }) if message == "Could not find account")
+        );
+        let (method, args) = transport.calls().pop().unwrap();
+        assert_eq!(method, "system.multicall");
+        assert!(matches!(&args[..], [Value::Array(calls)] if calls.len() == 2));
+        assert!(bofh.run_commands(&[]).unwrap().is_empty());
+        assert_eq!(
+            transport
+                .calls()
+                .iter()
+                .filter(|(method, _)| method == "system.listMethods")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn run_commands_after_restart() {
+        let command = connect(&Arc::new(mock_server())).commands()["user"].commands["info"].clone();
+        let calls = [(&command, vec!["olanor"]), (&command, vec!["nobody"])];
+        let restarted = "Cerebrum.modules.bofhd.errors.ServerRestartedError:";
+        let get_commands = |transport: &MockTransport| {
+            transport
+                .calls()
+                .iter()
+                .filter(|(method, _)| method == "get_commands")
+                .count()
+        };
+
+        // A command that failed because the server restarted is run again after fetching the commands
+        let transport = Arc::new(
+            mock_server()
+                .respond(
+                    "system.listMethods",
+                    Value::Array(vec![Value::from("system.multicall")]),
+                )
+                .respond(
+                    "system.multicall",
+                    Value::Array(vec![
+                        Value::Struct(BTreeMap::from([
+                            (String::from("faultCode"), Value::Int(1)),
+                            (String::from("faultString"), Value::from(restarted)),
+                        ])),
+                        Value::Array(vec![Value::from("Account: nobody")]),
+                    ]),
+                ),
+        );
+        let bofh = connect(&transport);
+        let fetched = get_commands(&transport);
+        let results = bofh.run_commands(&calls).unwrap();
+        assert_eq!(
+            results[0].as_ref().unwrap().as_message(),
+            Some("Account: olanor")
+        );
+        assert_eq!(
+            results[1].as_ref().unwrap().as_message(),
+            Some("Account: nobody")
+        );
+        assert_eq!(get_commands(&transport), fetched + 1);
+        assert_eq!(
+            transport.calls().last(),
+            Some(&(
+                String::from("run_command"),
+                vec![
+                    Value::from("session"),
+                    Value::from("user_info"),
+                    Value::from("olanor")
+                ]
+            ))
+        );
+
+        // So is the whole batch, if the server restarted before it
+        let transport = Arc::new(
+            mock_server()
+                .respond(
+                    "system.listMethods",
+                    Value::Array(vec![Value::from("system.multicall")]),
+                )
+                .respond(
+                    "system.multicall",
+                    Value::Array(vec![
+                        Value::Array(vec![Value::from("Account: olanor")]),
+                        Value::Array(vec![Value::from("Account: nobody")]),
+                    ]),
+                )
+                .fault_once("system.multicall", restarted),
+        );
+        let bofh = connect(&transport);
+        let fetched = get_commands(&transport);
+        let results = bofh.run_commands(&calls).unwrap();
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(get_commands(&transport), fetched + 1);
+    }
+
     #[test]
-    fn connect() {
-        let _bofh = Bofh::new(String::from("https://cerebrum-uio-test.uio.no:8000"));
+    fn nested_args() {
+        let arg = CommandArg::List(vec![
+            CommandArg::from("STUDENT"),
+            CommandArg::Group(vec![String::from("ansatt")]),
+            CommandArg::Map(BTreeMap::from([(
+                String::from("ou"),
+                CommandArg::from("150000"),
+            )])),
+        ]);
+        assert_eq!(
+            Value::from(arg),
+            Value::Array(vec![
+                Value::from("STUDENT"),
+                Value::Array(vec![Value::from("ansatt")]),
+                Value::Struct(BTreeMap::from([(
+                    String::from("ou"),
+                    Value::from("150000")
+                )])),
+            ])
+        );
+    }
+
+    #[test]
+    fn repeated_args() {
+        let command = command_with_args(vec![
+            argument("groupName", false, false),
+            argument("accountName", false, true),
+        ]);
+        let args = |args: &[&str]| -> Vec<CommandArg> {
+            args.iter().map(|&arg| CommandArg::from(arg)).collect()
+        };
+        assert_eq!(
+            command.pack_repeated_args(&args(&["admins", "olanor"])),
+            args(&["admins", "olanor"])
+        );
+        let packed = vec![
+            CommandArg::from("admins"),
+            CommandArg::Group(vec![
+                String::from("olanor"),
+                String::from("karinor"),
+                String::from("pernor"),
+            ]),
+        ];
+        assert_eq!(
+            command.pack_repeated_args(&args(&["admins", "olanor", "karinor", "pernor"])),
+            packed
+        );
+        assert_eq!(
+            command.pack_repeated_args(&[
+                CommandArg::from("admins"),
+                CommandArg::from("olanor"),
+                CommandArg::Group(vec![String::from("karinor"), String::from("pernor")]),
+            ]),
+            packed
+        );
+        let map = CommandArg::Map(BTreeMap::from([(
+            String::from("spread"),
+            CommandArg::from("ldap"),
+        )]));
+        assert_eq!(
+            command.pack_repeated_args(&[
+                CommandArg::from("admins"),
+                CommandArg::from("olanor"),
+                map.clone(),
+            ]),
+            [
+                CommandArg::from("admins"),
+                CommandArg::List(vec![CommandArg::from("olanor"), map]),
+            ]
+        );
+        let not_repeated = command_with_args(vec![argument("groupName", false, false)]);
+        assert_eq!(
+            not_repeated.pack_repeated_args(&args(&["admins", "olanor"])),
+            args(&["admins", "olanor"])
+        );
+
+        let transport = Arc::new(mock_server());
+        let bofh = connect(&transport);
+        bofh.run_command(&command, &["admins", "olanor", "karinor"])
+            .unwrap();
+        let packed = Some((
+            String::from("run_command"),
+            vec![
+                Value::from("session"),
+                Value::from("group_add"),
+                Value::from("admins"),
+                Value::Array(vec![Value::from("olanor"), Value::from("karinor")]),
+            ],
+        ));
+        assert_eq!(transport.calls().last(), packed.as_ref());
+
+        // Writing the result checks and packs the arguments the same way
+        let calls = transport.calls().len();
+        let mut output = vec![];
+        let formatter = crate::format::PlainFormatter::default();
+        assert!(matches!(
+            bofh.run_command_to_writer(&command, &["admins"], &formatter, &mut output),
+            Err(BofhError::ArgumentError { .. })
+        ));
+        assert_eq!(transport.calls().len(), calls);
+        bofh.run_command_to_writer(
+            &command,
+            &["admins", "olanor", "karinor"],
+            &formatter,
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(transport.calls().last(), packed.as_ref());
+        assert_eq!(output, b"Account: olanor\n");
+    }
+
+    #[test]
+    fn resume_session() {
+        let transport = Arc::new(mock_server());
+        let mut bofh = connect(&transport);
+        assert_eq!(bofh.session_id(), "session");
+        bofh.set_logout_on_drop(false);
+        drop(bofh);
+        assert!(transport
+            .calls()
+            .iter()
+            .all(|(method, _)| method != "logout"));
+
+        let unauthenticated = || {
+            BofhBuilder::new(String::from("https://bofhd.example.com:8000"))
+                .transport(Arc::clone(&transport) as _)
+                .connect()
+                .unwrap()
+        };
+        let bofh = unauthenticated()
+            .with_session(String::from("session"))
+            .unwrap();
+        assert!(bofh.is_authenticated());
+        assert_eq!(bofh.commands().len(), 1);
+        assert_eq!(
+            transport.calls().last(),
+            Some(&(String::from("get_commands"), vec![Value::from("session")]))
+        );
+
+        let expired = Arc::new(mock_server().fault(
+            "get_commands",
+            "Cerebrum.modules.bofhd.errors.SessionExpiredError:Session expired",
+        ));
+        let result = BofhBuilder::new(String::from("https://bofhd.example.com:8000"))
+            .transport(Arc::clone(&expired) as _)
+            .build_with_session(String::from("old"));
+        assert!(matches!(result, Err(BofhError::SessionExpiredError(_))));
+        assert!(expired.calls().iter().all(|(method, _)| method != "logout"));
+    }
+
+    #[test]
+    fn retry_after_login() {
+        let transport = Arc::new(mock_server().fault_once(
+            "run_command",
+            "Cerebrum.modules.bofhd.errors.SessionExpiredError:Session expired",
+        ));
+        let mut bofh = connect(&transport);
+        let command = bofh.commands()["user"].commands["info"].clone();
+        let request = match bofh.run_command(&command, &["olanor"]) {
+            Err(BofhError::SessionExpiredError(request)) => request,
+            result => panic!("unexpected result {:?}", result),
+        };
+        let response = bofh
+            .retry_after_login("olanor", String::from("secret"), &request)
+            .unwrap();
+        assert_eq!(response.as_message(), Some("Account: olanor"));
+        assert!(bofh.is_authenticated());
+    }
+
+    #[test]
+    fn no_session_after_end_session() {
+        let mut bofh = connect(&Arc::new(mock_server()));
+        assert!(bofh.end_session().is_ok());
+        assert!(matches!(bofh.end_session(), Err(BofhError::NoSessionError)));
+        assert!(matches!(
+            bofh.run_command_str("user_info", &[]),
+            Err(BofhError::NoSessionError)
+        ));
+        assert!(!bofh.is_authenticated());
+        bofh.login_again("olanor", String::from("secret")).unwrap();
+        assert!(bofh.is_authenticated());
+        assert!(!bofh.commands().is_empty());
+    }
+
+    #[test]
+    fn logout() {
+        let transport = Arc::new(mock_server());
+        let (bofh, result) = connect(&transport).logout();
+        assert!(result.is_ok());
+        assert!(!bofh.is_authenticated());
+        assert_eq!(
+            transport.calls().last(),
+            Some(&(String::from("logout"), vec![Value::from("session")]))
+        );
+        let bofh = bofh.login("olanor", String::from("secret")).unwrap();
+        assert!(bofh.is_authenticated());
+
+        let failing = Arc::new(mock_server().fault(
+            "logout",
+            "Cerebrum.modules.bofhd.errors.CerebrumError:Could not log out",
+        ));
+        let (bofh, result) = connect(&failing).logout();
+        assert!(matches!(result, Err(BofhError::CerebrumError { .. })));
+        assert!(!bofh.is_authenticated());
+        let calls = failing.calls().len();
+        let bofh = bofh.login("olanor", String::from("secret")).unwrap();
+        assert!(bofh.is_authenticated());
+        drop(bofh);
+        // The failed session isn't logged out again, only the new one
+        assert_eq!(
+            failing.calls()[calls..]
+                .iter()
+                .filter(|(method, _)| method == "logout")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn faults() {
+        let server = |fault: &str| {
+            Arc::new(mock_server().fault(
+                "run_command",
+                &format!("Cerebrum.modules.bofhd.errors.{}", fault),
+            ))
+        };
+        let run = |bofh: &Bofh| bofh.run_command_str("user_info", &["olanor"]);
+        let bofh = connect(&server("PermissionDenied:Not allowed"));
+        assert!(
+            matches!(run(&bofh), Err(BofhError::PermissionDenied(message)) if message == "Not allowed")
+        );
+        let bofh = connect(&server("CerebrumError:Could not find account"));
+        assert!(matches!(run(&bofh), Err(BofhError::CerebrumError { .. })));
+        let bofh = connect(&server("SessionExpiredError:"));
+        assert!(matches!(run(&bofh), Err(BofhError::SessionExpiredError(_))));
+        assert!(bofh.session_expired());
+        assert!(!bofh.is_authenticated());
+    }
+
+    #[test]
+    fn invalid_certificates() {
+        let path = std::env::temp_dir().join("bofh-invalid-certificate.pem");
+        assert!(matches!(
+            read_certificates(&path.with_extension("missing")),
+            Err(BofhError::CertificateError(_))
+        ));
+        std::fs::write(&path, "not a certificate").unwrap();
+        assert!(matches!(
+            read_certificates(&path),
+            Err(BofhError::CertificateError(_))
+        ));
+        std::fs::write(
+            &path,
+            "-----BEGIN CERTIFICATE-----\nbm9wZQ==\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            read_certificates(&path),
+            Err(BofhError::CertificateError(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn prompt_map() {
+        let entry = |description: Vec<Value>, value: Value| {
+            Value::Array(vec![Value::Array(description), value])
+        };
+        let map = Value::Array(vec![
+            entry(vec![Value::from("Accounts"), Value::Nil], Value::Nil),
+            entry(
+                vec![
+                    Value::from("%-3s %s"),
+                    Value::from("1"),
+                    Value::from("olanor"),
+                ],
+                Value::from("olanor"),
+            ),
+            entry(
+                vec![
+                    Value::from("%-3s %s"),
+                    Value::from("2"),
+                    Value::from("karinor"),
+                ],
+                Value::from("karinor"),
+            ),
+        ]);
+        let prompt_map = PromptMap::from_value(&map).unwrap();
+        assert_eq!(prompt_map.headers, vec!["Accounts"]);
+        assert_eq!(prompt_map.rows.len(), 2);
+        assert_eq!(prompt_map.rows[0].display, "1   olanor");
+        assert_eq!(prompt_map.rows[1].value, Value::from("karinor"));
+    }
+
+    #[test]
+    fn read_only_heuristic() {
+        let command = |name: &str, read_only| Command {
+            fullname: format!("user_{}", name),
+            name: name.to_owned(),
+            args: vec![],
+            format_suggestion: None,
+            help: None,
+            read_only,
+            permissions: None,
+            prompt_func: false,
+        };
+        assert!(command("info", None).is_read_only());
+        assert!(command("list_members", None).is_read_only());
+        assert!(!command("delete", None).is_read_only());
+        assert!(!command("info", Some(false)).is_read_only());
+        assert!(command("delete", Some(true)).is_read_only());
+    }
+
+    fn command_with_args(args: Vec<Argument>) -> Command {
+        Command {
+            fullname: String::from("group_add"),
+            name: String::from("add"),
+            args,
+            format_suggestion: None,
+            help: None,
+            read_only: None,
+            permissions: None,
+            prompt_func: false,
+        }
+    }
+
+    fn argument(arg_type: &str, optional: bool, repeat: bool) -> Argument {
+        Argument {
+            optional,
+            repeat,
+            arg_type: Some(ArgType::from(arg_type)),
+            ..Argument::default()
+        }
+    }
+
+    #[test]
+    fn logged_args() {
+        let command = command_with_args(vec![
+            argument("accountName", false, false),
+            argument("accountPassword", false, true),
+        ]);
+        let args = [
+            CommandArg::from("olanor"),
+            CommandArg::from("secret"),
+            CommandArg::from("hunter2"),
+        ];
+        assert_eq!(
+            command.logged_args(&args),
+            [r#"Value("olanor")"#, "<redacted>", "<redacted>"]
+        );
+        let prompt_func = Command {
+            prompt_func: true,
+            ..command_with_args(vec![])
+        };
+        assert_eq!(prompt_func.logged_args(&args[..1]), ["<redacted>"]);
+    }
+
+    #[test]
+    fn display() {
+        let mut command = command_with_args(vec![
+            Argument {
+                prompt: Some(String::from("Group")),
+                ..argument("groupName", false, false)
+            },
+            argument("accountName", true, true),
+        ]);
+        assert_eq!(command.args[0].to_string(), "<Group>");
+        assert_eq!(command.args[1].to_string(), "[<accountName>...]");
+        assert_eq!(command.to_string(), "group add <Group> [<accountName>...]");
+        let group = CommandGroup {
+            name: String::from("group"),
+            commands: BTreeMap::from([(String::from("add"), command.clone())]),
+        };
+        assert_eq!(
+            group.to_string(),
+            "group\n  add  <Group> [<accountName>...]"
+        );
+
+        command.args = vec![];
+        command.prompt_func = true;
+        assert_eq!(command.to_string(), "group add (prompted by the server)");
+        command.prompt_func = false;
+        command.args = vec![];
+        assert_eq!(command.to_string(), "group add");
+    }
+
+    #[test]
+    fn usage() {
+        let command = command_with_args(vec![
+            argument("accountName", false, false),
+            argument("groupName", false, false),
+            argument("email", true, false),
+        ]);
+        assert_eq!(command.usage(), "accountName groupName [email]");
+        assert_eq!(command.usage_after(1), "groupName [email]");
+        assert_eq!(command.usage_after(2), "[email]");
+        assert_eq!(command.usage_after(3), "");
+        assert_eq!(command.usage_after(5), "");
+
+        let command = command_with_args(vec![
+            argument("groupName", false, false),
+            argument("accountName", false, true),
+        ]);
+        assert_eq!(command.usage(), "groupName accountName...");
+        assert_eq!(command.usage_after(1), "accountName...");
+        assert_eq!(command.usage_after(2), "accountName...");
+        assert_eq!(command.usage_after(7), "accountName...");
+
+        let command = command_with_args(vec![argument("spread", true, true)]);
+        assert_eq!(command.usage_after(0), "[spread...]");
+        assert_eq!(command.usage_after(3), "[spread...]");
+
+        assert_eq!(command_with_args(vec![]).usage_after(1), "");
+    }
+
+    #[test]
+    fn argument_count() {
+        let command = command_with_args(vec![
+            argument("accountName", false, false),
+            argument("email", true, false),
+            argument("spread", true, true),
+        ]);
+        assert!(matches!(
+            command.validate_args(0),
+            Err(BofhError::ArgumentError { got: 0, .. })
+        ));
+        assert!(command.validate_args(1).is_ok());
+        assert!(command.validate_args(2).is_ok());
+        assert!(command.validate_args(5).is_ok());
+
+        let command = command_with_args(vec![
+            argument("accountName", false, false),
+            argument("email", true, false),
+        ]);
+        assert!(command.validate_args(2).is_ok());
+        assert_eq!(
+            command.validate_args(3).unwrap_err().to_string(),
+            "Wrong number of arguments, expected 1 to 2 but got 3"
+        );
+        assert_eq!(
+            command_with_args(vec![argument("spread", false, true)])
+                .validate_args(0)
+                .unwrap_err()
+                .to_string(),
+            "Wrong number of arguments, expected at least 1 but got 0"
+        );
+
+        assert_eq!(command.arity(), Some((1, Some(2))));
+
+        // Commands with a prompt function
+        let prompt_func = Command {
+            prompt_func: true,
+            ..command_with_args(vec![])
+        };
+        assert!(prompt_func.validate_args(3).is_ok());
+        assert_eq!(prompt_func.arity(), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_commands() {
+        let command = command_with_args(vec![argument("accountName", false, false)]);
+        let json = serde_json::to_string(&command).unwrap();
+        assert!(json.contains(r#""arg_type":"accountName""#));
+        assert!(!json.contains("help"));
+        let command: Command = serde_json::from_str(&json).unwrap();
+        assert_eq!(command.args[0].arg_type, Some(ArgType::AccountName));
+        assert_eq!(command.usage(), "accountName");
+    }
+
+    #[test]
+    fn malformed_commands() {
+        let names =
+            |group: &str, name: &str| Value::Array(vec![Value::from(group), Value::from(name)]);
+        let spec = Value::Array(vec![
+            names("user", "info"),
+            Value::Array(vec![Value::Struct(
+                [
+                    (String::from("type"), Value::from("accountName")),
+                    (String::from("optional"), Value::from("True")),
+                    (String::from("default"), Value::Nil),
+                ]
+                .into_iter()
+                .collect(),
+            )]),
+        ]);
+        let (group, command) = parse_command("user_info", &spec).unwrap();
+        assert_eq!(group, "user");
+        assert_eq!(command.usage(), "[accountName]");
+        assert!(command.args[0].default.is_none());
+
+        let no_group = Value::Array(vec![Value::Array(vec![]), Value::Array(vec![])]);
+        assert_eq!(
+            parse_command("user_info", &no_group)
+                .unwrap_err()
+                .to_string(),
+            "Malformed response from the server: command user_info: command tuple missing group name"
+        );
+        let bad_type = Value::Array(vec![
+            names("user", "info"),
+            Value::Array(vec![Value::Struct(
+                [(String::from("type"), Value::Int(1))]
+                    .into_iter()
+                    .collect(),
+            )]),
+        ]);
+        assert!(matches!(
+            parse_command("user_info", &bad_type),
+            Err(BofhError::MalformedResponse(_))
+        ));
+        assert!(parse_command("user_info", &Value::Nil).is_err());
+    }
+
+    #[test]
+    fn server_computed_defaults() {
+        let command = |group: &str, name: &str, default: Value| {
+            Value::Array(vec![
+                Value::Array(vec![Value::from(group), Value::from(name)]),
+                Value::Array(vec![Value::Struct(
+                    [
+                        (String::from("type"), Value::from("accountName")),
+                        (String::from("default"), default),
+                    ]
+                    .into_iter()
+                    .collect(),
+                )]),
+            ])
+        };
+        let transport = Arc::new(
+            mock_server().respond(
+                "get_commands",
+                Value::Struct(
+                    [
+                        (
+                            String::from("user_info"),
+                            command("user", "info", Value::Int(1)),
+                        ),
+                        (
+                            String::from("user_home"),
+                            command("user", "home", Value::Bool(true)),
+                        ),
+                        (
+                            String::from("user_shell"),
+                            command("user", "shell", Value::from("bash")),
+                        ),
+                        (
+                            String::from("user_quarantine"),
+                            command("user", "quarantine", Value::Array(vec![])),
+                        ),
+                    ]
+                    .into_iter()
+                    .collect(),
+                ),
+            ),
+        );
+        let bofh = connect(&transport);
+        let user = &bofh.commands()["user"].commands;
+        assert!(user["info"].args[0].server_default);
+        assert!(user["info"].args[0].default.is_none());
+        assert!(user["home"].args[0].server_default);
+        assert!(!user["shell"].args[0].server_default);
+        assert_eq!(user["shell"].args[0].default.as_deref(), Some("bash"));
+        // Only the command that can't be parsed is skipped
+        assert!(!user.contains_key("quarantine"));
+        assert_eq!(user.len(), 3);
+    }
+
+    #[test]
+    fn cerebrum_errors() {
+        let parse = |fault| match parse_cerebrum_error(fault) {
+            BofhError::CerebrumError { kind, message } => (kind, message),
+            err => panic!("unexpected error {:?}", err),
+        };
+        assert_eq!(
+            parse("CerebrumError:Could not find account: nobody"),
+            (
+                String::from("CerebrumError"),
+                String::from("Could not find account: nobody")
+            )
+        );
+        assert_eq!(
+            parse("UnknownError:Something went wrong"),
+            (
+                String::from("UnknownError"),
+                String::from("Something went wrong")
+            )
+        );
+        assert_eq!(
+            parse("CerebrumError:Cerebrum.Errors.NotFoundError: Could not find account"),
+            (
+                String::from("Cerebrum.Errors.NotFoundError"),
+                String::from("Could not find account")
+            )
+        );
+        assert_eq!(
+            parse_cerebrum_error("CerebrumError:No such group").to_string(),
+            "No such group"
+        );
+    }
+
+    #[test]
+    fn bofh_bools() {
+        assert!(parse_bofh_bool(&Value::from("True")));
+        assert!(parse_bofh_bool(&Value::from("true")));
+        assert!(parse_bofh_bool(&Value::from("1")));
+        assert!(parse_bofh_bool(&Value::Bool(true)));
+        assert!(!parse_bofh_bool(&Value::from("False")));
+        assert!(!parse_bofh_bool(&Value::Bool(false)));
+        assert!(!parse_bofh_bool(&Value::Nil));
+
+        let argument = parse_argument(&Value::Struct(
+            [(String::from("repeat"), Value::from("true"))]
+                .into_iter()
+                .collect(),
+        ))
+        .unwrap();
+        assert!(argument.repeat);
+        // Missing
+        assert!(!argument.optional);
+    }
+
+    #[test]
+    fn server_version() {
+        assert_eq!(
+            parse_server_version("Welcome to bofhd version 1.2.3.\nMaintenance tonight"),
+            Some(String::from("1.2.3"))
+        );
+        assert_eq!(
+            parse_server_version("Cerebrum Version 0.9-beta"),
+            Some(String::from("0.9-beta"))
+        );
+        assert_eq!(parse_server_version("The new version is out"), None);
+        assert_eq!(parse_server_version("Welcome"), None);
+        assert_eq!(parse_server_version("version"), None);
+    }
+
+    #[test]
+    fn malformed_session() {
+        assert_eq!(parse_session(&Value::from("abc123")).unwrap(), "abc123");
+        let error = Value::Struct(
+            [(String::from("error"), Value::from("Unknown user"))]
+                .into_iter()
+                .collect(),
+        );
+        assert!(matches!(
+            parse_session(&error),
+            Err(BofhError::MalformedResponse(_))
+        ));
+        assert!(matches!(
+            parse_session(&Value::Int(1)),
+            Err(BofhError::MalformedResponse(_))
+        ));
+    }
+
+    #[test]
+    fn expired_request_hides_arguments() {
+        let request = ExpiredRequest {
+            command: String::from("run_command"),
+            args: vec![
+                CommandArg::from("user_password"),
+                CommandArg::from("hunter2"),
+            ],
+        };
+        let debug = format!("{:?}", BofhError::SessionExpiredError(request));
+        assert!(debug.contains("run_command"));
+        assert!(!debug.contains("hunter2"));
+    }
+
+    #[test]
+    fn prompt_func_result() {
+        let response = Value::Struct(
+            [
+                (String::from("prompt"), Value::from("Account type")),
+                (String::from("last_arg"), Value::from("True")),
+                (String::from("default"), Value::Int(1)),
+                (
+                    String::from("map"),
+                    Value::Array(vec![Value::Array(vec![
+                        Value::Array(vec![Value::from("%s"), Value::from("posix")]),
+                        Value::from("p"),
+                    ])]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let result = PromptFuncResult::from_value(&response).unwrap();
+        assert_eq!(result.prompt, "Account type");
+        assert!(result.last_arg);
+        assert!(!result.raw);
+        assert_eq!(result.default.as_deref(), Some("1"));
+        assert_eq!(result.help_ref, None);
+        assert_eq!(result.map.unwrap().rows[0].display, "posix");
+        assert!(PromptFuncResult::from_value(&Value::from("prompt")).is_none());
+    }
+
+    #[test]
+    fn arg_type_round_trip() {
+        for name in ["accountName", "yesNo", "string", "someNewType"] {
+            let arg_type: ArgType = name.parse().unwrap();
+            assert_eq!(arg_type.to_string(), name);
+        }
+        assert_eq!(ArgType::from("groupName"), ArgType::GroupName);
+        assert_eq!(ArgType::from("string"), ArgType::SimpleString);
+        assert_eq!(
+            ArgType::from("posixShell"),
+            ArgType::Other(String::from("posixShell"))
+        );
+        assert_eq!(ArgType::YesNo.values(), Some(&["yes", "no"][..]));
+        assert_eq!(ArgType::AccountName.values(), None);
     }
 }
@@ -1,25 +1,175 @@
-use bofh::Bofh;
-use clap::Parser;
+use bofh::format::{
+    limit_rows, DateTimeFormat, DebugFormatter, JsonFormatter, OutputFormatter, PlainFormatter,
+};
+use bofh::{Bofh, BofhBuilder, BofhError, CommandArg, ExpiredRequest, Response, Unauthenticated};
+use clap::{Parser, ValueEnum};
+use colored::Colorize;
+use log::LevelFilter;
+mod completions;
+mod config;
+mod favorites;
+mod help;
 mod helper;
-use crate::helper::BofhHelper;
-use rpassword::prompt_password;
+mod internal;
+mod interrupt;
+mod pager;
+mod password;
+mod plan;
+mod prompt;
+mod replay;
+mod session;
+mod source;
+mod template;
+mod tokenize;
+use crate::config::Config;
+use crate::helper::{BofhHelper, MatchMode};
+use crate::password::PasswordSource;
+use crate::plan::Plan;
+use crate::session::Sessions;
+use crate::tokenize::Token;
+use bofh::Value;
 use rustyline::{config::Configurer, error::ReadlineError, Editor};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How command results are written
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub(crate) enum Format {
+    /// The raw XML-RPC values
+    Debug,
+    /// Plain text, one row or struct member per line
+    Plain,
+    /// JSON on a single line, with dates and times as ISO 8601 strings
+    Json,
+}
+
+impl Format {
+    /// The formatter for this format, coloring the output if `color` is set and the format supports it
+    fn formatter(self, datetime: DateTimes, color: bool) -> Box<dyn OutputFormatter> {
+        match self {
+            Self::Debug => Box::new(DebugFormatter),
+            Self::Plain => Box::new(PlainFormatter {
+                datetime: datetime.into(),
+                color,
+            }),
+            Self::Json => Box::new(JsonFormatter),
+        }
+    }
+}
+
+/// How date and time values in command results are written
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub(crate) enum DateTimes {
+    /// ISO 8601, as sent by the server
+    Iso8601,
+    /// In the local timezone
+    Local,
+}
+
+impl From<DateTimes> for DateTimeFormat {
+    fn from(datetime: DateTimes) -> Self {
+        match datetime {
+            DateTimes::Iso8601 => Self::Iso8601,
+            DateTimes::Local => Self::Local,
+        }
+    }
+}
 
 /// The Cerebrum Bofh client
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
-struct Args {
+pub(crate) struct Args {
     /// Run command and exit
     #[clap(long)]
     cmd: Option<String>,
 
+    /// Print all available commands, one per line, and exit.
+    /// Each line holds the tab-separated group, subcommand and argument types.
+    #[clap(long, conflicts_with_all = &["cmd", "replay"])]
+    dump_commands: bool,
+
+    /// Print a completion script for SHELL based on the server's commands, and exit
+    #[clap(long, value_enum, value_name = "SHELL", conflicts_with_all = &["cmd", "replay", "dump-commands"])]
+    completions: Option<completions::Shell>,
+
+    /// Replay a recorded session from FILE and report differing output
+    #[clap(long, value_name = "FILE", conflicts_with = "cmd")]
+    replay: Option<String>,
+
+    /// Record the commands run at the prompt and their output to FILE, for --replay. Passwords are left out
+    #[clap(long, value_name = "FILE", conflicts_with_all = &["cmd", "replay"])]
+    record: Option<String>,
+
+    /// Run at most N commands per second when replaying or sourcing commands
+    #[clap(long, value_name = "N", conflicts_with = "delay")]
+    rate: Option<f64>,
+
+    /// Wait MS milliseconds between commands when replaying or sourcing commands
+    #[clap(long, value_name = "MS")]
+    delay: Option<u64>,
+
+    /// Allow calling bofhd methods directly with the `raw` command. Nothing stops a raw call from doing damage
+    #[clap(long, conflicts_with = "read-only")]
+    allow_raw: bool,
+
+    /// Refuse to run commands that (probably) modify state
+    #[clap(long)]
+    read_only: bool,
+
+    /// Show at most N rows of each command result
+    #[clap(long, help_heading = "Output settings", value_name = "N")]
+    max_rows: Option<usize>,
+
+    /// Match commands and subcommands regardless of case, like `User Info olanor`.
+    /// Arguments are always sent as typed.
+    #[clap(long)]
+    normalize_commands: bool,
+
+    /// Match typed commands and subcommands with MODE, for completion, highlighting and running them.
+    /// With `substring`, `nfo` matches `info`; with `fuzzy`, `ifo` does too.
+    #[clap(long, value_enum, value_name = "MODE", default_value_t = MatchMode::Prefix)]
+    match_mode: MatchMode,
+
+    /// Send unknown commands to the server as is, like `user_info olanor`, instead of
+    /// reporting them as unknown. Beware that typos will be sent to the server too.
+    /// Has no effect with --read-only.
+    #[clap(long)]
+    passthrough: bool,
+
+    /// Write command results in FORMAT. Can be overridden for a single command with a
+    /// `format FORMAT` prefix or a `--FORMAT` suffix, like `user info olanor --plain`
+    #[clap(long, value_enum, help_heading = "Output settings", value_name = "FORMAT", default_value_t = Format::Debug)]
+    format: Format,
+
+    /// Write date and time values in command results in FORMAT (only with --format plain)
+    #[clap(long, value_enum, help_heading = "Output settings", value_name = "FORMAT", default_value_t = DateTimes::Iso8601)]
+    datetime: DateTimes,
+
+    /// Never show long command results in a pager ($PAGER, or `less -R`)
+    #[clap(long, help_heading = "Output settings")]
+    no_pager: bool,
+
+    /// Never use colors. Colors are also left out if the NO_COLOR environment variable is set, or if stdout isn't a terminal
+    #[clap(long, help_heading = "Output settings")]
+    no_color: bool,
+
+    /// Write command results to FILE instead of stdout
+    #[clap(short, long, value_name = "FILE")]
+    output: Option<String>,
+
     /// Use CA certificates from PEM
-    #[clap(short, long, help_heading = "Connection settings", value_name = "PEM", default_value_t = String::from("foo"))]
-    cert: String,
+    #[clap(short, long, help_heading = "Connection settings", value_name = "PEM")]
+    cert: Option<String>,
 
-    /// set verbosity of log messages to N
+    /// set verbosity of log messages to N: 1 logs the bofhd methods called, 2 their arguments too,
+    /// and 3 the faults returned by the server
     #[clap(long, help_heading = "Output settings", value_name = "N")]
-    verbosity: Option<String>,
+    verbosity: Option<u8>,
 
     /// increase verbosity of log messages
     #[clap(
@@ -42,11 +192,31 @@ struct Args {
     #[clap(long, short, help_heading = "Connection settings", default_value_t = whoami::username())]
     user: String,
 
-    /// skip certificate hostname validation
+    /// qualify the username with REALM (user@REALM), unless it already has one
+    #[clap(long, help_heading = "Connection settings")]
+    realm: Option<String>,
+
+    /// read the password from the first line of FILE
+    #[clap(long, help_heading = "Connection settings", value_name = "FILE")]
+    password_file: Option<String>,
+
+    /// read the password from the first line of stdin
+    #[clap(
+        long,
+        help_heading = "Connection settings",
+        conflicts_with = "password-file"
+    )]
+    password_stdin: bool,
+
+    /// keep the session when exiting, and resume it next time instead of asking for the password
+    #[clap(long, help_heading = "Connection settings")]
+    save_session: bool,
+
+    /// skip certificate and hostname validation (insecure, for test servers)
     #[clap(long, help_heading = "Connection settings")]
     insecure: bool,
 
-    /// set connection timeout to N seconds
+    /// set connection timeout to N seconds (0 means no timeout)
     #[clap(
         long,
         default_value_t = 0,
@@ -60,16 +230,523 @@ struct Args {
     #[clap(long, help_heading = "REPL behavior", alias = "vim")]
     vi: bool,
 
-    /// use a custom prompt
+    /// check for a changed Message of the Day every N minutes, and show it if it changed
+    #[clap(long, help_heading = "REPL behavior", value_name = "N")]
+    motd_interval: Option<u64>,
+
+    /// stop `source` at the first command that fails
+    #[clap(long, help_heading = "REPL behavior")]
+    strict: bool,
+
+    /// keep at most N lines in the command history
+    #[clap(
+        long,
+        help_heading = "REPL behavior",
+        value_name = "N",
+        default_value_t = 1000
+    )]
+    history_size: usize,
+
+    /// keep the command history in FILE [default: bofh/history in the platform's data directory]
+    #[clap(long, help_heading = "REPL behavior", value_name = "FILE")]
+    history_file: Option<PathBuf>,
+
+    /// use a custom prompt, which may contain {user}, {url}, {host} and {status} (ok, or expired when the session has expired)
     #[clap(long, short, help_heading = "REPL behavior", default_value_t = String::from("bofh> "))]
     prompt: String,
 }
 
+impl Args {
+    /// Whether to use colors, see `--no-color`
+    fn color(&self) -> bool {
+        !self.no_color
+            && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+            && io::stdout().is_terminal()
+    }
+
+    /// The level of log messages to show, as set by `--quiet`, `--verbosity` or `-v`
+    fn log_level(&self) -> LevelFilter {
+        if self.quiet {
+            return LevelFilter::Off;
+        }
+        match self.verbosity.unwrap_or(self.verbosity_level) {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+
+    /// Where the command history is kept, as set by `--history-file`
+    fn history_path(&self) -> Option<PathBuf> {
+        self.history_file
+            .clone()
+            .or_else(|| dirs::data_dir().map(|dir| dir.join("bofh").join("history")))
+    }
+
+    /// The delay between commands in batch modes, as set by `--rate` or `--delay`
+    fn command_delay(&self) -> Duration {
+        match (self.rate, self.delay) {
+            (Some(rate), _) if rate > 0.0 => Duration::from_secs_f64(1.0 / rate),
+            (_, Some(delay)) => Duration::from_millis(delay),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+/// Qualifies a username with a realm, as `user@REALM`, unless it already has one
+fn qualify_username(user: &str, realm: Option<&str>) -> String {
+    match realm {
+        Some(realm) if !user.contains('@') => format!("{}@{}", user, realm),
+        _ => user.to_owned(),
+    }
+}
+
+/// Gets the user's password from `passwords` and authenticates with it.
+///
+/// Returns `Ok(None)` if no password could be read, like when the user cancels the prompt.
+fn authenticate(
+    bofh: Bofh<Unauthenticated>,
+    user: &str,
+    passwords: &mut dyn PasswordSource,
+) -> Result<Option<Bofh>, BofhError> {
+    let password = match passwords.password(&format!("Password for {}: ", user)) {
+        Ok(password) => password,
+        Err(_) => return Ok(None),
+    };
+    bofh.authenticate(user, password).map(Some)
+}
+
+/// Gets the user's password from `passwords` after the session has expired, logs in again, and retries `request`.
+///
+/// Returns the [`BofhError::SessionExpiredError`] as it is if no password could be read.
+fn retry_expired(
+    bofh: &mut Bofh,
+    user: &str,
+    request: ExpiredRequest,
+    passwords: &mut dyn PasswordSource,
+) -> Result<Response, BofhError> {
+    match passwords.password(&format!("Password for {}: ", user)) {
+        Ok(password) => bofh.retry_after_login(user, password, &request),
+        Err(_) => Err(BofhError::SessionExpiredError(request)),
+    }
+}
+
+/// Resumes the session saved for `user` by `--save-session`, if there is one that's still valid.
+///
+/// Otherwise, returns a client to log in with: `bofh`, or a new one built with `builder` if the saved session was tried.
+fn resume_session(
+    bofh: Bofh<Unauthenticated>,
+    builder: &BofhBuilder,
+    args: &Args,
+    user: &str,
+) -> Result<Result<Bofh, Bofh<Unauthenticated>>, BofhError> {
+    if !args.save_session {
+        return Ok(Err(bofh));
+    }
+    let session = match Sessions::load() {
+        Ok(sessions) => sessions.get(&args.url, user).map(str::to_owned),
+        Err(err) => {
+            eprintln!("Warning: Failed to load the saved session: {}", err);
+            None
+        }
+    };
+    let Some(session) = session else {
+        return Ok(Err(bofh));
+    };
+    match bofh.with_session(session) {
+        Ok(bofh) => Ok(Ok(bofh)),
+        Err(err) => {
+            log::info!("Failed to resume the saved session: {}", err);
+            forget_session(args, user);
+            builder.clone().connect().map(Err)
+        }
+    }
+}
+
+/// Saves the session for resuming it in the next run, and keeps it open when exiting, if `--save-session` is set
+fn save_session(args: &Args, user: &str, bofh: &mut Bofh) {
+    if !args.save_session {
+        return;
+    }
+    bofh.set_logout_on_drop(false);
+    if let Err(err) = Sessions::load().and_then(|mut sessions| {
+        sessions.insert(&args.url, user, bofh.session_id());
+        sessions.save()
+    }) {
+        eprintln!("Warning: Failed to save the session: {}", err);
+    }
+}
+
+/// Forgets the session saved for `user` by `--save-session`, like when it's logged out
+fn forget_session(args: &Args, user: &str) {
+    if !args.save_session {
+        return;
+    }
+    if let Err(err) = Sessions::load().and_then(|mut sessions| {
+        if sessions.remove(&args.url, user) {
+            sessions.save()?;
+        }
+        Ok(())
+    }) {
+        eprintln!("Warning: Failed to forget the saved session: {}", err);
+    }
+}
+
+/// Writes to the transcript started by `script`, if there is one.
+///
+/// If writing fails, the recording is stopped, so the failure is only reported once.
+fn transcribe(
+    script: &mut Option<(String, File)>,
+    write: impl FnOnce(&mut File) -> io::Result<()>,
+) {
+    if let Some((path, file)) = script {
+        if let Err(err) = write(file) {
+            eprintln!("Failed to write to {}, stopped recording: {}", path, err);
+            *script = None;
+        }
+    }
+}
+
+/// How long to wait for the Message of the Day, see [`bofh::BofhBuilder::motd_timeout`]
+const MOTD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many times to ask the user for the password when logging in again
+const LOGIN_ATTEMPTS: usize = 3;
+
+/// Logs in again after `logout`, asking for the password until it's accepted, at most [`LOGIN_ATTEMPTS`] times.
+/// A password that can't change, like one from `--password-file`, is only tried once, so a wrong one isn't sent
+/// again and again (see [`PasswordSource::can_retry`]).
+///
+/// If `bofh` is `None`, or a login attempt fails, a new client is built with `builder`.
+/// Returns `None` if the user cancels the password prompt, if the server can't be reached, or if no attempt succeeds.
+fn log_in_again(
+    mut bofh: Option<Bofh<Unauthenticated>>,
+    builder: &BofhBuilder,
+    user: &str,
+    passwords: &mut dyn PasswordSource,
+) -> Option<Bofh> {
+    let attempts = if passwords.can_retry() {
+        LOGIN_ATTEMPTS
+    } else {
+        1
+    };
+    for _ in 0..attempts {
+        let unauthenticated = match bofh.take() {
+            Some(bofh) => bofh,
+            None => match builder.clone().connect() {
+                Ok(bofh) => bofh,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return None;
+                }
+            },
+        };
+        match authenticate(unauthenticated, user, passwords) {
+            Ok(bofh) => return bofh,
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+    None
+}
+
+/// Appends the lines entered in this session to the history file at `path`, creating its directory if necessary
+fn save_history(rl: &mut Editor<BofhHelper>, path: Option<&Path>) -> Result<(), String> {
+    let path = path.ok_or("Failed to find a data directory")?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|err| format!("Failed to create {}: {}", dir.display(), err))?;
+    }
+    rl.append_history(path)
+        .map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+}
+
+/// Writes a command result in `format` to stdout, or to a file if one is given.
+/// Results that don't fit in the terminal are shown in a pager, unless `--no-pager` is set.
+///
+/// Binary results are written to files as raw bytes.
+fn write_result(value: &Value, args: &Args, format: Format, output: Option<(&str, &mut File)>) {
+    let (value, row_count) = limit_rows(value, args.max_rows);
+    if row_count.is_truncated() {
+        eprintln!(
+            "Showing {} of {} rows (see --max-rows)",
+            row_count.displayed, row_count.total
+        );
+    }
+    if let Some((path, file)) = output {
+        let bytes = match &value {
+            Value::Base64(bytes) => bytes.clone(),
+            value => format
+                .formatter(args.datetime, false)
+                .format(value)
+                .into_bytes(),
+        };
+        match file.write_all(&bytes) {
+            Ok(()) => println!("Wrote {} bytes to {}", bytes.len(), path),
+            Err(err) => eprintln!("Failed to write to {}: {}", path, err),
+        }
+    } else {
+        let text = format.formatter(args.datetime, args.color()).format(&value);
+        if args.no_pager || !pager::page(&text) {
+            if let Err(err) = io::stdout().write_all(text.as_bytes()) {
+                eprintln!("Failed to write the result: {}", err);
+            }
+        }
+    }
+}
+
+/// A command result as `write_result` writes it in `format`, without colors or a pager, like for a recording
+pub(crate) fn render_result(value: &Value, args: &Args, format: Format) -> String {
+    let (value, _) = limit_rows(value, args.max_rows);
+    format.formatter(args.datetime, false).format(&value)
+}
+
+/// Writes the error from a command to stderr, in red if colors are used
+fn print_error(err: &str) {
+    eprintln!("{}", err.red());
+}
+
+/// Writes what the user can do about the error from a command to stderr, if anything
+fn print_hint(err: &DispatchError) {
+    if let DispatchError::Bofh(BofhError::PermissionDenied(_)) = err {
+        eprintln!("You don't have access to this; ask your local IT staff to be granted access if you need it");
+    }
+}
+
+/// Splits a format directive off a command line, either a `format FORMAT` prefix or a `--FORMAT` suffix,
+/// so it isn't sent to the server as an argument
+fn split_format(command: &mut Vec<Token>) -> Result<Option<Format>, String> {
+    let (format, split) = match tokenize::values(command).as_slice() {
+        ["format", format, ..] => {
+            let format = Format::from_str(format, true).map_err(|_| {
+                format!(
+                    "Unknown format '{}', use one of: {}",
+                    format,
+                    Format::value_variants()
+                        .iter()
+                        .filter_map(|format| format.to_possible_value())
+                        .map(|value| value.get_name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })?;
+            (format, 0..2)
+        }
+        ["format"] => return Err(String::from("Usage: format FORMAT COMMAND...")),
+        [.., last] if last.starts_with("--") => match Format::from_str(&last[2..], true) {
+            Ok(format) => (format, command.len() - 1..command.len()),
+            Err(_) => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    command.drain(split);
+    Ok(Some(format))
+}
+
+/// Why a command line couldn't be run
+#[derive(Debug)]
+pub(crate) enum DispatchError {
+    /// The client refused to run the command line, like when the command is unknown
+    Rejected(String),
+    /// The server failed to run the command
+    Bofh(BofhError),
+}
+
+impl fmt::Display for DispatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rejected(message) => write!(f, "{}", message),
+            Self::Bofh(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<DispatchError> for String {
+    fn from(err: DispatchError) -> Self {
+        err.to_string()
+    }
+}
+
+/// Resolves a command line against the command table and runs it on the server.
+///
+/// This is the dispatch path shared by the REPL and the non-interactive modes.
+///
+/// With `--passthrough`, a command line that doesn't resolve is sent to the server as is, with the first word as the full command name.
+pub(crate) fn dispatch(
+    bofh: &Bofh,
+    helper: &BofhHelper,
+    args: &Args,
+    command: &[Token],
+) -> Result<Value, DispatchError> {
+    let words = tokenize::values(command);
+    let subcommand = match helper.resolve(&words) {
+        Ok(subcommand) => subcommand,
+        Err(_) if args.passthrough && !args.read_only && !command.is_empty() => {
+            return bofh
+                .run_command_str_args(words[0], &arguments(&command[1..])?)
+                .map(Response::into_value)
+                .map_err(DispatchError::Bofh);
+        }
+        Err(err) => return Err(DispatchError::Rejected(err)),
+    };
+    check_read_only(args, &words, subcommand).map_err(DispatchError::Rejected)?;
+    bofh.run_command_args(subcommand, &arguments(&command[2..])?)
+        .map(Response::into_value)
+        .map_err(DispatchError::Bofh)
+}
+
+/// The command arguments for the words in a command line that are arguments, see [`tokenize::argument`]
+fn arguments(words: &[Token]) -> Result<Vec<CommandArg>, DispatchError> {
+    words
+        .iter()
+        .map(tokenize::argument)
+        .collect::<Result<_, _>>()
+        .map_err(DispatchError::Rejected)
+}
+
+/// Refuses to run `subcommand` (as typed in `command`) with `--read-only`, unless it (probably) only reads state
+fn check_read_only(
+    args: &Args,
+    command: &[&str],
+    subcommand: &bofh::Command,
+) -> Result<(), String> {
+    if args.read_only && !subcommand.is_read_only() {
+        return Err(format!(
+            "Refusing to run '{} {}' in read-only mode",
+            command[0], command[1]
+        ));
+    }
+    Ok(())
+}
+
+/// Guesses which of a command's arguments caused an error, by looking for an argument that's mentioned in the error message
+fn offending_argument(args: &[&str], error: &str) -> Option<usize> {
+    let error_words: Vec<&str> = error
+        .split(|c: char| c.is_whitespace() || "'\"`,:;()[]".contains(c))
+        .collect();
+    args.iter().rposition(|arg| error_words.contains(arg))
+}
+
+/// Describes what's known about the permissions needed to run a command, for the `perms` command
+fn describe_permissions(helper: &BofhHelper, command: &[&str]) -> String {
+    let subcommand = match helper.resolve(command) {
+        Ok(subcommand) => subcommand,
+        Err(err) => {
+            return format!(
+                "{}\nThe server only lists the commands you're allowed to run, so you probably \
+                 lack the permissions for it, if it exists.",
+                err
+            )
+        }
+    };
+    let permissions = match &subcommand.permissions {
+        Some(permissions) if permissions.is_empty() => String::from("none"),
+        Some(permissions) => permissions.join(", "),
+        None => String::from("unknown, the server doesn't say"),
+    };
+    format!(
+        "Required permissions for '{}': {}\n\
+         The server lists the command as available to you, but it may still refuse particular arguments.\n\
+         The command probably {}.",
+        subcommand.fullname,
+        permissions,
+        if subcommand.is_read_only() {
+            "only reads state"
+        } else {
+            "modifies state"
+        }
+    )
+}
+
+/// Lists the commands in each command group, with their argument prompts, for the `commands` command.
+///
+/// With a (possibly abbreviated) group name, only that group's commands are listed.
+fn list_commands(helper: &BofhHelper, group: Option<&str>) -> Result<String, String> {
+    let groups: Vec<&bofh::CommandGroup> = match group {
+        None => helper.commands.values().collect(),
+        Some(group) => match helper.group_candidates(group).as_slice() {
+            [name] => vec![&helper.commands[*name]],
+            [] => {
+                return Err(format!(
+                    "Unknown command '{}'{}",
+                    group,
+                    helper::did_you_mean(helper.commands.keys().map(String::as_str), group)
+                ))
+            }
+            _ => return Err(format!("Unknown command '{}'", group)),
+        },
+    };
+    Ok(groups
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>()
+        .join("\n"))
+}
+
+/// Splits a trailing output redirection (`> FILE` or `>FILE`) off a command line
+fn split_redirect(command: &mut Vec<Token>) -> Option<String> {
+    let (path, words) = match tokenize::values(command).as_slice() {
+        [.., ">", path] => ((*path).to_owned(), 2),
+        [.., last] if last.len() > 1 && last.starts_with('>') => (last[1..].to_owned(), 1),
+        _ => return None,
+    };
+    command.truncate(command.len() - words);
+    Some(path)
+}
+
 fn main() {
     let args = Args::parse();
+    // Everything that's colored with `colored` follows this, from command results to completion candidates
+    colored::control::set_override(args.color());
+    // Other crates' messages are only interesting when something is wrong
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Warn.min(args.log_level()))
+        .filter_module("bofh", args.log_level())
+        .format_timestamp(None)
+        .init();
+
+    let mut config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let user = qualify_username(
+        &args.user,
+        args.realm.as_deref().or(config.realm.as_deref()),
+    );
+
+    let mut output = match &args.output {
+        Some(path) => match File::create(path) {
+            Ok(file) => Some((path.as_str(), file)),
+            Err(err) => {
+                eprintln!("Failed to open {}: {}", path, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
 
-    println!("Connecting to {}\n", &args.url);
-    let mut bofh = match Bofh::new(args.url) {
+    // With JSON results, stdout is left for the results alone, so it can be piped to other programs
+    let status = |message: &str| match args.format {
+        Format::Json => eprintln!("{}\n", message),
+        _ => println!("{}\n", message),
+    };
+    status(&format!("Connecting to {}", &args.url));
+    let mut builder = BofhBuilder::new(args.url.clone())
+        .timeout(match args.timeout {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds.into())),
+        })
+        // Commands may take a while, but the Message of the Day shouldn't
+        .motd_timeout(Some(MOTD_TIMEOUT))
+        .insecure(args.insecure)
+        .interrupt(interrupt::flag());
+    if let Some(cert) = &args.cert {
+        builder = builder.ca_cert(cert);
+    }
+    let bofh = match builder.clone().connect() {
         Ok(bofh) => bofh,
         Err(err) => {
             eprintln!("{}", err);
@@ -78,26 +755,148 @@ fn main() {
     };
 
     if let Some(motd) = &bofh.motd {
-        println!("{}\n", motd);
+        status(motd);
     }
 
-    let password = match prompt_password(format!("Password for {}: ", &args.user)) {
-        Ok(password) => password,
-        Err(_) => std::process::exit(0), // FIXME errors on windows?
+    let resumed = match resume_session(bofh, &builder, &args, &user) {
+        Ok(resumed) => resumed,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let mut passwords: Box<dyn PasswordSource> =
+        match password::from_args(args.password_file.as_deref(), args.password_stdin) {
+            Ok(passwords) => passwords,
+            // A resumed session only needs a password if it expires
+            Err(err) if resumed.is_ok() => {
+                let message = err.to_string();
+                Box::new(move |_prompt: &str| Err(io::Error::other(message.clone())))
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        };
+    let (mut bofh, resumed) = match resumed {
+        Ok(bofh) => {
+            status(&format!("Resumed the saved session for {}", user));
+            (bofh, true)
+        }
+        Err(bofh) => match authenticate(bofh, &user, passwords.as_mut()) {
+            Ok(Some(bofh)) => (bofh, false),
+            Ok(None) => std::process::exit(0), // FIXME errors on windows?
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        },
     };
+    save_session(&args, &user, &mut bofh);
 
-    let commands = match bofh.login(&args.user, password) {
+    // Some servers are only partially compatible, so we try to stay useful without a command table
+    let commands = if resumed {
+        // The commands were fetched when checking the session
+        Ok(bofh.commands().clone())
+    } else {
+        bofh.get_commands()
+    };
+    let commands = match commands {
         Ok(commands) => commands,
         Err(err) => {
-            eprintln!("{}", err);
-            std::process::exit(1);
+            eprintln!(
+                "Warning: Failed to get the available commands: {}\n\
+                 Tab completion and highlighting are disabled, and server commands can't be run. \
+                 Use 'reload' to try again.",
+                err
+            );
+            BTreeMap::new()
         }
     };
+    let mut helper = BofhHelper::new(commands);
+    helper.normalize_commands = args.normalize_commands;
+    helper.match_mode = args.match_mode;
+    helper.color = args.color();
+
+    if args.dump_commands {
+        for command_group in helper.commands.values() {
+            for command in command_group.commands.values() {
+                println!(
+                    "{}\t{}\t{}",
+                    command_group.name,
+                    command.name,
+                    command.usage()
+                );
+            }
+        }
+        return;
+    }
+
+    if let Some(shell) = args.completions {
+        print!(
+            "{}",
+            completions::generate(shell, &args.url, &helper.commands)
+        );
+        return;
+    }
+
+    if let Some(cmd) = &args.cmd {
+        let mut command = match tokenize::tokenize(cmd) {
+            Ok(command) => command,
+            Err(err) => {
+                eprintln!("{}", err);
+                drop(bofh);
+                std::process::exit(1);
+            }
+        };
+        let format = match split_format(&mut command) {
+            Ok(format) => format.unwrap_or(args.format),
+            Err(err) => {
+                eprintln!("{}", err);
+                drop(bofh);
+                std::process::exit(1);
+            }
+        };
+        let code = if command.is_empty() {
+            0
+        } else {
+            match dispatch(&bofh, &helper, &args, &command) {
+                Ok(ok) => {
+                    write_result(
+                        &ok,
+                        &args,
+                        format,
+                        output.as_mut().map(|(path, file)| (*path, file)),
+                    );
+                    0
+                }
+                Err(err) => {
+                    print_error(&err.to_string());
+                    print_hint(&err);
+                    1
+                }
+            }
+        };
+        // Log out before exiting, since `exit` doesn't run destructors
+        drop(bofh);
+        std::process::exit(code);
+    }
+
+    if let Some(path) = &args.replay {
+        let code = match replay::replay(path, &bofh, &helper, &args, &mut password::Terminal) {
+            Ok(0) => 0,
+            Ok(_) => 1,
+            Err(err) => {
+                eprintln!("{}", err);
+                1
+            }
+        };
+        drop(bofh);
+        std::process::exit(code);
+    }
 
     let mut rl = Editor::new().expect("Failed to connect to terminal/TTY");
-    rl.set_helper(Some(BofhHelper {
-        commands: &commands,
-    }));
+    rl.set_helper(Some(helper));
 
     if args.vi {
         rl.set_edit_mode(rustyline::EditMode::Vi);
@@ -106,50 +905,231 @@ fn main() {
         rl.set_completion_type(rustyline::CompletionType::List);
     }
 
-    if rl.load_history("history.txt").is_err() {
+    rl.set_max_history_size(args.history_size);
+    rl.set_history_ignore_dups(true);
+    let history = args.history_path();
+    if history
+        .as_ref()
+        .is_none_or(|path| rl.load_history(path).is_err())
+    {
         println!("No previous history.");
     }
 
+    let motd_interval = args
+        .motd_interval
+        .map(|minutes| Duration::from_secs(minutes * 60));
+    let mut motd_checked = Instant::now();
+    let mut plan: Option<Plan> = None;
+    // The transcript started by `script`, and the path it's written to
+    let mut script: Option<(String, File)> = None;
+    // Asks before running a plan
+    let mut confirm = |question: &str| {
+        print!("{}", question);
+        let mut answer = String::new();
+        io::stdout().flush().is_ok()
+            && io::stdin().read_line(&mut answer).is_ok()
+            && matches!(answer.trim(), "y" | "Y" | "yes")
+    };
+    // Asks for the arguments that are missing from a command line. The answers aren't command lines, so they're read
+    // with an editor of their own, which doesn't complete or highlight them as commands.
+    let mut arguments = Editor::<()>::new().expect("Failed to connect to terminal/TTY");
+    let mut read = |prompt: &str, secret: bool| {
+        if secret {
+            password::Terminal.password(prompt).ok()
+        } else {
+            arguments.readline(prompt).ok()
+        }
+    };
+    // The recording for `--replay` started by `--record`, and the path it's written to
+    let mut recording = match &args.record {
+        Some(path) => match File::create(path) {
+            Ok(file) => Some((path.clone(), file)),
+            Err(err) => {
+                eprintln!("Failed to open {}: {}", path, err);
+                drop(bofh);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
     loop {
-        match rl.readline(&args.prompt) {
+        // The check only happens between commands, so it won't interrupt the output of one
+        if let Some(interval) = motd_interval {
+            if motd_checked.elapsed() >= interval {
+                motd_checked = Instant::now();
+                match bofh.refresh_motd() {
+                    Ok(true) => println!(
+                        "The Message of the Day has changed:\n{}\n",
+                        bofh.motd.as_deref().unwrap_or_default()
+                    ),
+                    Ok(false) => {}
+                    Err(err) => eprintln!("Failed to check the Message of the Day: {}", err),
+                }
+            }
+        }
+        let state = template::PromptState {
+            user: &user,
+            url: &bofh.url,
+            expired: bofh.session_expired(),
+        };
+        let render = |color| {
+            let prompt = template::render(&args.prompt, &state, color);
+            match &plan {
+                Some(_) => format!("[plan] {}", prompt),
+                None => prompt,
+            }
+        };
+        match rl.readline(&render(args.color())) {
             Ok(line) => {
-                let command: Vec<&str> = line.split_whitespace().collect();
-                if !command.is_empty() {
-                    let candidates = rl.helper().unwrap().command_candidates(command[0]);
-                    if candidates.len() == 1 {
-                        let command_group = commands.get(candidates[0]).unwrap();
-                        if command.len() > 1 {
-                            let candidates = rl
-                                .helper()
-                                .unwrap()
-                                .subcommand_candidates(candidates[0], command[1]);
-                            if candidates.len() == 1 {
-                                let subcommand = command_group.commands.get(candidates[0]).unwrap();
-                                match bofh.run_command(subcommand.fullname.as_str(), &command[2..])
-                                {
-                                    Ok(ok) => println!("{:?}", ok),
-                                    Err(err) => eprintln!("{}", err),
+                transcribe(&mut script, |file| {
+                    writeln!(file, "{}{}", render(false), line)
+                });
+                let mut command = match tokenize::tokenize(&line) {
+                    Ok(command) => command,
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        rl.add_history_entry(&line);
+                        continue;
+                    }
+                };
+                let mut redirect = match split_redirect(&mut command) {
+                    Some(path) => match File::create(&path) {
+                        Ok(file) => Some((path, file)),
+                        Err(err) => {
+                            eprintln!("Failed to open {}: {}", path, err);
+                            rl.add_history_entry(&line);
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                // A format prefix shifts the arguments' positions in the line, which matters for highlighting them
+                let shift = if command.first().map(|word| word.value.as_str()) == Some("format") {
+                    2
+                } else {
+                    0
+                };
+                let format = match split_format(&mut command) {
+                    Ok(format) => format.unwrap_or(args.format),
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        rl.add_history_entry(&line);
+                        continue;
+                    }
+                };
+                let outcome = internal::run_line(
+                    &mut internal::Context {
+                        bofh: &mut bofh,
+                        helper: rl.helper_mut().unwrap(),
+                        args: &args,
+                        config: &mut config,
+                        plan: &mut plan,
+                        script: &mut script,
+                        confirm: &mut confirm,
+                        read: &mut read,
+                        user: &user,
+                        passwords: passwords.as_mut(),
+                        sourcing: 0,
+                    },
+                    &command,
+                );
+                let record = match outcome {
+                    internal::Line::Handled { record } => record,
+                    internal::Line::Quit => {
+                        rl.add_history_entry(&line);
+                        break;
+                    }
+                    internal::Line::Logout => {
+                        rl.add_history_entry(&line);
+                        // An expired session is already gone on the server, so failing to log it out doesn't matter
+                        let authenticated = bofh.is_authenticated();
+                        let (unauthenticated, result) = bofh.logout();
+                        match result {
+                            Err(err) if authenticated => eprintln!("Failed to log out: {}", err),
+                            _ => (),
+                        }
+                        println!("Logged out");
+                        forget_session(&args, &user);
+                        bofh = match log_in_again(
+                            Some(unauthenticated),
+                            &builder,
+                            &user,
+                            passwords.as_mut(),
+                        ) {
+                            Some(bofh) => bofh,
+                            None => break,
+                        };
+                        save_session(&args, &user, &mut bofh);
+                        if let Err(err) = bofh.init_commands(false) {
+                            eprintln!("Warning: Failed to get the available commands: {}", err);
+                        }
+                        rl.helper_mut().unwrap().commands = bofh.commands().clone();
+                        continue;
+                    }
+                    internal::Line::Refused(err) => {
+                        eprintln!("{}", err);
+                        rl.add_history_entry(&line);
+                        continue;
+                    }
+                    internal::Line::Ran {
+                        command: full,
+                        result,
+                    } => {
+                        match result {
+                            Ok(ok) => {
+                                write_result(
+                                    &ok,
+                                    &args,
+                                    format,
+                                    match redirect.as_mut() {
+                                        Some((path, file)) => Some((path.as_str(), file)),
+                                        None => output.as_mut().map(|(path, file)| (*path, file)),
+                                    },
+                                );
+                                transcribe(&mut script, |file| {
+                                    format
+                                        .formatter(args.datetime, false)
+                                        .write_value(&ok, file)
+                                });
+                                let output = render_result(&ok, &args, args.format);
+                                transcribe(&mut recording, |file| {
+                                    writeln!(
+                                        file,
+                                        "{}",
+                                        replay::record(rl.helper().unwrap(), &full, &output)
+                                    )
+                                });
+                            }
+                            Err(err) => {
+                                let message = err.to_string();
+                                if let Some(index) = offending_argument(
+                                    tokenize::values(&command).get(2..).unwrap_or_default(),
+                                    &message,
+                                ) {
+                                    rl.helper()
+                                        .unwrap()
+                                        .set_argument_error(&line, index + shift);
                                 }
-                            } else {
-                                eprintln!("Unknown command '{} {}'", command[0], command[1]);
+                                print_error(&message);
+                                print_hint(&err);
+                                transcribe(&mut script, |file| writeln!(file, "{}", message));
+                                transcribe(&mut recording, |file| {
+                                    writeln!(
+                                        file,
+                                        "{}",
+                                        replay::record(rl.helper().unwrap(), &full, &message)
+                                    )
+                                });
                             }
-                        } else {
-                            eprintln!(
-                                "Incomplete command '{}', possible subcommands:\n{}",
-                                command_group.name,
-                                command_group
-                                    .commands
-                                    .keys()
-                                    .cloned()
-                                    .collect::<Vec<String>>()
-                                    .join(", "),
-                            );
                         }
-                    } else {
-                        eprintln!("Unknown command '{}'", command[0]);
+                        true
                     }
+                };
+                // Blank lines aren't worth going back to in the history
+                if record && !line.trim().is_empty() {
+                    rl.add_history_entry(&line);
                 }
-                rl.add_history_entry(&line);
             }
             Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
                 break;
@@ -161,5 +1141,238 @@ fn main() {
         }
     }
     println!("So long, and thanks for all the fish!");
-    rl.append_history("history.txt").unwrap();
+    if let Err(err) = save_history(&mut rl, history.as_deref()) {
+        eprintln!("Warning: the history wasn't saved: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bofh::transport::MockTransport;
+    use std::sync::Arc;
+
+    #[test]
+    fn read_only() {
+        let command = |name: &str| bofh::Command {
+            fullname: format!("group_{}", name),
+            name: name.to_owned(),
+            args: vec![],
+            format_suggestion: None,
+            help: None,
+            read_only: None,
+            permissions: None,
+            prompt_func: false,
+        };
+        let read_only = Args::parse_from(["bofh", "--read-only"]);
+        assert_eq!(
+            check_read_only(&read_only, &["group", "add"], &command("add")),
+            Err(String::from(
+                "Refusing to run 'group add' in read-only mode"
+            ))
+        );
+        assert!(check_read_only(&read_only, &["group", "info"], &command("info")).is_ok());
+        let args = Args::parse_from(["bofh"]);
+        assert!(check_read_only(&args, &["group", "add"], &command("add")).is_ok());
+    }
+
+    #[test]
+    fn dispatch_groups() {
+        let user_info = Value::Array(vec![
+            Value::Array(vec![Value::from("user"), Value::from("info")]),
+            Value::Array(vec![Value::Struct(
+                [(String::from("type"), Value::from("accountName"))]
+                    .into_iter()
+                    .collect(),
+            )]),
+        ]);
+        let transport = Arc::new(
+            MockTransport::new()
+                .respond("get_motd", "Welcome")
+                .respond("login", "session")
+                .respond(
+                    "get_commands",
+                    Value::Struct(
+                        [(String::from("user_info"), user_info)]
+                            .into_iter()
+                            .collect(),
+                    ),
+                )
+                .respond("run_command", "Account: olanor")
+                .respond("logout", Value::Nil),
+        );
+        let bofh = BofhBuilder::new(String::from("https://bofhd.example.com:8000"))
+            .transport(Arc::clone(&transport) as _)
+            .connect()
+            .unwrap()
+            .login("olanor", String::from("secret"))
+            .unwrap();
+        let helper = BofhHelper::new(bofh.commands().clone());
+        let args = Args::parse_from(["bofh", "--passthrough"]);
+        let sent = |line: &str| {
+            let command = tokenize::tokenize(line).unwrap();
+            dispatch(&bofh, &helper, &args, &command).unwrap();
+            transport.calls().pop().unwrap().1[1..].to_vec()
+        };
+        let group = Value::Array(vec![Value::from("a"), Value::from("b")]);
+        // Groups are sent the same way whether the command resolves or is passed through
+        assert_eq!(
+            sent("user info (a b)"),
+            [Value::from("user_info"), group.clone()]
+        );
+        assert_eq!(sent("user_info (a b)"), [Value::from("user_info"), group]);
+        assert_eq!(
+            sent("user info '(a b)'"),
+            [Value::from("user_info"), Value::from("(a b)")]
+        );
+        let broken = Token {
+            quoted: false,
+            ..Token::literal("(a 'b)")
+        };
+        for command in [
+            tokenize::tokenize("user info").unwrap(),
+            tokenize::tokenize("user_info").unwrap(),
+        ] {
+            let command = [command, vec![broken.clone()]].concat();
+            assert!(matches!(
+                dispatch(&bofh, &helper, &args, &command),
+                Err(DispatchError::Rejected(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn log_in_again_when_expired() {
+        let user_info = Value::Array(vec![
+            Value::Array(vec![Value::from("user"), Value::from("info")]),
+            Value::Array(vec![]),
+        ]);
+        let transport = Arc::new(
+            MockTransport::new()
+                .respond("get_motd", "Welcome")
+                .respond("login", "session")
+                .respond(
+                    "get_commands",
+                    Value::Struct(
+                        [(String::from("user_info"), user_info)]
+                            .into_iter()
+                            .collect(),
+                    ),
+                )
+                .respond("run_command", "Account: olanor")
+                .fault_once(
+                    "run_command",
+                    "Cerebrum.modules.bofhd.errors.SessionExpiredError:Session expired",
+                )
+                .respond("logout", Value::Nil),
+        );
+        let unauthenticated = || {
+            BofhBuilder::new(String::from("https://bofhd.example.com:8000"))
+                .transport(Arc::clone(&transport) as _)
+                .connect()
+                .unwrap()
+        };
+        let mut prompts = vec![];
+        let mut passwords = vec!["second", "first"];
+        let mut script = |prompt: &str| {
+            prompts.push(prompt.to_owned());
+            passwords
+                .pop()
+                .map(String::from)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+        };
+
+        let mut bofh = authenticate(unauthenticated(), "olanor", &mut script)
+            .unwrap()
+            .unwrap();
+        let request = match bofh.run_command_str("user_info", &[]) {
+            Err(BofhError::SessionExpiredError(request)) => request,
+            result => panic!("unexpected result {:?}", result),
+        };
+        assert!(bofh.session_expired());
+        assert_eq!(
+            retry_expired(&mut bofh, "olanor", request.clone(), &mut script)
+                .unwrap()
+                .as_message(),
+            Some("Account: olanor")
+        );
+        assert!(!bofh.session_expired());
+        // Once the script runs out of passwords, the session stays expired
+        assert!(matches!(
+            retry_expired(&mut bofh, "olanor", request, &mut script),
+            Err(BofhError::SessionExpiredError(_))
+        ));
+        assert!(authenticate(unauthenticated(), "olanor", &mut script)
+            .unwrap()
+            .is_none());
+
+        assert_eq!(prompts.len(), 4);
+        assert!(prompts
+            .iter()
+            .all(|prompt| prompt == "Password for olanor: "));
+        let logins: Vec<Vec<Value>> = transport
+            .calls()
+            .into_iter()
+            .filter(|(method, _)| method == "login")
+            .map(|(_, args)| args)
+            .collect();
+        assert_eq!(
+            logins,
+            [
+                vec![Value::from("olanor"), Value::from("first")],
+                vec![Value::from("olanor"), Value::from("second")],
+            ]
+        );
+    }
+
+    #[test]
+    fn log_in_again_with_wrong_password() {
+        let rejected = "Cerebrum.modules.bofhd.errors.CerebrumError:Unknown username or password";
+        let server = || {
+            MockTransport::new()
+                .respond("get_motd", "Welcome")
+                .respond("get_commands", Value::Struct(BTreeMap::new()))
+                .respond("logout", Value::Nil)
+        };
+        let builder = |transport: &Arc<MockTransport>| {
+            BofhBuilder::new(String::from("https://bofhd.example.com:8000"))
+                .transport(Arc::clone(transport) as _)
+        };
+        let logins = |transport: &MockTransport| {
+            transport
+                .calls()
+                .iter()
+                .filter(|(method, _)| method == "login")
+                .count()
+        };
+
+        // A password from a file is only sent once, since asking for it again gives the same one
+        let transport = Arc::new(server().fault("login", rejected));
+        let mut fixed = password::Fixed(String::from("wrong"));
+        assert!(log_in_again(None, &builder(&transport), "olanor", &mut fixed).is_none());
+        assert_eq!(logins(&transport), 1);
+
+        // The user is asked again, until they get it right
+        let transport = Arc::new(
+            server()
+                .respond("login", "session")
+                .fault_once("login", rejected)
+                .fault_once("login", rejected),
+        );
+        let mut passwords = vec!["secret", "wrong", "wrong"];
+        let mut script = |_: &str| {
+            passwords
+                .pop()
+                .map(String::from)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+        };
+        assert!(log_in_again(None, &builder(&transport), "olanor", &mut script).is_some());
+        assert_eq!(logins(&transport), 3);
+
+        // But only so many times
+        let transport = Arc::new(server().fault("login", rejected));
+        let mut script = |_: &str| Ok(String::from("wrong"));
+        assert!(log_in_again(None, &builder(&transport), "olanor", &mut script).is_none());
+        assert_eq!(logins(&transport), LOGIN_ATTEMPTS);
+    }
 }
@@ -0,0 +1,50 @@
+//! Paging command results that don't fit in the terminal.
+//!
+//! Results are piped through the pager in `$PAGER`, or `less -R` (which keeps colors) if it isn't set.
+//! Nothing is paged unless stdout is a terminal, so results piped to other programs are written as they are.
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::process::{Command, Stdio};
+use terminal_size::{terminal_size, Height};
+
+/// The pager to use when `$PAGER` isn't set
+const DEFAULT_PAGER: &str = "less -R";
+
+/// Shows `text` in the pager if stdout is a terminal and `text` is taller than it.
+///
+/// Returns whether `text` was shown; if not, it's up to the caller to write it.
+pub(crate) fn page(text: &str) -> bool {
+    if !io::stdout().is_terminal() {
+        return false;
+    }
+    match terminal_size() {
+        // Leave a line for the prompt
+        Some((_, Height(height))) if text.lines().count() >= usize::from(height) => {}
+        _ => return false,
+    }
+    let pager = env::var("PAGER")
+        .ok()
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| String::from(DEFAULT_PAGER));
+    let mut words = pager.split_whitespace();
+    let program = words.next().unwrap_or_default();
+    let mut child = match Command::new(program)
+        .args(words)
+        .stdin(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => {
+            eprintln!("Failed to start the pager '{}': {}", pager, err);
+            return false;
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager may be quit before it has read everything, which is fine
+        let _ = stdin.write_all(text.as_bytes());
+    }
+    if let Err(err) = child.wait() {
+        eprintln!("Failed to wait for the pager '{}': {}", pager, err);
+    }
+    true
+}
@@ -0,0 +1,123 @@
+//! Where passwords (and other secrets) come from.
+//!
+//! The client asks a [`PasswordSource`] instead of reading the terminal directly, so the login flow
+//! can be driven by something other than a user at a terminal.
+use std::fs;
+use std::io::{self, BufRead, IsTerminal};
+
+/// The environment variable that can hold the password, see [`from_args`]
+pub(crate) const PASSWORD_VARIABLE: &str = "BOFH_PASSWORD";
+
+/// Something that can provide a password when prompted for one
+pub(crate) trait PasswordSource {
+    /// Gets a password, showing `prompt` if that makes sense for the source
+    fn password(&mut self, prompt: &str) -> io::Result<String>;
+
+    /// Whether asking again can give a different password, so a rejected one is worth asking for again
+    fn can_retry(&self) -> bool {
+        false
+    }
+}
+
+/// Prompts the user on the terminal, without echoing the password
+#[derive(Debug, Default)]
+pub(crate) struct Terminal;
+
+impl PasswordSource for Terminal {
+    fn password(&mut self, prompt: &str) -> io::Result<String> {
+        rpassword::prompt_password(prompt)
+    }
+
+    fn can_retry(&self) -> bool {
+        true
+    }
+}
+
+/// Any closure can be a password source, which is handy for tests
+impl<F: FnMut(&str) -> io::Result<String>> PasswordSource for F {
+    fn password(&mut self, prompt: &str) -> io::Result<String> {
+        self(prompt)
+    }
+
+    fn can_retry(&self) -> bool {
+        true
+    }
+}
+
+/// A password that was read once, like from a file, and is given whenever one is asked for
+#[derive(Debug)]
+pub(crate) struct Fixed(pub(crate) String);
+
+impl PasswordSource for Fixed {
+    fn password(&mut self, _prompt: &str) -> io::Result<String> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Picks where the password comes from. The first of these that is given wins:
+///
+/// 1. The file at `file` (`--password-file`)
+/// 2. The first line of stdin, if `stdin` is set (`--password-stdin`)
+/// 3. The [`PASSWORD_VARIABLE`] environment variable
+/// 4. A prompt on the terminal, if stdin is one
+///
+/// Only the first line of a file or stdin is read, and its line ending (`\n` or `\r\n`) is not part of the password.
+pub(crate) fn from_args(file: Option<&str>, stdin: bool) -> io::Result<Box<dyn PasswordSource>> {
+    if let Some(file) = file {
+        let password = fs::read_to_string(file).map_err(|err| {
+            io::Error::new(err.kind(), format!("Failed to read {}: {}", file, err))
+        })?;
+        Ok(Box::new(Fixed(first_line(&password))))
+    } else if stdin {
+        let mut password = String::new();
+        io::stdin().lock().read_line(&mut password)?;
+        Ok(Box::new(Fixed(first_line(&password))))
+    } else if let Ok(password) = std::env::var(PASSWORD_VARIABLE) {
+        Ok(Box::new(Fixed(password)))
+    } else if io::stdin().is_terminal() {
+        Ok(Box::new(Terminal))
+    } else {
+        Err(io::Error::other(format!(
+            "No password given, and stdin isn't a terminal to prompt for one. \
+             Use --password-file, --password-stdin or {}",
+            PASSWORD_VARIABLE
+        )))
+    }
+}
+
+/// The first line of `text`, without its line ending (`\n` or `\r\n`)
+fn first_line(text: &str) -> String {
+    text.lines()
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches('\r')
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_lines() {
+        assert_eq!(first_line("hunter2"), "hunter2");
+        assert_eq!(first_line("hunter2\n"), "hunter2");
+        assert_eq!(first_line("hunter2\r\n"), "hunter2");
+        assert_eq!(first_line("hunter2\r\n# for olanor\r\n"), "hunter2");
+        assert_eq!(first_line(" hunter2 \n\n"), " hunter2 ");
+        assert_eq!(first_line(""), "");
+    }
+
+    #[test]
+    fn password_file() {
+        let path = std::env::temp_dir().join(format!("bofh-password-{}", std::process::id()));
+        fs::write(&path, "hunter2\r\n# for olanor\n").unwrap();
+        let source = from_args(path.to_str(), false);
+        fs::remove_file(&path).unwrap();
+        let mut source = source.unwrap();
+        assert_eq!(source.password("Password: ").unwrap(), "hunter2");
+        // The file is only read once, so asking again gives the same password
+        assert!(!source.can_retry());
+        assert!(from_args(path.to_str(), false).is_err());
+    }
+}
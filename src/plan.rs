@@ -0,0 +1,81 @@
+//! Client-side plans for bulk operations.
+//!
+//! bofhd has no transactions, so a plan is just a list of command lines that are collected,
+//! shown for confirmation, and then run in sequence, stopping at the first failure.
+//!
+//! Note that this is *not* atomic: commands that were run before a failure have taken effect,
+//! and are not rolled back. When a plan stops, it reports exactly which commands were run and
+//! which were not, so the operator can reconcile manually.
+use crate::helper::BofhHelper;
+use crate::tokenize;
+use crate::Args;
+use bofh::Bofh;
+
+/// A list of command lines that will be run in sequence
+#[derive(Debug, Default)]
+pub(crate) struct Plan {
+    pub(crate) commands: Vec<String>,
+}
+
+impl Plan {
+    /// Adds a command line to the plan, if it resolves to a known command
+    pub(crate) fn add(
+        &mut self,
+        helper: &BofhHelper,
+        args: &Args,
+        line: &str,
+    ) -> Result<(), String> {
+        let command = tokenize::tokenize(line)?;
+        if let Err(err) = helper.resolve(&tokenize::values(&command)) {
+            if !args.passthrough || args.read_only {
+                return Err(err);
+            }
+        }
+        self.commands.push(tokenize::join(&command));
+        Ok(())
+    }
+
+    /// Prints the planned commands, numbered from one
+    pub(crate) fn show(&self) {
+        if self.commands.is_empty() {
+            println!("The plan is empty");
+        }
+        for (number, command) in self.commands.iter().enumerate() {
+            println!("{:>4}  {}", number + 1, command);
+        }
+    }
+
+    /// Runs the planned commands in sequence, stopping at the first one that fails.
+    ///
+    /// On failure, the returned message tells which commands were run (and not rolled back) and which were not.
+    pub(crate) fn run(&self, bofh: &Bofh, helper: &BofhHelper, args: &Args) -> Result<(), String> {
+        let total = self.commands.len();
+        for (index, line) in self.commands.iter().enumerate() {
+            // The lines were joined from words when they were added, so they split back into them
+            let command = tokenize::tokenize(line).unwrap_or_default();
+            println!("[{}/{}] {}", index + 1, total, line);
+            match crate::dispatch(bofh, helper, args, &command) {
+                Ok(ok) => crate::write_result(&ok, args, args.format, None),
+                Err(err) => {
+                    let mut message = format!(
+                        "Stopped at command {} of {}: {}\n{}\n\n",
+                        index + 1,
+                        total,
+                        line,
+                        err
+                    );
+                    message += "Commands run, and NOT rolled back:";
+                    for (number, line) in self.commands.iter().enumerate().take(index) {
+                        message += &format!("\n{:>4}  {}", number + 1, line);
+                    }
+                    message += "\nCommands not run:";
+                    for (number, line) in self.commands.iter().enumerate().skip(index) {
+                        message += &format!("\n{:>4}  {}", number + 1, line);
+                    }
+                    return Err(message);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+//! Prompting for the arguments that are missing from a command line, like the classic bofh clients do.
+//!
+//! Commands with an argument list are only prompted for when required arguments are missing. Commands whose arguments
+//! are decided by the server (with `call_prompt_func`) are prompted for until the server says the last argument is given.
+use bofh::format::value_to_string;
+use bofh::{ArgType, Bofh, Command};
+
+/// Prompts for the arguments that are missing from `command` after the `given` ones, and returns them.
+///
+/// `read` shows a prompt and reads a line, hiding the input if the second argument is set, or returns `None` if the user cancels.
+pub(crate) fn missing_arguments(
+    bofh: &Bofh,
+    command: &Command,
+    given: &[&str],
+    read: &mut dyn FnMut(&str, bool) -> Option<String>,
+) -> Result<Vec<String>, String> {
+    let mut args: Vec<String> = given.iter().map(|&arg| arg.to_owned()).collect();
+    match command.arity() {
+        None => prompt_func(bofh, command, &mut args, read)?,
+        Some((required, _)) if given.len() < required => {
+            for (index, arg) in command.args.iter().enumerate().skip(given.len()) {
+                let so_far: Vec<&str> = args.iter().map(String::as_str).collect();
+                // A server that can't come up with a default shouldn't stop the user from typing the argument
+                let default = match &arg.default {
+                    Some(default) => Some(default.clone()),
+                    None if arg.server_default => bofh
+                        .get_default_param(command, &so_far)
+                        .unwrap_or_else(|err| {
+                            log::info!("No default for {}: {}", command.fullname, err);
+                            None
+                        }),
+                    None => None,
+                };
+                let label = arg
+                    .prompt
+                    .clone()
+                    .or_else(|| arg.arg_type.as_ref().map(ToString::to_string))
+                    .unwrap_or_else(|| format!("Argument {}", index + 1));
+                let prompt = match &default {
+                    Some(default) => format!("{} [{}]: ", label, default),
+                    None => format!("{}: ", label),
+                };
+                let rest_optional = command.args[index + 1..].iter().all(|arg| arg.optional);
+                let secret = arg.arg_type == Some(ArgType::AccountPassword);
+                let value = loop {
+                    let input = read(&prompt, secret).ok_or("Cancelled")?;
+                    match (input.trim(), &default) {
+                        ("", Some(default)) => break Some(default.clone()),
+                        ("", None) if arg.optional && rest_optional => break None,
+                        ("", None) => eprintln!("{} is required", label),
+                        (input, _) => break Some(input.to_owned()),
+                    }
+                };
+                match value {
+                    Some(value) => args.push(value),
+                    None => break,
+                }
+            }
+        }
+        Some(_) => {}
+    }
+    Ok(args.split_off(given.len()))
+}
+
+/// Prompts for the arguments of a command whose arguments are decided by the server, one at a time, until the server says
+/// the last argument is given. Arguments that are already given are used as they are.
+fn prompt_func(
+    bofh: &Bofh,
+    command: &Command,
+    args: &mut Vec<String>,
+    read: &mut dyn FnMut(&str, bool) -> Option<String>,
+) -> Result<(), String> {
+    for index in 0.. {
+        let so_far: Vec<&str> = args[..index].iter().map(String::as_str).collect();
+        let result = bofh
+            .call_prompt_func(command, &so_far)
+            .map_err(|err| err.to_string())?;
+        if index >= args.len() {
+            let rows = result.map.as_ref().map(|map| {
+                for header in &map.headers {
+                    println!("{}", header);
+                }
+                for (number, row) in map.rows.iter().enumerate() {
+                    println!("{:>4}  {}", number + 1, row.display);
+                }
+                &map.rows
+            });
+            let prompt = match &result.default {
+                Some(default) => format!("{} [{}]: ", result.prompt, default),
+                None => format!("{}: ", result.prompt),
+            };
+            let value = loop {
+                let input = read(&prompt, false).ok_or("Cancelled")?;
+                let input = input.trim();
+                match (input, &result.default, rows) {
+                    ("", Some(default), _) => break default.clone(),
+                    ("", None, _) => eprintln!("{} is required", result.prompt),
+                    // Rows in the map are selected by number, unless the input should be passed as is
+                    (input, _, Some(rows)) if !result.raw => {
+                        match input
+                            .parse::<usize>()
+                            .ok()
+                            .and_then(|number| rows.get(number.checked_sub(1)?))
+                        {
+                            Some(row) => break value_to_string(&row.value),
+                            None => eprintln!("Select one of the numbers above"),
+                        }
+                    }
+                    (input, _, _) => break input.to_owned(),
+                }
+            };
+            args.push(value);
+        }
+        if result.last_arg {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bofh::transport::MockTransport;
+    use bofh::{BofhBuilder, Value};
+    use std::sync::Arc;
+
+    #[test]
+    fn server_defaults() {
+        let arg = |arg_type: &str, default: Value| {
+            Value::Struct(
+                [
+                    (String::from("type"), Value::from(arg_type)),
+                    (String::from("default"), default),
+                ]
+                .into_iter()
+                .collect(),
+            )
+        };
+        let user_create = Value::Array(vec![
+            Value::Array(vec![Value::from("user"), Value::from("create")]),
+            Value::Array(vec![
+                arg("accountName", Value::Nil),
+                arg("personId", Value::Int(1)),
+            ]),
+        ]);
+        let transport = Arc::new(
+            MockTransport::new()
+                .respond("get_motd", "Welcome")
+                .respond("login", "session")
+                .respond(
+                    "get_commands",
+                    Value::Struct(
+                        [(String::from("user_create"), user_create)]
+                            .into_iter()
+                            .collect(),
+                    ),
+                )
+                .fault(
+                    "get_default_param",
+                    "Cerebrum.modules.bofhd.errors.CerebrumError:No default",
+                )
+                .respond("logout", Value::Nil),
+        );
+        let bofh = BofhBuilder::new(String::from("https://bofhd.example.com:8000"))
+            .transport(Arc::clone(&transport) as _)
+            .connect()
+            .unwrap()
+            .login("olanor", String::from("secret"))
+            .unwrap();
+        let command = bofh.command("user", "create").unwrap();
+        let mut prompts = vec![];
+        let args = missing_arguments(&bofh, &command, &[], &mut |prompt, _| {
+            prompts.push(prompt.to_owned());
+            Some(String::from("olanor"))
+        });
+        assert_eq!(
+            args,
+            Ok(vec![String::from("olanor"), String::from("olanor")])
+        );
+        // The failing default is left out, rather than failing the command
+        assert_eq!(prompts, ["accountName: ", "personId: "]);
+        let defaults: Vec<Vec<Value>> = transport
+            .calls()
+            .into_iter()
+            .filter(|(method, _)| method == "get_default_param")
+            .map(|(_, args)| args)
+            .collect();
+        assert_eq!(
+            defaults,
+            [vec![
+                Value::from("session"),
+                Value::from("user_create"),
+                Value::from("olanor"),
+            ]]
+        );
+    }
+}
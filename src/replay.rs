@@ -0,0 +1,252 @@
+//! Replaying recorded sessions against the bofhd server.
+//!
+//! A recording is a JSONL file with one object per command, for example:
+//!
+//! ```text
+//! {"command": "user info olanor", "output": "..."}
+//! {"command": "user password olanor <redacted>"}
+//! ```
+//!
+//! Recordings are written by the REPL with `--record`, see [`record`].
+//!
+//! Each command is run again through the same dispatch path as the REPL, and its output is
+//! compared to the recorded `output` (if any), as it's written with `--format`, ignoring trailing
+//! whitespace. Arguments that were redacted in the recording are prompted for at replay time.
+use crate::helper::BofhHelper;
+use crate::password::PasswordSource;
+use crate::tokenize::{self, Token};
+use crate::Args;
+use bofh::{ArgType, Bofh};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::thread;
+
+/// Placeholder for arguments that were redacted from a recording
+pub(crate) const REDACTED: &str = "<redacted>";
+
+/// A recorded command and the output it produced
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Record {
+    pub(crate) command: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) output: Option<String>,
+}
+
+/// The line that records running `command` with `output` (a result as rendered by `render_result`, or an error message).
+///
+/// Arguments that are passwords are replaced with [`REDACTED`], and so is every argument of a command that can't be resolved,
+/// since there's no telling which of them are passwords.
+pub(crate) fn record(helper: &BofhHelper, command: &[Token], output: &str) -> String {
+    let subcommand = helper.resolve(&tokenize::values(command)).ok();
+    let words: Vec<Token> = command
+        .iter()
+        .enumerate()
+        .map(|(index, word)| match (index, subcommand) {
+            (0 | 1, Some(_)) => word.clone(),
+            (index, Some(subcommand))
+                if subcommand
+                    .remaining_args(index - 2)
+                    .first()
+                    .is_some_and(|arg| arg.arg_type != Some(ArgType::AccountPassword)) =>
+            {
+                word.clone()
+            }
+            _ => Token::literal(REDACTED),
+        })
+        .collect();
+    let record = Record {
+        command: tokenize::join(&words),
+        output: Some(output.to_owned()),
+    };
+    serde_json::to_string(&record).expect("a record is always valid JSON")
+}
+
+/// Replays the recording at `path`, returning the number of commands whose output differed.
+///
+/// Waits between each command as configured by `--rate`/`--delay`, to avoid overloading the server.
+pub(crate) fn replay(
+    path: &str,
+    bofh: &Bofh,
+    helper: &BofhHelper,
+    args: &Args,
+    passwords: &mut dyn PasswordSource,
+) -> Result<usize, String> {
+    let delay = args.command_delay();
+    let file = File::open(path).map_err(|err| format!("Failed to open {}: {}", path, err))?;
+    let mut commands = 0;
+    let mut differences = 0;
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| format!("Failed to read {}: {}", path, err))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line)
+            .map_err(|err| format!("{}:{}: invalid record: {}", path, number + 1, err))?;
+
+        let mut command = tokenize::tokenize(&record.command)
+            .map_err(|err| format!("{}:{}: {}", path, number + 1, err))?;
+        for (position, word) in command.iter_mut().enumerate() {
+            if word.value == REDACTED {
+                let prompt = format!("Redacted argument {} of '{}': ", position, record.command);
+                *word = Token::literal(
+                    passwords
+                        .password(&prompt)
+                        .map_err(|err| format!("Failed to read: {}", err))?,
+                );
+            }
+        }
+        if command.is_empty() {
+            continue;
+        }
+
+        if commands > 0 && !delay.is_zero() {
+            thread::sleep(delay);
+        }
+        commands += 1;
+        let output = match crate::dispatch(bofh, helper, args, &command) {
+            Ok(ok) => crate::render_result(&ok, args, args.format),
+            Err(err) => err.to_string(),
+        };
+        if let Some(expected) = &record.output {
+            if expected.trim_end() != output.trim_end() {
+                differences += 1;
+                println!("{}:{}: {}", path, number + 1, record.command);
+                println!("- {}", expected);
+                println!("+ {}", output);
+            }
+        }
+    }
+    println!(
+        "Replayed {} commands, {} with differing output",
+        commands, differences
+    );
+    Ok(differences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bofh::transport::MockTransport;
+    use bofh::{BofhBuilder, Value};
+    use clap::Parser;
+    use std::sync::Arc;
+
+    /// The words of a command line
+    fn line(line: &str) -> Vec<Token> {
+        tokenize::tokenize(line).unwrap()
+    }
+
+    /// An argument of type `arg_type`, as bofhd specifies it
+    fn spec(arg_type: &str) -> Value {
+        Value::Struct(
+            [(String::from("type"), Value::from(arg_type))]
+                .into_iter()
+                .collect(),
+        )
+    }
+
+    fn connect(transport: &Arc<MockTransport>) -> Bofh {
+        BofhBuilder::new(String::from("https://bofhd.example.com:8000"))
+            .transport(Arc::clone(transport) as _)
+            .connect()
+            .unwrap()
+            .login("olanor", String::from("secret"))
+            .unwrap()
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let command = |name: &str, args: Vec<Value>| {
+            Value::Array(vec![
+                Value::Array(vec![Value::from("user"), Value::from(name)]),
+                Value::Array(args),
+            ])
+        };
+        let transport = Arc::new(
+            MockTransport::new()
+                .respond("get_motd", "Welcome")
+                .respond("login", "session")
+                .respond(
+                    "get_commands",
+                    Value::Struct(
+                        [
+                            (
+                                String::from("user_info"),
+                                command("info", vec![spec("accountName")]),
+                            ),
+                            (
+                                String::from("user_password"),
+                                command(
+                                    "password",
+                                    vec![spec("accountName"), spec("accountPassword")],
+                                ),
+                            ),
+                        ]
+                        .into_iter()
+                        .collect(),
+                    ),
+                )
+                .respond("run_command", "Account: olanor")
+                .respond("logout", Value::Nil),
+        );
+        let bofh = connect(&transport);
+        let helper = BofhHelper::new(bofh.commands().clone());
+        let args = Args::parse_from(["bofh", "--format", "plain"]);
+
+        let output = crate::render_result(&Value::from("Account: olanor"), &args, args.format);
+        assert_eq!(output, "Account: olanor\n");
+        let info = record(&helper, &line("user info olanor"), &output);
+        assert_eq!(
+            info,
+            r#"{"command":"user info olanor","output":"Account: olanor\n"}"#
+        );
+        let password = record(&helper, &line("user password olanor hunter2"), &output);
+        assert!(password.starts_with(r#"{"command":"user password olanor <redacted>""#));
+        assert!(!record(&helper, &line("user_info olanor"), "").contains("olanor"));
+
+        let path = std::env::temp_dir().join(format!("bofh-replay-{}.jsonl", std::process::id()));
+        std::fs::write(
+            &path,
+            [
+                info.as_str(),
+                password.as_str(),
+                r#"{"command": "user info karinor", "output": "Account: karinor"}"#,
+                r#"{"command": "user info olanor"}"#,
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+        let mut prompts = vec![];
+        let differences = replay(
+            path.to_str().unwrap(),
+            &bofh,
+            &helper,
+            &args,
+            &mut |prompt: &str| {
+                prompts.push(prompt.to_owned());
+                Ok(String::from("hunter2"))
+            },
+        );
+        std::fs::remove_file(&path).unwrap();
+        // Only the recorded output for karinor differs
+        assert_eq!(differences, Ok(1));
+        assert_eq!(prompts.len(), 1);
+        let run_commands: Vec<Vec<Value>> = transport
+            .calls()
+            .into_iter()
+            .filter(|(method, _)| method == "run_command")
+            .map(|(_, args)| args)
+            .collect();
+        assert_eq!(run_commands.len(), 4);
+        assert_eq!(
+            run_commands[1],
+            [
+                Value::from("session"),
+                Value::from("user_password"),
+                Value::from("olanor"),
+                Value::from("hunter2"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! Saved sessions, `bofh/sessions.toml` in the platform's data directory, for `--save-session`.
+//!
+//! A session identifier lets anyone act as the user until it expires, so the file is only readable by the user (on Unix).
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// The saved session identifiers, by server URL and username
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(transparent)]
+pub(crate) struct Sessions(BTreeMap<String, BTreeMap<String, String>>);
+
+impl Sessions {
+    pub(crate) fn path() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("bofh").join("sessions.toml"))
+    }
+
+    /// Loads the saved sessions, or none if there is no file
+    pub(crate) fn load() -> Result<Self, String> {
+        let path = match Self::path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(Self::default()),
+        };
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        toml::from_str(&contents).map_err(|err| format!("Invalid {}: {}", path.display(), err))
+    }
+
+    /// Saves the sessions, creating the directory if necessary
+    pub(crate) fn save(&self) -> Result<(), String> {
+        let path = Self::path().ok_or("Failed to find a data directory")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|err| format!("Failed to create {}: {}", dir.display(), err))?;
+        }
+        let contents = toml::to_string(self).map_err(|err| err.to_string())?;
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+            // The mode only applies to new files
+            if path.exists() {
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+                    .map_err(|err| format!("Failed to protect {}: {}", path.display(), err))?;
+            }
+        }
+        options
+            .open(&path)
+            .and_then(|mut file| file.write_all(contents.as_bytes()))
+            .map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+    }
+
+    /// The session saved for `user` on the server at `url`
+    pub(crate) fn get(&self, url: &str, user: &str) -> Option<&str> {
+        self.0.get(url)?.get(user).map(String::as_str)
+    }
+
+    /// Saves `session` for `user` on the server at `url`, replacing any earlier one
+    pub(crate) fn insert(&mut self, url: &str, user: &str, session: &str) {
+        self.0
+            .entry(url.to_owned())
+            .or_default()
+            .insert(user.to_owned(), session.to_owned());
+    }
+
+    /// Forgets the session saved for `user` on the server at `url`, returning whether there was one
+    pub(crate) fn remove(&mut self, url: &str, user: &str) -> bool {
+        let Some(users) = self.0.get_mut(url) else {
+            return false;
+        };
+        let removed = users.remove(user).is_some();
+        if users.is_empty() {
+            self.0.remove(url);
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions() {
+        let mut sessions = Sessions::default();
+        sessions.insert("https://bofhd.example.com:8000/", "olanor", "s1");
+        sessions.insert("https://bofhd.example.com:8000/", "karinor", "s2");
+        assert_eq!(
+            sessions.get("https://bofhd.example.com:8000/", "olanor"),
+            Some("s1")
+        );
+        assert_eq!(sessions.get("https://other.example.com/", "olanor"), None);
+
+        let toml = toml::to_string(&sessions).unwrap();
+        let sessions: Sessions = toml::from_str(&toml).unwrap();
+        assert_eq!(
+            sessions.get("https://bofhd.example.com:8000/", "karinor"),
+            Some("s2")
+        );
+
+        let mut sessions = sessions;
+        assert!(sessions.remove("https://bofhd.example.com:8000/", "olanor"));
+        assert!(!sessions.remove("https://bofhd.example.com:8000/", "olanor"));
+        assert!(sessions.remove("https://bofhd.example.com:8000/", "karinor"));
+        assert!(sessions.0.is_empty());
+    }
+}
@@ -0,0 +1,91 @@
+//! The `source` command, for running the command lines in a file as if they were typed at the prompt.
+//!
+//! Empty lines and lines starting with `#` are skipped. A failing command is reported with its line
+//! number, and the rest of the file is still run, unless `--strict` is set.
+use crate::internal::{self, Context, Line};
+use crate::tokenize::{self, Token};
+use crate::Format;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::thread;
+
+/// How many files deep `source` goes, so a file that runs itself doesn't go on forever
+const MAX_DEPTH: usize = 8;
+
+/// Runs the command lines in the file at `path`, returning the number of commands that failed.
+///
+/// Each line is run like at the prompt, see [`internal::run_line`], so it can be an internal command too.
+/// Waits between each command as configured by `--rate`/`--delay`, to avoid overloading the server.
+pub(crate) fn source(path: &str, context: &mut Context<'_>) -> Result<usize, String> {
+    if context.sourcing >= MAX_DEPTH {
+        return Err(format!(
+            "Not running {}, files are already being run {} levels deep",
+            path, MAX_DEPTH
+        ));
+    }
+    context.sourcing += 1;
+    let result = source_lines(path, context);
+    context.sourcing -= 1;
+    result
+}
+
+/// Runs the command lines in the file at `path`, see [`source`]
+fn source_lines(path: &str, context: &mut Context<'_>) -> Result<usize, String> {
+    let args = context.args;
+    let delay = args.command_delay();
+    let file = File::open(path).map_err(|err| format!("Failed to open {}: {}", path, err))?;
+    let mut commands = 0;
+    let mut failures = 0;
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| format!("Failed to read {}: {}", path, err))?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let result = tokenize::tokenize(line).and_then(|mut command| {
+            let format = crate::split_format(&mut command)?;
+            // A line with only a format, like `--json`, has nothing to run
+            if command.is_empty() {
+                return Ok(());
+            }
+            if commands > 0 && !delay.is_zero() {
+                thread::sleep(delay);
+            }
+            commands += 1;
+            println!("{}{}", args.prompt, line);
+            run(context, &command, format.unwrap_or(args.format))
+        });
+        if let Err(err) = result {
+            failures += 1;
+            eprintln!("{}:{}: {}", path, number + 1, err);
+            if args.strict {
+                return Err(format!(
+                    "Stopped at line {} of {} (--strict)",
+                    number + 1,
+                    path
+                ));
+            }
+        }
+    }
+    Ok(failures)
+}
+
+/// Runs a command line from a file, and writes its result in `format`
+fn run(context: &mut Context<'_>, command: &[Token], format: Format) -> Result<(), String> {
+    match internal::run_line(context, command) {
+        Line::Handled { record: true } => Ok(()),
+        // The internal command has said what went wrong already
+        Line::Handled { record: false } => Err(String::from("The command failed")),
+        Line::Logout | Line::Quit => Err(format!("Can't {} from a file", tokenize::join(command))),
+        Line::Refused(err) => Err(err),
+        Line::Ran {
+            result: Ok(value), ..
+        } => {
+            crate::write_result(&value, context.args, format, None);
+            Ok(())
+        }
+        Line::Ran {
+            result: Err(err), ..
+        } => Err(err.to_string()),
+    }
+}
@@ -0,0 +1,111 @@
+//! Rendering the REPL prompt from the `--prompt` template.
+//!
+//! The template may contain these tokens, which are filled in before each command:
+//!
+//! * `{user}`: the username, with the realm if there is one
+//! * `{url}`: the URL of the bofhd server
+//! * `{host}`: the server's hostname, without the scheme, port and path of the URL
+//! * `{status}`: `ok`, or `expired` (in red) when the server has said that the session has expired
+//!
+//! Other text, including unknown tokens, is shown as it is.
+use colored::Colorize;
+
+/// What the prompt tokens are filled in with
+pub(crate) struct PromptState<'a> {
+    pub(crate) user: &'a str,
+    pub(crate) url: &'a str,
+    pub(crate) expired: bool,
+}
+
+/// Fills in the tokens in `template`, with the status in color if `color` is set
+pub(crate) fn render(template: &str, state: &PromptState, color: bool) -> String {
+    let status = match (state.expired, color) {
+        (false, false) => String::from("ok"),
+        (false, true) => "ok".green().to_string(),
+        (true, false) => String::from("expired"),
+        (true, true) => "expired".red().to_string(),
+    };
+    let mut prompt = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        prompt.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let token = rest.find('}').map(|end| &rest[..=end]);
+        let value = match token {
+            Some("{user}") => state.user,
+            Some("{url}") => state.url,
+            Some("{host}") => host(state.url),
+            Some("{status}") => &status,
+            _ => {
+                prompt.push('{');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        prompt.push_str(value);
+        rest = &rest[token.map_or(0, str::len)..];
+    }
+    prompt.push_str(rest);
+    prompt
+}
+
+/// The hostname in `url`, like `bofhd.example.com` in `https://bofhd.example.com:8000/`
+fn host(url: &str) -> &str {
+    let authority = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = authority.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    match host.strip_prefix('[') {
+        // An IPv6 address, like `[::1]:8000`
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATE: PromptState = PromptState {
+        user: "olanor",
+        url: "https://bofhd.example.com:8000/",
+        expired: false,
+    };
+
+    #[test]
+    fn tokens() {
+        assert_eq!(render("bofh> ", &STATE, false), "bofh> ");
+        assert_eq!(
+            render("{user}@{host} [{status}]> ", &STATE, false),
+            "olanor@bofhd.example.com [ok]> "
+        );
+        assert_eq!(
+            render("{url}> ", &STATE, false),
+            "https://bofhd.example.com:8000/> "
+        );
+        assert_eq!(render("{unknown} {user", &STATE, false), "{unknown} {user");
+        assert_eq!(render("{{user}}", &STATE, false), "{olanor}");
+    }
+
+    #[test]
+    fn status() {
+        let expired = PromptState {
+            expired: true,
+            ..STATE
+        };
+        assert_eq!(render("{status}", &expired, false), "expired");
+        assert_eq!(
+            render("{status}", &expired, true),
+            "expired".red().to_string()
+        );
+    }
+
+    #[test]
+    fn hosts() {
+        assert_eq!(host("https://bofhd.example.com:8000/"), "bofhd.example.com");
+        assert_eq!(host("http://localhost"), "localhost");
+        assert_eq!(host("bofhd.example.com:8000"), "bofhd.example.com");
+        assert_eq!(host("https://user@[::1]:8000/path"), "::1");
+    }
+}
@@ -0,0 +1,359 @@
+//! Splitting command lines into words.
+//!
+//! Words are separated by whitespace, like with `split_whitespace`, but quotes make the text between them part of a single
+//! word, so an argument can contain spaces: `person create "Ola Nordmann"`. Like in a shell:
+//!
+//! * Text in single quotes is taken literally
+//! * In double quotes, a backslash escapes a double quote or another backslash
+//! * Outside quotes, a backslash escapes any character
+//! * Quoted text is part of the surrounding word, so `a"b c"` is the word `ab c`, and `""` is an empty word
+//!
+//! A word can also be a group of words in parentheses, like `(STUDENT ansatt)` in `person affiliation (STUDENT ansatt)`,
+//! which is sent to the server as a single argument, an XML-RPC array of the words in it (see [`argument`]). Groups can't be nested.
+//! The group's word is its words, quoted as needed, in parentheses. A quoted word is never a group, so `'(x)'` is the word `(x)`.
+use bofh::CommandArg;
+use std::ops::Range;
+
+/// A word on a command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Token {
+    /// The word, with quotes and escapes removed
+    pub(crate) value: String,
+    /// Where the word is on the line, including any quotes
+    pub(crate) span: Range<usize>,
+    /// Whether any of the word was quoted or escaped, which makes it a word even if it looks like a group
+    pub(crate) quoted: bool,
+}
+
+impl Token {
+    /// A word that's taken as it is, like an argument that was prompted for
+    pub(crate) fn literal(value: impl Into<String>) -> Self {
+        Self {
+            value: value.into(),
+            span: 0..0,
+            quoted: true,
+        }
+    }
+
+    /// Whether the word is a group of words in parentheses, see [`argument`]
+    pub(crate) fn is_group(&self) -> bool {
+        !self.quoted && self.value.starts_with('(') && self.value.ends_with(')')
+    }
+}
+
+/// Splits `line` into words.
+///
+/// Returns a message suitable for the user if a quote isn't closed, or if the line ends with a backslash.
+pub(crate) fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    match scan(line, None) {
+        (tokens, None) => Ok(tokens),
+        (_, Some(err)) => Err(err),
+    }
+}
+
+/// Splits `line` into words like [`tokenize`], but for a line that's still being typed:
+/// an unclosed quote extends to the end of the line, and a trailing backslash is ignored.
+pub(crate) fn tokenize_partial(line: &str) -> Vec<Token> {
+    scan(line, None).0
+}
+
+/// Splits `line` into word values, see [`tokenize`]
+pub(crate) fn words(line: &str) -> Result<Vec<String>, String> {
+    Ok(tokenize(line)?
+        .into_iter()
+        .map(|token| token.value)
+        .collect())
+}
+
+/// The values of `tokens`, for matching a command line against commands
+pub(crate) fn values(tokens: &[Token]) -> Vec<&str> {
+    tokens.iter().map(|token| token.value.as_str()).collect()
+}
+
+/// The command argument for `token`, which is a [`CommandArg::Group`] if the word is a group in parentheses.
+///
+/// Returns a message suitable for the user if the words in the group can't be split.
+pub(crate) fn argument(token: &Token) -> Result<CommandArg, String> {
+    if token.is_group() {
+        let group = &token.value[1..token.value.len() - 1];
+        return Ok(CommandArg::Group(words(group)?));
+    }
+    Ok(CommandArg::from(token.value.as_str()))
+}
+
+/// Joins `words` into a command line that splits back into the same words, quoting the ones that need it
+pub(crate) fn join(words: &[Token]) -> String {
+    words
+        .iter()
+        .map(|token| {
+            let word = token.value.as_str();
+            if token.is_group()
+                || !word.is_empty()
+                    && !word.contains(|c: char| c.is_whitespace() || "'\"\\()".contains(c))
+            {
+                word.to_owned()
+            } else if !word.contains('\'') {
+                format!("'{}'", word)
+            } else {
+                format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Splits `line` into words, and tells what's wrong with it first, if anything.
+///
+/// For the inside of a group, `group` is the column where the group's text starts in the whole line.
+fn scan(line: &str, group: Option<usize>) -> (Vec<Token>, Option<String>) {
+    let column = |index: usize| group.unwrap_or_default() + index + 1;
+    let mut tokens = vec![];
+    let mut error = None;
+    let mut current: Option<Token> = None;
+    let mut chars = line.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        if c.is_whitespace() {
+            tokens.extend(current.take());
+            continue;
+        }
+        if c == '(' && current.is_none() {
+            if group.is_some() {
+                error.get_or_insert(format!("Nested group at column {}", column(index)));
+            }
+            let end = group_end(line, index);
+            let inside = &line[index + 1..end.unwrap_or(line.len())];
+            let (words, group_error) = scan(inside, Some(column(index)));
+            error = error.or(group_error);
+            if end.is_none() {
+                error.get_or_insert(format!("Unterminated group at column {}", column(index)));
+            }
+            let span_end = end.map_or(line.len(), |end| end + 1);
+            tokens.push(Token {
+                value: format!("({})", join(&words)),
+                span: index..span_end,
+                quoted: false,
+            });
+            while chars.peek().is_some_and(|&(next, _)| next < span_end) {
+                chars.next();
+            }
+            continue;
+        }
+        let token = current.get_or_insert_with(|| Token {
+            value: String::new(),
+            span: index..index,
+            quoted: false,
+        });
+        match c {
+            '\'' | '"' => {
+                token.quoted = true;
+                let mut closed = false;
+                while let Some((_, quoted)) = chars.next() {
+                    if quoted == c {
+                        closed = true;
+                        break;
+                    }
+                    if c == '"' && quoted == '\\' {
+                        if let Some((_, escaped @ ('"' | '\\'))) = chars.peek().copied() {
+                            token.value.push(escaped);
+                            chars.next();
+                            continue;
+                        }
+                    }
+                    token.value.push(quoted);
+                }
+                if !closed {
+                    error.get_or_insert(format!("Unterminated quote at column {}", column(index)));
+                }
+            }
+            '\\' => match chars.next() {
+                Some((_, escaped)) => {
+                    token.quoted = true;
+                    token.value.push(escaped);
+                }
+                None => {
+                    error.get_or_insert(String::from(
+                        "Nothing to escape after the backslash at the end of the line",
+                    ));
+                }
+            },
+            c => token.value.push(c),
+        }
+        token.span.end = chars.peek().map_or(line.len(), |&(next, _)| next);
+    }
+    tokens.extend(current);
+    (tokens, error)
+}
+
+/// Finds the `)` that closes the group opened by the `(` at `open` in `line`, skipping quoted and escaped characters
+fn group_end(line: &str, open: usize) -> Option<usize> {
+    let mut quote = None;
+    let mut chars = line[open + 1..].char_indices();
+    while let Some((index, c)) = chars.next() {
+        match (quote, c) {
+            (None, ')') => return Some(open + 1 + index),
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '\\') | (Some('"'), '\\') => {
+                chars.next();
+            }
+            (Some(open_quote), c) if c == open_quote => quote = None,
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The first word of `line`
+    fn token(line: &str) -> Token {
+        tokenize(line).unwrap().remove(0)
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(
+            words(r#"person create "Ola Nordmann" 'x y'"#).unwrap(),
+            ["person", "create", "Ola Nordmann", "x y"]
+        );
+        assert_eq!(
+            words("  user   info olanor ").unwrap(),
+            ["user", "info", "olanor"]
+        );
+        assert_eq!(words(r#"a"b c"d '' """#).unwrap(), ["ab cd", "", ""]);
+        assert_eq!(words(r"a\ b \'").unwrap(), ["a b", "'"]);
+    }
+
+    #[test]
+    fn nested_quotes() {
+        assert_eq!(
+            words(r#""it's" 'say "hi"'"#).unwrap(),
+            ["it's", r#"say "hi""#]
+        );
+        assert_eq!(
+            words(r#""say \"hi\" \\ \n""#).unwrap(),
+            [r#"say "hi" \ \n"#]
+        );
+        assert_eq!(
+            words(r"'no \' escapes'").unwrap_err(),
+            "Unterminated quote at column 15"
+        );
+    }
+
+    #[test]
+    fn unterminated_quote() {
+        assert_eq!(
+            tokenize(r#"user info "Ola"#).unwrap_err(),
+            "Unterminated quote at column 11"
+        );
+        assert!(tokenize(r"user info \").is_err());
+        assert_eq!(
+            tokenize_partial(r#"user info "Ola N"#)[2],
+            Token {
+                value: String::from("Ola N"),
+                span: 10..16,
+                quoted: true,
+            }
+        );
+    }
+
+    #[test]
+    fn spans() {
+        let line = r#"user  "in fo" x"#;
+        let spans: Vec<&str> = tokenize(line)
+            .unwrap()
+            .into_iter()
+            .map(|token| &line[token.span])
+            .collect();
+        assert_eq!(spans, ["user", r#""in fo""#, "x"]);
+    }
+
+    #[test]
+    fn groups() {
+        assert_eq!(
+            words("person affiliation (STUDENT ansatt) x").unwrap(),
+            ["person", "affiliation", "(STUDENT ansatt)", "x"]
+        );
+        assert_eq!(
+            argument(&token("(STUDENT ansatt)")),
+            Ok(CommandArg::Group(vec![
+                String::from("STUDENT"),
+                String::from("ansatt")
+            ]))
+        );
+        assert_eq!(argument(&token("STUDENT")), Ok(CommandArg::from("STUDENT")));
+        assert_eq!(words("a (b)c").unwrap(), ["a", "(b)", "c"]);
+        assert_eq!(words("a(b)").unwrap(), ["a(b)"]);
+    }
+
+    #[test]
+    fn empty_group() {
+        assert_eq!(words("x () ( )").unwrap(), ["x", "()", "()"]);
+        assert_eq!(argument(&token("()")), Ok(CommandArg::Group(vec![])));
+    }
+
+    #[test]
+    fn quoted_groups() {
+        for line in ["'(x)'", r#""(x)""#, r"\(x)"] {
+            assert!(!token(line).is_group(), "{}", line);
+        }
+        assert_eq!(argument(&token("'(x)'")), Ok(CommandArg::from("(x)")));
+        assert_eq!(argument(&token(r"\(x)")), Ok(CommandArg::from("(x)")));
+        assert_eq!(
+            argument(&Token::literal("(a b)")),
+            Ok(CommandArg::from("(a b)"))
+        );
+        // A group that didn't come from the tokenizer may not split into words
+        let broken = Token {
+            quoted: false,
+            ..Token::literal("(a 'b)")
+        };
+        assert!(broken.is_group());
+        assert!(argument(&broken).is_err());
+    }
+
+    #[test]
+    fn tokens_in_group() {
+        let line = r#"x ( "Ola Nordmann" 'a)b' c\ d )"#;
+        let tokens = tokenize(line).unwrap();
+        assert_eq!(tokens[1].span, 2..line.len());
+        assert_eq!(
+            argument(&tokens[1]),
+            Ok(CommandArg::Group(vec![
+                String::from("Ola Nordmann"),
+                String::from("a)b"),
+                String::from("c d"),
+            ]))
+        );
+        assert_eq!(
+            tokenize("x (a (b))").unwrap_err(),
+            "Nested group at column 6"
+        );
+        assert_eq!(
+            tokenize("x (a 'b)").unwrap_err(),
+            "Unterminated quote at column 6"
+        );
+        assert_eq!(
+            tokenize("x (a b").unwrap_err(),
+            "Unterminated group at column 3"
+        );
+    }
+
+    #[test]
+    fn join_round_trip() {
+        let original = ["user", "Ola Nordmann", "it's", r#"a"b\c"#, "", "(x)"];
+        let literals: Vec<Token> = original.into_iter().map(Token::literal).collect();
+        let tokens = tokenize(&join(&literals)).unwrap();
+        assert_eq!(values(&tokens), original);
+        assert!(tokens.iter().all(|token| !token.is_group()));
+        // Groups stay groups, and words that look like groups stay words
+        let tokens = tokenize(r"x (a 'b c') '(y)'").unwrap();
+        let joined = tokenize(&join(&tokens)).unwrap();
+        assert_eq!(values(&joined), values(&tokens));
+        assert_eq!(
+            joined.iter().map(Token::is_group).collect::<Vec<_>>(),
+            [false, true, false]
+        );
+    }
+}
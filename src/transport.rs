@@ -0,0 +1,346 @@
+//! Sending XML-RPC requests to a bofhd server
+//!
+//! [`Bofh`](crate::Bofh) talks to the server through a [`Transport`], which is an [`HttpTransport`] unless another one
+//! is given to [`BofhBuilder::transport`](crate::BofhBuilder::transport). A [`MockTransport`] responds with canned
+//! values instead, for testing clients without a server.
+
+use crate::BofhError;
+use crate::Value;
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::Cursor;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use xmlrpc::Request;
+
+/// Sends XML-RPC method calls to a bofhd server
+pub trait Transport: fmt::Debug + Send + Sync {
+    /// Calls `method` with `args` on the server, and returns the result.
+    ///
+    /// # Errors
+    ///
+    /// A fault response from the server must be returned as a [`BofhError::Fault`] with the fault string as it is,
+    /// which [`Bofh`](crate::Bofh) parses into the more specific errors, like [`BofhError::SessionExpiredError`].
+    fn call(&self, method: &str, args: &[Value]) -> Result<Value, BofhError>;
+
+    /// Calls `method` like [`Self::call`], but gives up after `timeout` with a [`BofhError::TimeoutError`], instead of
+    /// after the transport's usual timeout.
+    ///
+    /// By default, the timeout is ignored.
+    ///
+    /// # Errors
+    ///
+    /// Like [`Self::call`].
+    fn call_with_timeout(
+        &self,
+        method: &str,
+        args: &[Value],
+        timeout: Duration,
+    ) -> Result<Value, BofhError> {
+        let _ = timeout;
+        self.call(method, args)
+    }
+}
+
+/// Sends requests to a bofhd server over HTTP(S)
+#[derive(Debug, Clone)]
+pub struct HttpTransport {
+    url: String,
+    client: Client,
+    interrupt: Option<Arc<AtomicBool>>,
+}
+
+impl HttpTransport {
+    /// Sends requests to the bofhd server at `url` with `client`
+    #[must_use]
+    pub fn new(url: String, client: Client) -> Self {
+        Self {
+            url,
+            client,
+            interrupt: None,
+        }
+    }
+
+    /// Abandons the request in flight when `interrupt` is set, see [`BofhBuilder::interrupt`](crate::BofhBuilder::interrupt)
+    #[must_use]
+    pub fn interrupt(mut self, interrupt: Arc<AtomicBool>) -> Self {
+        self.interrupt = Some(interrupt);
+        self
+    }
+
+    /// Posts `body` to the server, and returns the response's status code, content type and body. With a `timeout`, it
+    /// replaces the client's.
+    ///
+    /// If the transport can be interrupted, the request is sent from another thread, which is left behind if the interrupt
+    /// flag is set before the response arrives.
+    fn post(
+        &self,
+        body: Vec<u8>,
+        timeout: Option<Duration>,
+    ) -> Result<(u16, String, Vec<u8>), BofhError> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "text/xml; charset=utf-8")
+            .body(body);
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        let send = move || {
+            let response = request.send()?;
+            let status = response.status().as_u16();
+            let content_type = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .unwrap_or_default()
+                .to_lowercase();
+            Ok((status, content_type, response.bytes()?.to_vec()))
+        };
+        let Some(interrupt) = &self.interrupt else {
+            return send().map_err(http_error);
+        };
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || sender.send(send()));
+        receive(&receiver, interrupt)
+    }
+
+    /// Sends a request to the bofhd server and returns the response body.
+    ///
+    /// Returns a [`BofhError::NotXmlRpcError`] if the response obviously isn't XML-RPC, which usually means that the URL points to something other than a bofhd server.
+    fn transmit(&self, request: &Request, timeout: Option<Duration>) -> Result<Vec<u8>, BofhError> {
+        let mut body = vec![];
+        request
+            .write_as_xml(&mut body)
+            .expect("Failed to serialize XML-RPC request");
+        let (status, content_type, body) = self.post(body, timeout)?;
+        let text = String::from_utf8_lossy(&body);
+        let text = text.trim_start();
+        let start = text.chars().take(9).collect::<String>().to_lowercase();
+        if content_type.contains("html")
+            || content_type.contains("json")
+            || !start.starts_with('<')
+            || start.starts_with("<html")
+            || start.starts_with("<!doctype")
+        {
+            return Err(BofhError::NotXmlRpcError {
+                status,
+                snippet: text
+                    .split_whitespace()
+                    .collect::<Vec<&str>>()
+                    .join(" ")
+                    .chars()
+                    .take(SNIPPET_LENGTH)
+                    .collect(),
+            });
+        }
+        Ok(body)
+    }
+
+    /// Calls `method`, giving up after `timeout` instead of the client's timeout if there is one
+    fn call_timeout(
+        &self,
+        method: &str,
+        args: &[Value],
+        timeout: Option<Duration>,
+    ) -> Result<Value, BofhError> {
+        let request = args.iter().fold(Request::new(method), |request, arg| {
+            request.arg(arg.clone())
+        });
+        let response = self.transmit(&request, timeout)?;
+        request
+            .call(ReceivedResponse(response))
+            .map_err(|err| match err.fault() {
+                Some(fault) => {
+                    log::trace!("Fault {}: {}", fault.fault_code, fault.fault_string);
+                    BofhError::Fault(fault.fault_string.clone())
+                }
+                None => BofhError::XmlRpcError(err),
+            })
+    }
+}
+
+impl Transport for HttpTransport {
+    fn call(&self, method: &str, args: &[Value]) -> Result<Value, BofhError> {
+        self.call_timeout(method, args, None)
+    }
+
+    fn call_with_timeout(
+        &self,
+        method: &str,
+        args: &[Value],
+        timeout: Duration,
+    ) -> Result<Value, BofhError> {
+        self.call_timeout(method, args, Some(timeout))
+    }
+}
+
+/// How often an interruptible request checks whether it's been interrupted
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How much of a non-XML-RPC response body to include in [`BofhError::NotXmlRpcError`]
+const SNIPPET_LENGTH: usize = 200;
+
+/// Waits for the response from the thread sending a request, unless the `interrupt` flag is set first.
+///
+/// Returns a [`BofhError::RequestLost`] if the thread stops without a response, like if it panics.
+fn receive<T>(
+    receiver: &mpsc::Receiver<Result<T, reqwest::Error>>,
+    interrupt: &AtomicBool,
+) -> Result<T, BofhError> {
+    loop {
+        if interrupt.swap(false, Ordering::SeqCst) {
+            return Err(BofhError::Interrupted);
+        }
+        match receiver.recv_timeout(INTERRUPT_POLL_INTERVAL) {
+            Ok(response) => return response.map_err(http_error),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Err(BofhError::RequestLost),
+        }
+    }
+}
+
+/// Tells timeouts apart from other HTTP errors
+fn http_error(err: reqwest::Error) -> BofhError {
+    if err.is_timeout() {
+        BofhError::TimeoutError
+    } else {
+        BofhError::HttpError(err)
+    }
+}
+
+/// A response body that has already been received, which [`xmlrpc`] can parse
+struct ReceivedResponse(Vec<u8>);
+
+impl xmlrpc::Transport for ReceivedResponse {
+    type Stream = Cursor<Vec<u8>>;
+
+    fn transmit(
+        self,
+        _request: &Request,
+    ) -> Result<Self::Stream, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Cursor::new(self.0))
+    }
+}
+
+/// Responds to each method with a canned value or fault, and records the calls, for testing without a server.
+///
+/// Methods without a response fail with a [`BofhError::Fault`]. A method can also fault a given number of times before it
+/// responds, like to script a session that expires, see [`MockTransport::fault_once`].
+///
+/// ```
+/// use bofh::transport::{MockTransport, Transport};
+/// use bofh::Value;
+///
+/// let transport = MockTransport::new().respond("get_motd", "Welcome");
+/// assert_eq!(
+///     transport.call("get_motd", &[]).unwrap(),
+///     Value::from("Welcome")
+/// );
+/// assert_eq!(transport.calls(), [(String::from("get_motd"), vec![])]);
+/// ```
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    responses: HashMap<String, Result<Value, String>>,
+    /// Faults to respond with before the usual response, oldest first
+    faults: Mutex<HashMap<String, VecDeque<String>>>,
+    calls: Mutex<Vec<(String, Vec<Value>)>>,
+}
+
+impl MockTransport {
+    /// Creates a transport that doesn't respond to any methods yet
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Responds to calls to `method` with `value`
+    #[must_use]
+    pub fn respond(mut self, method: &str, value: impl Into<Value>) -> Self {
+        self.responses.insert(method.to_owned(), Ok(value.into()));
+        self
+    }
+
+    /// Responds to calls to `method` with a fault, like `Cerebrum.modules.bofhd.errors.CerebrumError:Not found`
+    #[must_use]
+    pub fn fault(mut self, method: &str, fault_string: &str) -> Self {
+        self.responses
+            .insert(method.to_owned(), Err(fault_string.to_owned()));
+        self
+    }
+
+    /// Responds to the next call to `method` with a fault, and to later calls as before. Calling this again adds another fault
+    /// to respond with after this one.
+    #[must_use]
+    pub fn fault_once(self, method: &str, fault_string: &str) -> Self {
+        self.faults
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .entry(method.to_owned())
+            .or_default()
+            .push_back(fault_string.to_owned());
+        self
+    }
+
+    /// The methods called so far, with their arguments, oldest first
+    #[must_use]
+    pub fn calls(&self) -> Vec<(String, Vec<Value>)> {
+        self.calls
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl Transport for MockTransport {
+    fn call(&self, method: &str, args: &[Value]) -> Result<Value, BofhError> {
+        self.calls
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .push((method.to_owned(), args.to_vec()));
+        if let Some(fault_string) = self
+            .faults
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .get_mut(method)
+            .and_then(VecDeque::pop_front)
+        {
+            return Err(BofhError::Fault(fault_string));
+        }
+        match self.responses.get(method) {
+            Some(Ok(value)) => Ok(value.clone()),
+            Some(Err(fault_string)) => Err(BofhError::Fault(fault_string.clone())),
+            None => Err(BofhError::Fault(format!("No response to {}", method))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lost_requests() {
+        let interrupt = AtomicBool::new(false);
+        let (sender, receiver) = mpsc::channel::<Result<(), reqwest::Error>>();
+        // Like the sending thread panicking
+        drop(sender);
+        assert!(matches!(
+            receive(&receiver, &interrupt),
+            Err(BofhError::RequestLost)
+        ));
+
+        let (sender, receiver) = mpsc::channel::<Result<(), reqwest::Error>>();
+        interrupt.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            receive(&receiver, &interrupt),
+            Err(BofhError::Interrupted)
+        ));
+        sender.send(Ok(())).unwrap();
+        assert!(receive(&receiver, &interrupt).is_ok());
+    }
+}